@@ -0,0 +1,2 @@
+-- Intentionally empty. The todos table predates the migrations, which only
+-- create it when it's missing, so reverting them must not drop existing data.
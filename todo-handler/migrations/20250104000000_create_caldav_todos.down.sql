@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS outbox_todo_idx;
+DROP TABLE IF EXISTS caldav_todos;
@@ -0,0 +1,14 @@
+-- Calendar properties of todos synced over CalDAV. Todos without a row are
+-- served as `todo-<id>.ics` with a generated UID.
+CREATE TABLE caldav_todos (
+    todo_id INTEGER PRIMARY KEY REFERENCES todos (id) ON DELETE CASCADE,
+    uid TEXT NOT NULL UNIQUE,
+    resource_name TEXT NOT NULL UNIQUE,
+    due TIMESTAMPTZ,
+    due_is_date BOOLEAN NOT NULL DEFAULT false,
+    completed_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Last modification times of calendar resources are derived from their events
+CREATE INDEX outbox_todo_idx ON outbox (todo_id);
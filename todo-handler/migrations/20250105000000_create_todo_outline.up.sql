@@ -0,0 +1,9 @@
+-- Where todos appear in Markdown checklists: under which heading (project),
+-- nested under which todo (subtask) and in which order. The other APIs don't
+-- know about projects or subtasks.
+CREATE TABLE todo_outline (
+    todo_id INTEGER PRIMARY KEY REFERENCES todos (id) ON DELETE CASCADE,
+    project TEXT,
+    parent_id INTEGER REFERENCES todos (id) ON DELETE SET NULL,
+    position INTEGER NOT NULL
+);
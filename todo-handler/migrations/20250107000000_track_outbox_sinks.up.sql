@@ -0,0 +1,5 @@
+-- Sinks each event was handed to, so a failing sink doesn't hold up the others
+ALTER TABLE outbox ADD COLUMN delivered_to TEXT[] NOT NULL DEFAULT '{}';
+
+-- Finds the dispatched events past their retention
+CREATE INDEX outbox_dispatched_idx ON outbox (dispatched_at) WHERE dispatched_at IS NOT NULL;
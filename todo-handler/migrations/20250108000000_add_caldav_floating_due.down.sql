@@ -0,0 +1 @@
+ALTER TABLE caldav_todos DROP COLUMN IF EXISTS due_is_floating;
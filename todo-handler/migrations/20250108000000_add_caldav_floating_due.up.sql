@@ -0,0 +1,2 @@
+-- Due times without a time zone, stored in `due` as if they were UTC
+ALTER TABLE caldav_todos ADD COLUMN due_is_floating BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+-- Last change of each todo, which outlives its pruned outbox events
+ALTER TABLE todos ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+UPDATE todos t SET updated_at = o.last_event
+    FROM (SELECT todo_id, max(created_at) AS last_event FROM outbox GROUP BY todo_id) o
+    WHERE o.todo_id = t.id;
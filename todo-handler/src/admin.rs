@@ -0,0 +1,102 @@
+use salvo::prelude::*;
+use serde_json::Value;
+
+use crate::backend_error::StoreError;
+use crate::backup::{self, Archive, RestoreMode};
+use crate::import_export::{file_headers, read_upload};
+use crate::schemas::RestoreResponse;
+use crate::users;
+
+// Backup and restore for operators, behind the configured admin token or an
+// API key issued with `todo-handler apikey issue`.
+
+pub fn router(token: Option<String>) -> Router {
+    Router::with_path("admin")
+        .hoop(AdminAuth::new(token))
+        .push(Router::with_path("backup").get(download_backup))
+        .push(Router::with_path("restore").post(restore_backup))
+}
+
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        AdminAuth { token }
+    }
+}
+
+#[handler]
+impl AdminAuth {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let given = users::bearer_token(req.headers()).map(str::to_string);
+
+        let authorized = match given {
+            Some(given) if self.token.as_ref().is_some_and(|token| constant_time_eq(given.as_bytes(), token.as_bytes())) => Ok(true),
+            Some(given) => users::authenticate(&given).await.map(|credentials| credentials.is_some()),
+            None => Ok(false),
+        };
+
+        match authorized {
+            Ok(true) => {
+                ctrl.call_next(req, depot, res).await;
+            }
+            Ok(false) => {
+                StoreError::Unauthorized("Missing or wrong admin token").write(req, depot, res).await;
+                ctrl.skip_rest();
+            }
+            Err(e) => {
+                e.write(req, depot, res).await;
+                ctrl.skip_rest();
+            }
+        }
+    }
+}
+
+/// Download a backup archive of every todo, outline, calendar entry, webhook, event, user and API key.
+///
+/// The archive includes webhook secrets and API key hashes.
+#[endpoint(
+    tags("admin"),
+    status_codes(200, 401, 429, 500),
+    responses((status_code = 200, description = "Backup archive", body = Archive))
+)]
+async fn download_backup(res: &mut Response) -> Result<(), StoreError> {
+    let archive = backup::create_archive().await?;
+
+    let file_name = format!("todo-handler-{}.json", archive.created_at.format("%Y%m%dT%H%M%SZ"));
+    file_headers(res, "application/json", &file_name);
+    res.render(Json(archive));
+    Ok(())
+}
+
+/// Restore a backup archive.
+///
+/// `replace` deletes all data and restores the archive with its original ids. `merge` adds
+/// the todos, webhooks and users whose names and URLs aren't taken yet, without their history.
+/// Older archive versions are upgraded first; nothing changes when the archive is invalid.
+#[endpoint(
+    tags("admin"),
+    status_codes(200, 400, 401, 413, 429, 500),
+    parameters(("mode" = String, Query, description = "`replace` or `merge`")),
+    request_body(content = Archive, content_type = "application/json", description = "Backup archive, as the body or a multipart upload"),
+    responses((status_code = 200, description = "What was restored", body = RestoreResponse))
+)]
+async fn restore_backup(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let mode: RestoreMode = req.query::<String>("mode")
+        .ok_or_else(|| StoreError::BadRequest("Missing 'mode' query parameter".to_string()))?
+        .parse()?;
+
+    // `BodyLimits` caps restores at `limits.max_restore_bytes`
+    let upload = read_upload(req, usize::MAX).await?;
+    let archive: Value = serde_json::from_str(&upload)
+        .map_err(|_| StoreError::BadRequest("Archive is not valid JSON".to_string()))?;
+
+    res.render(Json(backup::restore(archive, mode).await?));
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,87 @@
+use salvo::oapi::swagger_ui::SwaggerUi;
+use salvo::oapi::OpenApi;
+use salvo::Router;
+
+const OPENAPI_PATH: &str = "/openapi.json";
+const DOCS_PATH: &str = "docs";
+
+// Documents every route of `router`, serves the document at `/openapi.json` and
+// Swagger UI at `/docs`. Returns the templates of the documented routes and of
+// the documentation itself.
+pub fn with_docs(router: Router) -> (Router, Vec<String>) {
+    let doc = OpenApi::new("Todo API", env!("CARGO_PKG_VERSION")).merge_router(&router);
+
+    let mut templates: Vec<String> = doc.paths.keys().cloned().collect();
+    templates.push(OPENAPI_PATH.to_string());
+    templates.push(format!("/{}/{{**rest}}", DOCS_PATH));
+
+    let router = router
+        .push(doc.into_router(OPENAPI_PATH))
+        .push(SwaggerUi::new(OPENAPI_PATH).into_router(DOCS_PATH));
+    (router, templates)
+}
+
+// Names the handlers of `router` added with `#[handler]` instead of `#[endpoint]`,
+// which therefore have no documentation
+#[cfg(test)]
+pub fn undocumented_handlers(router: &Router) -> Vec<&'static str> {
+    let mut undocumented: Vec<&'static str> = router
+        .goal
+        .iter()
+        .filter(|goal| salvo::oapi::EndpointRegistry::find(&goal.type_id()).is_none())
+        .map(|goal| goal.type_name())
+        .collect();
+    for child in router.routers() {
+        undocumented.extend(undocumented_handlers(child));
+    }
+    undocumented
+}
+
+// The route templates the server answers, used to label requests by the route they
+// matched, such as `/v2/todos/{id}`, rather than by their path
+#[derive(Clone, Debug)]
+pub struct RouteTemplates {
+    routes: Vec<Vec<String>>,
+}
+
+impl RouteTemplates {
+    // Takes OpenAPI style templates, such as `/v2/todos/{id}` or `/docs/{**rest}`
+    pub fn new(templates: impl IntoIterator<Item = String>) -> Self {
+        let mut routes: Vec<Vec<String>> = templates
+            .into_iter()
+            .map(|path| path.split('/').filter(|segment| !segment.is_empty()).map(str::to_string).collect())
+            .collect();
+        // Literal segments win over parameters, e.g. `/todos/export.ics` over `/todos/{id}`
+        routes.sort_by_key(|segments: &Vec<String>| std::cmp::Reverse(segments.iter().filter(|segment| !is_parameter(segment)).count()));
+        routes.dedup();
+        RouteTemplates { routes }
+    }
+
+    // The template `path` matches, if any
+    pub fn find(&self, path: &str) -> Option<String> {
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+        self.routes
+            .iter()
+            .find(|route| route_matches(route, &segments))
+            .map(|route| format!("/{}", route.join("/")))
+    }
+}
+
+fn is_parameter(segment: &str) -> bool {
+    segment.starts_with('{')
+}
+
+fn route_matches(route: &[String], segments: &[&str]) -> bool {
+    for (index, part) in route.iter().enumerate() {
+        // `{**rest}` and `{*rest}` take every remaining segment
+        if part.starts_with("{*") {
+            return true;
+        }
+        match segments.get(index) {
+            Some(segment) if is_parameter(part) || part == segment => {}
+            _ => return false,
+        }
+    }
+    route.len() == segments.len()
+}
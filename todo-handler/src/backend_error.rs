@@ -1,11 +1,17 @@
+use salvo::oapi::{Components, Content, EndpointOutRegister, Operation, Response as OapiResponse, ToSchema};
+use salvo::http::header::RETRY_AFTER;
 use salvo::prelude::*;
 use thiserror::Error;
 
+use crate::circuit_breaker;
+use crate::schemas::ErrorResponse;
+
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum BackendError {
-    #[error("Environment variable error: {0}")]
-    EnvError(String),
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
 
     #[error("Request error: {0:?}")]
     ReqwestError(#[from] reqwest::Error),
@@ -13,6 +19,27 @@ pub enum BackendError {
     #[error("Sqlx error: {0:?}")]
     SqlxError(#[from] sqlx::Error),
 
+    #[error("Migration error: {0:?}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Schema error: {0}")]
+    SchemaBehind(String),
+
+    #[error("Serialization error: {0:?}")]
+    SerdeJsonError(#[from] serde_json::Error),
+
+    #[error("IO error: {0:?}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("gRPC transport error: {0:?}")]
+    TonicError(#[from] tonic::transport::Error),
+
+    #[error("Invalid address: {0:?}")]
+    AddrParseError(#[from] std::net::AddrParseError),
+
+    #[error("Store error: {0}")]
+    StoreError(#[from] StoreError),
+
     #[error("Salvo parse error: {0:?}")]
     SalvoParseError (#[from] salvo::http::ParseError),
 }
@@ -20,7 +47,99 @@ pub enum BackendError {
 #[async_trait]
 impl Writer for BackendError {
     async fn write(self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        tracing::error!(error = %self, "Request failed");
         res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
         res.render(format!("{:?}", self));
     }
-}
\ No newline at end of file
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("Todo with id {0} does not exist")]
+    NotFound(i32),
+
+    #[error("Todo with that name already exists")]
+    Conflict,
+
+    #[error("{0}")]
+    Unauthorized(&'static str),
+
+    #[error("Request body is larger than {0} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("Rate limit exceeded, retry in {0} seconds")]
+    RateLimited(u64),
+
+    #[error("Database unavailable, retry in {0} seconds")]
+    Unavailable(u64),
+
+    #[error("{0}")]
+    Unexpected(&'static str),
+
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl StoreError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            StoreError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            StoreError::NotFound(_) => StatusCode::NOT_FOUND,
+            StoreError::Conflict => StatusCode::CONFLICT,
+            StoreError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            StoreError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            StoreError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            StoreError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            // Queries outside the todo store aren't guarded by the circuit breaker
+            StoreError::Database(e) if circuit_breaker::is_unavailable(e) => StatusCode::SERVICE_UNAVAILABLE,
+            StoreError::Unexpected(_) | StoreError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[async_trait]
+impl Writer for StoreError {
+    async fn write(self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        // Client errors are visible from the status the request is logged with, and
+        // the circuit breaker logs once when the database becomes unavailable
+        if self.status_code().is_server_error() && !matches!(self, StoreError::Unavailable(_)) {
+            tracing::error!(error = %self, "Store error");
+        }
+        match &self {
+            StoreError::RateLimited(retry_after) | StoreError::Unavailable(retry_after) => {
+                res.headers_mut().insert(RETRY_AFTER, (*retry_after).into());
+            }
+            StoreError::Database(e) if circuit_breaker::is_unavailable(e) => {
+                res.headers_mut().insert(RETRY_AFTER, circuit_breaker::retry_after().into());
+            }
+            _ => {}
+        }
+        res.status_code(self.status_code());
+        res.render(Json(ErrorResponse::new(self.to_string())));
+    }
+}
+
+// Documents the error envelope for every status a `StoreError` can produce
+impl EndpointOutRegister for StoreError {
+    fn register(components: &mut Components, operation: &mut Operation) {
+        let schema = ErrorResponse::to_schema(components);
+        for (status_code, description) in [
+            ("400", "Missing or invalid input"),
+            ("401", "Missing or wrong credentials"),
+            ("404", "Resource not found"),
+            ("409", "Conflicts with an existing resource"),
+            ("413", "Request body too large"),
+            ("429", "Too many requests, retry after `Retry-After` seconds"),
+            ("500", "Database or unexpected error"),
+            ("503", "Database unavailable, retry after `Retry-After` seconds"),
+        ] {
+            operation.responses.insert(
+                status_code,
+                OapiResponse::new(description).add_content("application/json", Content::new(schema.clone())),
+            );
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use once_cell::sync::OnceCell;
+use salvo::async_trait;
+use salvo::http::header::CACHE_CONTROL;
+use salvo::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::backend_error::{BackendError, StoreError};
+use crate::config::CacheConfig;
+use crate::{metrics, replica};
+
+// Read-through cache in front of the todo queries dashboards repeat. Values are
+// stored as JSON, so an external cache can hold them by implementing
+// `CacheBackend`. Mutations in `todo_store` and restores drop the entries they
+// affect on this instance; other instances notice once `cache.ttl_secs` passed.
+
+pub const TODO_LIST_KEY: &str = "todos";
+
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BackendError>;
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), BackendError>;
+
+    async fn remove(&self, keys: &[String]) -> Result<(), BackendError>;
+
+    async fn clear(&self) -> Result<(), BackendError>;
+}
+
+// Entries of this process, dropped once expired; past `max_entries` the least
+// recently used make room
+pub struct MemoryCache {
+    entries: Mutex<LruCache<String, (Vec<u8>, Instant)>>,
+}
+
+struct Cache {
+    backend: Box<dyn CacheBackend>,
+    ttl: Duration,
+    // Bumped by every invalidation, so values loaded before one aren't stored
+    generation: AtomicU64,
+}
+
+static CACHE: OnceCell<Cache> = OnceCell::new();
+
+tokio::task_local! {
+    // Set for requests asking for fresh data with `Cache-Control: no-cache`
+    static BYPASS: bool;
+}
+
+#[async_trait]
+impl CacheBackend for MemoryCache {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BackendError> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((value, expires)) if *expires > Instant::now() => Ok(Some(value.clone())),
+            Some(_) => {
+                entries.pop(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), BackendError> {
+        self.entries.lock().unwrap().put(key.to_string(), (value, Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn remove(&self, keys: &[String]) -> Result<(), BackendError> {
+        let mut entries = self.entries.lock().unwrap();
+        for key in keys {
+            entries.pop(key);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), BackendError> {
+        self.entries.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+impl MemoryCache {
+    // `max_entries` is checked by `Config::validate`
+    pub fn new(max_entries: usize) -> Self {
+        let capacity = NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN);
+        MemoryCache { entries: Mutex::new(LruCache::new(capacity)) }
+    }
+}
+
+// `None` for `cache.backend = "none"`
+pub fn parse_backend(config: &CacheConfig) -> Result<Option<Box<dyn CacheBackend>>, BackendError> {
+    match config.backend.trim() {
+        "none" => Ok(None),
+        "memory" => Ok(Some(Box::new(MemoryCache::new(config.max_entries)))),
+        backend => Err(BackendError::ConfigError(format!("Unknown cache backend '{}', expected 'none' or 'memory'", backend))),
+    }
+}
+
+// Caches the queries from now on; without it they always go to the database
+pub fn init(backend: Box<dyn CacheBackend>, ttl: Duration) {
+    tracing::info!(backend = backend.name(), ttl_secs = ttl.as_secs(), "Caching todo queries");
+    let _ = CACHE.set(Cache { backend, ttl, generation: AtomicU64::new(0) });
+}
+
+pub fn todo_key(todo_id: i32) -> String {
+    format!("todo:{}", todo_id)
+}
+
+// The cached value of `key`, or the result of `load`, which is cached when it succeeds
+pub async fn read_through<T, F>(query: &str, key: &str, load: F) -> Result<T, StoreError>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = Result<T, StoreError>>,
+{
+    let Some(cache) = CACHE.get() else {
+        return load.await;
+    };
+    // Callers who just changed something skip entries the replica may have filled without their change
+    if BYPASS.try_with(|bypass| *bypass).unwrap_or(false) || replica::reads_own_writes() {
+        metrics::record_cache_lookup(query, "bypass");
+        return load.await;
+    }
+
+    // An unreachable cache is treated as empty
+    match cache.backend.get(key).await {
+        Ok(Some(bytes)) => match serde_json::from_slice(&bytes) {
+            Ok(value) => {
+                metrics::record_cache_lookup(query, "hit");
+                return Ok(value);
+            }
+            Err(e) => tracing::warn!(error = %e, key, "Ignoring an unreadable cache entry"),
+        },
+        Ok(None) => {}
+        Err(e) => tracing::warn!(error = %e, key, "Could not read from the cache"),
+    }
+    metrics::record_cache_lookup(query, "miss");
+
+    let generation = cache.generation.load(Ordering::SeqCst);
+    let value = load.await?;
+    // What a lagging replica returned is only kept until it should have caught up
+    let ttl = replica::lag_remaining().map_or(cache.ttl, |remaining| remaining.min(cache.ttl));
+    if cache.generation.load(Ordering::SeqCst) == generation {
+        if let Ok(bytes) = serde_json::to_vec(&value) {
+            if let Err(e) = cache.backend.set(key, bytes, ttl).await {
+                tracing::warn!(error = %e, key, "Could not write to the cache");
+            }
+            // An invalidation may have removed the key just before the set; it counts
+            // before removing, so either it removes the value or this check sees it
+            if cache.generation.load(Ordering::SeqCst) != generation {
+                if let Err(e) = cache.backend.remove(&[key.to_string()]).await {
+                    tracing::warn!(error = %e, key, "Could not drop an outdated cache entry");
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+// Drops the entries of a changed todo and the list holding it
+pub async fn invalidate_todo(todo_id: i32) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    cache.generation.fetch_add(1, Ordering::SeqCst);
+    if let Err(e) = cache.backend.remove(&[TODO_LIST_KEY.to_string(), todo_key(todo_id)]).await {
+        tracing::warn!(error = %e, todo_id, "Could not drop cached todos");
+    }
+}
+
+// Drops every entry, after changes too wide to track such as a restore
+pub async fn invalidate_all() {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    cache.generation.fetch_add(1, Ordering::SeqCst);
+    if let Err(e) = cache.backend.clear().await {
+        tracing::warn!(error = %e, "Could not clear the cache");
+    }
+}
+
+// Lets clients skip the cache with `Cache-Control: no-cache` or `no-store`
+#[handler]
+pub async fn request_policy(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let bypass = req.headers().get_all(CACHE_CONTROL).iter().any(|value| {
+        value.to_str().is_ok_and(|directives| {
+            directives.split(',').any(|directive| matches!(directive.trim().to_ascii_lowercase().as_str(), "no-cache" | "no-store"))
+        })
+    });
+
+    BYPASS.scope(bypass, ctrl.call_next(req, depot, res)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entries() {
+        let cache = MemoryCache::new(2);
+        let ttl = Duration::from_secs(60);
+
+        cache.set("a", b"1".to_vec(), ttl).await.unwrap();
+        cache.set("b", b"2".to_vec(), ttl).await.unwrap();
+        cache.get("a").await.unwrap();
+        cache.set("c", b"3".to_vec(), ttl).await.unwrap();
+
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(cache.get("b").await.unwrap(), None);
+        assert_eq!(cache.get("c").await.unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_missing() {
+        let cache = MemoryCache::new(2);
+
+        cache.set("a", b"1".to_vec(), Duration::ZERO).await.unwrap();
+
+        assert_eq!(cache.get("a").await.unwrap(), None);
+    }
+}
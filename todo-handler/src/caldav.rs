@@ -0,0 +1,734 @@
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+use salvo::http::header::{HeaderValue, ALLOW, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use salvo::prelude::*;
+use sha2::{Digest, Sha256};
+use sqlx::prelude::FromRow;
+use sqlx::PgConnection;
+
+use crate::backend_error::StoreError;
+use crate::ical::{self, CalendarTodo, Due};
+use crate::{circuit_breaker, get_postgres, todo_store, Todo};
+
+// CalDAV (RFC 4791) view of the todos: one calendar collection of VTODO
+// resources. `/caldav/` acts as principal and calendar home at once.
+// WebDAV methods can't be described in OpenAPI, so this router is mounted
+// outside the generated document.
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+const HOME_HREF: &str = "/caldav/";
+const CALENDAR_HREF: &str = "/caldav/todos/";
+
+const DEFAULT_UID_SUFFIX: &str = "@todo-handler";
+
+const ICS_CONTENT_TYPE: &str = "text/calendar; charset=utf-8; component=VTODO";
+
+// Characters left as they are in resource hrefs
+const HREF_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_').remove(b'@');
+
+const RESOURCE_QUERY: &str = "SELECT t.id, t.name, t.description, t.done, c.uid, c.resource_name, c.due, c.due_is_date, c.due_is_floating, c.completed_at,
+        GREATEST(t.updated_at, c.updated_at) AS last_modified
+    FROM todos t LEFT JOIN caldav_todos c ON c.todo_id = t.id";
+
+// The paths of `router`, which OpenAPI doesn't document
+pub const ROUTE_TEMPLATES: [&str; 4] = ["/.well-known/caldav", "/caldav", "/caldav/todos", "/caldav/todos/{name}"];
+
+pub fn router() -> Router {
+    Router::new()
+        .push(Router::with_path(".well-known/caldav").goal(well_known))
+        .push(
+            Router::with_path("caldav")
+                .goal(home)
+                .push(
+                    Router::with_path("todos")
+                        .goal(calendar)
+                        .push(Router::with_path("<name>").goal(todo_resource))
+                )
+        )
+}
+
+#[derive(FromRow, Debug)]
+struct ResourceRow {
+    id: i32,
+    name: String,
+    description: String,
+    done: bool,
+    uid: Option<String>,
+    resource_name: Option<String>,
+    due: Option<DateTime<Utc>>,
+    due_is_date: Option<bool>,
+    due_is_floating: Option<bool>,
+    completed_at: Option<DateTime<Utc>>,
+    last_modified: DateTime<Utc>,
+}
+
+// A todo rendered as a calendar object resource
+struct TodoResource {
+    todo_id: i32,
+    name: String,
+    todo: CalendarTodo,
+    ics: String,
+    etag: String,
+}
+
+enum Resource<'a> {
+    Home,
+    Calendar { ctag: &'a str },
+    Todo(&'a TodoResource),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PropName {
+    namespace: String,
+    name: String,
+}
+
+// The parts of a PROPFIND or REPORT body this server understands
+#[derive(Debug, Default)]
+struct DavRequest {
+    root: Option<PropName>,
+    props: Vec<PropName>,
+    all_props: bool,
+    hrefs: Vec<String>,
+    // `name` of every comp-filter, outermost first
+    components: Vec<String>,
+}
+
+#[handler]
+async fn well_known(res: &mut Response) {
+    res.render(Redirect::permanent(HOME_HREF));
+}
+
+#[handler]
+async fn home(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    match req.method().as_str() {
+        "OPTIONS" => options(res),
+        "PROPFIND" => {
+            let request = DavRequest::read(req).await?;
+            let mut responses = vec![Resource::Home.response(&request)];
+            if depth(req) > 0 {
+                let ctag = ctag(&load_resources().await.map_err(dav_error)?);
+                responses.push(Resource::Calendar { ctag: &ctag }.response(&request));
+            }
+            multistatus(res, responses);
+        }
+        _ => return Err(StatusError::method_not_allowed()),
+    }
+    Ok(())
+}
+
+#[handler]
+async fn calendar(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    match req.method().as_str() {
+        "OPTIONS" => options(res),
+        "PROPFIND" => {
+            let request = DavRequest::read(req).await?;
+            let resources = load_resources().await.map_err(dav_error)?;
+            let ctag = ctag(&resources);
+
+            let mut responses = vec![Resource::Calendar { ctag: &ctag }.response(&request)];
+            if depth(req) > 0 {
+                responses.extend(resources.iter().map(|resource| Resource::Todo(resource).response(&request)));
+            }
+            multistatus(res, responses);
+        }
+        "REPORT" => {
+            let request = DavRequest::read(req).await?;
+            let resources = load_resources().await.map_err(dav_error)?;
+            let report = request.root.as_ref().filter(|root| root.namespace == CALDAV).map(|root| root.name.as_str());
+
+            let responses = match report {
+                // Only component filters are applied, clients filter the rest themselves
+                Some("calendar-query") => {
+                    let wants_todos = request.components.get(1).is_none_or(|component| component == "VTODO");
+                    resources
+                        .iter()
+                        .filter(|_| wants_todos)
+                        .map(|resource| Resource::Todo(resource).response(&request))
+                        .collect()
+                }
+                Some("calendar-multiget") => request.hrefs
+                    .iter()
+                    .map(|href| {
+                        let name = resource_name(href);
+                        match resources.iter().find(|resource| Some(&resource.name) == name.as_ref()) {
+                            Some(resource) => Resource::Todo(resource).response(&request),
+                            None => format!("<D:response><D:href>{}</D:href><D:status>HTTP/1.1 404 Not Found</D:status></D:response>", escape(href)),
+                        }
+                    })
+                    .collect(),
+                _ => return Err(StatusError::forbidden().brief("Unsupported report")),
+            };
+            multistatus(res, responses);
+        }
+        _ => return Err(StatusError::method_not_allowed()),
+    }
+    Ok(())
+}
+
+#[handler]
+async fn todo_resource(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    let name = req.param::<String>("name").ok_or_else(StatusError::not_found)?;
+
+    match req.method().as_str() {
+        "OPTIONS" => options(res),
+        "GET" | "HEAD" => {
+            let resource = find_resource(&name).await.map_err(dav_error)?.ok_or_else(StatusError::not_found)?;
+            write_ics(res, &resource)?;
+        }
+        "PROPFIND" => {
+            let request = DavRequest::read(req).await?;
+            let resource = find_resource(&name).await.map_err(dav_error)?.ok_or_else(StatusError::not_found)?;
+            multistatus(res, vec![Resource::Todo(&resource).response(&request)]);
+        }
+        "PUT" => {
+            let existing = find_resource(&name).await.map_err(dav_error)?;
+            check_preconditions(req, existing.as_ref())?;
+
+            let body = req.payload().await.map_err(|_| StatusError::bad_request())?;
+            let calendars = ical::parse(&String::from_utf8_lossy(body)).map_err(dav_error)?;
+            let components: Vec<_> = calendars.iter().flat_map(ical::Component::todos).collect();
+            let todo = match components.as_slice() {
+                [component] => CalendarTodo::from_component(component).map_err(dav_error)?,
+                [] => return Err(StatusError::forbidden().brief("Only VTODO components are supported")),
+                _ => return Err(StatusError::bad_request().brief("A resource must contain exactly one VTODO")),
+            };
+            if todo.uid.trim().is_empty() {
+                return Err(StatusError::bad_request().brief("Missing UID"));
+            }
+
+            let created = existing.is_none();
+            store_todo(&name, existing, &todo).await.map_err(dav_error)?;
+
+            let resource = find_resource(&name).await.map_err(dav_error)?.ok_or_else(StatusError::internal_server_error)?;
+            res.status_code(if created { StatusCode::CREATED } else { StatusCode::NO_CONTENT });
+            insert_header(res, ETAG, &resource.etag);
+        }
+        "DELETE" => {
+            let resource = find_resource(&name).await.map_err(dav_error)?.ok_or_else(StatusError::not_found)?;
+            check_preconditions(req, Some(&resource))?;
+
+            todo_store::delete_todo(resource.todo_id).await.map_err(dav_error)?;
+            res.status_code(StatusCode::NO_CONTENT);
+        }
+        _ => return Err(StatusError::method_not_allowed()),
+    }
+    Ok(())
+}
+
+// Every todo with its calendar properties
+#[tracing::instrument(skip_all)]
+pub async fn calendar_todos() -> Result<Vec<CalendarTodo>, StoreError> {
+    let resources = load_resources().await?;
+
+    Ok(resources.into_iter().map(|resource| resource.todo).collect())
+}
+
+// Updates the todo with the UID of `todo`, or creates it; also returns whether it was created
+#[tracing::instrument(skip_all, fields(uid = %todo.uid))]
+pub async fn store_by_uid(todo: &CalendarTodo) -> Result<(Todo, bool), StoreError> {
+    let query = format!("{} WHERE c.uid = $1 OR (c.todo_id IS NULL AND t.id = $2)", RESOURCE_QUERY);
+    let existing = circuit_breaker::read("find_calendar_uid", || {
+        sqlx::query_as::<_, ResourceRow>(&query)
+            .bind(&todo.uid)
+            .bind(default_uid_todo_id(&todo.uid))
+            .fetch_optional(get_postgres())
+    })
+    .await?
+    .map(TodoResource::from);
+
+    match existing {
+        Some(existing) => {
+            let name = existing.name.clone();
+            Ok((store_todo(&name, Some(existing), todo).await?, false))
+        }
+        // Derived from the UID, which may contain characters that don't belong in a URL
+        None => {
+            let name = format!("{}.ics", &hex::encode(Sha256::digest(todo.uid.as_bytes()))[..32]);
+            Ok((store_todo(&name, None, todo).await?, true))
+        }
+    }
+}
+
+// Sets the due date of a todo within the caller's transaction; only calendars and file imports know about it
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn set_due(conn: &mut PgConnection, todo_id: i32, due: Option<Due>) -> Result<(), StoreError> {
+    sqlx::query(
+        "INSERT INTO caldav_todos (todo_id, uid, resource_name, due, due_is_date, due_is_floating) VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (todo_id) DO UPDATE SET due = EXCLUDED.due, due_is_date = EXCLUDED.due_is_date,
+            due_is_floating = EXCLUDED.due_is_floating, updated_at = now()"
+    )
+    .bind(todo_id)
+    .bind(format!("todo-{}{}", todo_id, DEFAULT_UID_SUFFIX))
+    .bind(format!("todo-{}.ics", todo_id))
+    .bind(due.map(|due| due.at))
+    .bind(due.is_some_and(|due| due.is_date))
+    .bind(due.is_some_and(|due| due.floating))
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// Creates or updates the todo behind a resource together with its calendar properties
+#[tracing::instrument(skip_all, fields(resource = name))]
+async fn store_todo(name: &str, existing: Option<TodoResource>, todo: &CalendarTodo) -> Result<Todo, StoreError> {
+    circuit_breaker::write(async {
+        let mut tx = todo_store::begin().await?;
+        let stored = match existing {
+            Some(existing) => {
+                if existing.todo.uid != todo.uid {
+                    return Err(StoreError::BadRequest("The UID of a resource can't be changed".to_string()));
+                }
+                todo_store::update_in(&mut tx, existing.todo_id, &todo.summary, &todo.description, todo.done).await?
+            }
+            None => {
+                // Generated names would clash with todos created through the other APIs
+                if default_todo_id(name).is_some() {
+                    return Err(StoreError::BadRequest(format!("Resource name '{}' is reserved", name)));
+                }
+                if sqlx::query("SELECT 1 FROM caldav_todos WHERE uid = $1")
+                    .bind(&todo.uid)
+                    .fetch_optional(&mut *tx)
+                    .await?
+                    .is_some()
+                {
+                    return Err(StoreError::BadRequest(format!("A resource with UID '{}' already exists", todo.uid)));
+                }
+
+                let created = todo_store::create_in(&mut tx, &todo.summary, &todo.description).await?;
+                match todo.done {
+                    true => todo_store::mark_done_in(&mut tx, created.id).await?,
+                    false => created,
+                }
+            }
+        };
+
+        sqlx::query(
+            "INSERT INTO caldav_todos (todo_id, uid, resource_name, due, due_is_date, due_is_floating, completed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (todo_id) DO UPDATE SET uid = EXCLUDED.uid, resource_name = EXCLUDED.resource_name, due = EXCLUDED.due,
+                due_is_date = EXCLUDED.due_is_date, due_is_floating = EXCLUDED.due_is_floating,
+                completed_at = EXCLUDED.completed_at, updated_at = now()"
+        )
+        .bind(stored.id)
+        .bind(&todo.uid)
+        .bind(name)
+        .bind(todo.due.map(|due| due.at))
+        .bind(todo.due.is_some_and(|due| due.is_date))
+        .bind(todo.due.is_some_and(|due| due.floating))
+        .bind(todo.completed_at)
+        .execute(&mut *tx)
+        .await?;
+
+        todo_store::commit(tx, &[stored.id]).await?;
+        Ok(stored)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all)]
+async fn load_resources() -> Result<Vec<TodoResource>, StoreError> {
+    let query = format!("{} ORDER BY t.id", RESOURCE_QUERY);
+    let rows = circuit_breaker::read("load_calendar", || sqlx::query_as::<_, ResourceRow>(&query).fetch_all(get_postgres())).await?;
+
+    Ok(rows.into_iter().map(TodoResource::from).collect())
+}
+
+#[tracing::instrument(skip_all, fields(resource = name))]
+async fn find_resource(name: &str) -> Result<Option<TodoResource>, StoreError> {
+    let query = format!("{} WHERE c.resource_name = $1 OR (c.todo_id IS NULL AND t.id = $2)", RESOURCE_QUERY);
+    let row = circuit_breaker::read("find_calendar_resource", || {
+        sqlx::query_as::<_, ResourceRow>(&query)
+            .bind(name)
+            .bind(default_todo_id(name))
+            .fetch_optional(get_postgres())
+    })
+    .await?;
+
+    Ok(row.map(TodoResource::from))
+}
+
+impl From<ResourceRow> for TodoResource {
+    fn from(row: ResourceRow) -> Self {
+        let todo = CalendarTodo {
+            uid: row.uid.unwrap_or_else(|| format!("todo-{}{}", row.id, DEFAULT_UID_SUFFIX)),
+            summary: row.name,
+            description: row.description,
+            done: row.done,
+            due: row.due.map(|at| Due {
+                at,
+                is_date: row.due_is_date.unwrap_or(false),
+                floating: row.due_is_floating.unwrap_or(false),
+            }),
+            completed_at: row.completed_at,
+            stamp: row.last_modified,
+        };
+        let ics = ical::calendar(std::slice::from_ref(&todo));
+        let etag = format!("\"{}\"", &hex::encode(Sha256::digest(ics.as_bytes()))[..32]);
+
+        TodoResource {
+            todo_id: row.id,
+            name: row.resource_name.unwrap_or_else(|| format!("todo-{}.ics", row.id)),
+            todo,
+            ics,
+            etag,
+        }
+    }
+}
+
+// `todo-<id>.ics`, the name of todos that were never written over CalDAV
+fn default_todo_id(name: &str) -> Option<i32> {
+    name.strip_prefix("todo-")?.strip_suffix(".ics")?.parse().ok()
+}
+
+// The generated UID of todos that were never written over CalDAV
+fn default_uid_todo_id(uid: &str) -> Option<i32> {
+    uid.strip_prefix("todo-")?.strip_suffix(DEFAULT_UID_SUFFIX)?.parse().ok()
+}
+
+// Changes whenever any resource is added, changed or removed
+fn ctag(resources: &[TodoResource]) -> String {
+    let mut hasher = Sha256::new();
+    for resource in resources {
+        hasher.update(resource.name.as_bytes());
+        hasher.update(resource.etag.as_bytes());
+    }
+    hex::encode(hasher.finalize())[..32].to_string()
+}
+
+impl Resource<'_> {
+    fn href(&self) -> String {
+        match self {
+            Resource::Home => HOME_HREF.to_string(),
+            Resource::Calendar { .. } => CALENDAR_HREF.to_string(),
+            Resource::Todo(resource) => format!("{}{}", CALENDAR_HREF, utf8_percent_encode(&resource.name, HREF_SET)),
+        }
+    }
+
+    // Inner XML of a property, `None` if this resource doesn't have it
+    fn prop(&self, prop: &PropName) -> Option<String> {
+        let home_href = format!("<D:href>{}</D:href>", HOME_HREF);
+        let value = match (prop.namespace.as_str(), prop.name.as_str(), self) {
+            (DAV, "resourcetype", Resource::Home) => "<D:collection/><D:principal/>".to_string(),
+            (DAV, "resourcetype", Resource::Calendar { .. }) => "<D:collection/><C:calendar/>".to_string(),
+            (DAV, "resourcetype", Resource::Todo(_)) => String::new(),
+            (DAV, "displayname", Resource::Home) => "Todo handler".to_string(),
+            (DAV, "displayname", Resource::Calendar { .. }) => "Todos".to_string(),
+            (DAV, "current-user-principal" | "principal-URL" | "owner", _) => home_href,
+            (CALDAV, "calendar-home-set", Resource::Home | Resource::Calendar { .. }) => home_href,
+            (DAV, "current-user-privilege-set", _) => {
+                "<D:privilege><D:read/></D:privilege><D:privilege><D:write/></D:privilege>".to_string()
+            }
+            (CALDAV, "supported-calendar-component-set", Resource::Calendar { .. }) => "<C:comp name=\"VTODO\"/>".to_string(),
+            (DAV, "supported-report-set", Resource::Calendar { .. }) => {
+                "<D:supported-report><D:report><C:calendar-query/></D:report></D:supported-report>\
+                <D:supported-report><D:report><C:calendar-multiget/></D:report></D:supported-report>".to_string()
+            }
+            (CALENDARSERVER, "getctag", Resource::Calendar { ctag }) => ctag.to_string(),
+            (DAV, "getetag", Resource::Calendar { ctag }) => escape(&format!("\"{}\"", ctag)),
+            (DAV, "getetag", Resource::Todo(resource)) => escape(&resource.etag),
+            (DAV, "getcontenttype", Resource::Todo(_)) => ICS_CONTENT_TYPE.to_string(),
+            (DAV, "getlastmodified", Resource::Todo(resource)) => {
+                resource.todo.stamp.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+            }
+            (CALDAV, "calendar-data", Resource::Todo(resource)) => escape(&resource.ics),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // `<D:response>` with one propstat for the found and one for the missing properties
+    fn response(&self, request: &DavRequest) -> String {
+        let props = if request.all_props { all_props() } else { request.props.clone() };
+
+        let mut found = String::new();
+        let mut missing = String::new();
+        for prop in &props {
+            match self.prop(prop) {
+                Some(value) => found.push_str(&element(prop, &value)),
+                None => missing.push_str(&element(prop, "")),
+            }
+        }
+
+        let mut response = format!("<D:response><D:href>{}</D:href>", escape(&self.href()));
+        if !found.is_empty() {
+            response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>", found));
+        }
+        if !missing.is_empty() && !request.all_props {
+            response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>", missing));
+        }
+        response.push_str("</D:response>");
+        response
+    }
+}
+
+// Returned for `<D:allprop/>` and empty PROPFIND bodies
+fn all_props() -> Vec<PropName> {
+    [
+        (DAV, "resourcetype"),
+        (DAV, "displayname"),
+        (DAV, "getetag"),
+        (DAV, "getcontenttype"),
+        (DAV, "getlastmodified"),
+        (CALENDARSERVER, "getctag"),
+    ]
+    .into_iter()
+    .map(|(namespace, name)| PropName { namespace: namespace.to_string(), name: name.to_string() })
+    .collect()
+}
+
+impl DavRequest {
+    async fn read(req: &mut Request) -> Result<Self, StatusError> {
+        let body = req.payload().await.map_err(|_| StatusError::bad_request())?;
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(DavRequest { all_props: true, ..Default::default() });
+        }
+
+        let body = std::str::from_utf8(body).map_err(|_| StatusError::bad_request().brief("Invalid XML body"))?;
+        DavRequest::parse(body).map_err(|e| StatusError::bad_request().brief(format!("Invalid XML body: {}", e)))
+    }
+
+    fn parse(body: &str) -> Result<Self, quick_xml::Error> {
+        let mut request = DavRequest::default();
+        let mut reader = NsReader::from_str(body);
+        reader.config_mut().trim_text(true);
+        let mut open: Vec<PropName> = Vec::new();
+
+        loop {
+            let (namespace, event) = reader.read_resolved_event()?;
+            let namespace = match namespace {
+                ResolveResult::Bound(namespace) => String::from_utf8_lossy(namespace.as_ref()).into_owned(),
+                _ => String::new(),
+            };
+
+            match event {
+                Event::Start(ref element) | Event::Empty(ref element) => {
+                    let name = PropName {
+                        namespace,
+                        name: String::from_utf8_lossy(element.local_name().as_ref()).into_owned(),
+                    };
+                    match open.last() {
+                        None => request.root = Some(name.clone()),
+                        Some(parent) if parent.namespace == DAV && parent.name == "prop" => request.props.push(name.clone()),
+                        _ => {}
+                    }
+                    if name.namespace == DAV && name.name == "allprop" {
+                        request.all_props = true;
+                    }
+                    if name.namespace == CALDAV && name.name == "comp-filter" {
+                        if let Some(component) = element.try_get_attribute("name")? {
+                            request.components.push(component.unescape_value()?.to_ascii_uppercase());
+                        }
+                    }
+                    if matches!(event, Event::Start(_)) {
+                        open.push(name);
+                    }
+                }
+                Event::Text(text) if open.last().is_some_and(|element| element.namespace == DAV && element.name == "href") => {
+                    request.hrefs.push(text.unescape()?.into_owned());
+                }
+                Event::End(_) => {
+                    open.pop();
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+// Name of the calendar resource an href points at
+fn resource_name(href: &str) -> Option<String> {
+    let (_, name) = href.split_once(CALENDAR_HREF)?;
+    let name = percent_decode_str(name).decode_utf8().ok()?;
+    Some(name.into_owned()).filter(|name| !name.is_empty())
+}
+
+fn depth(req: &Request) -> u8 {
+    match req.header::<String>("Depth").as_deref() {
+        Some("0") => 0,
+        _ => 1,
+    }
+}
+
+// If-Match and If-None-Match, as clients use them to avoid overwriting each other's changes
+fn check_preconditions(req: &Request, existing: Option<&TodoResource>) -> Result<(), StatusError> {
+    let matches = |header| {
+        req.header::<String>(header).map(|tags| {
+            tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                existing.is_some_and(|existing| tag == "*" || tag == existing.etag)
+            })
+        })
+    };
+
+    if matches(IF_MATCH) == Some(false) || matches(IF_NONE_MATCH) == Some(true) {
+        return Err(StatusError::precondition_failed());
+    }
+    Ok(())
+}
+
+fn options(res: &mut Response) {
+    insert_header(res, "DAV", "1, 3, calendar-access");
+    insert_header(res, ALLOW, "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT");
+}
+
+fn write_ics(res: &mut Response, resource: &TodoResource) -> Result<(), StatusError> {
+    insert_header(res, CONTENT_TYPE, ICS_CONTENT_TYPE);
+    insert_header(res, ETAG, &resource.etag);
+    res.write_body(resource.ics.clone()).map_err(|_| StatusError::internal_server_error())
+}
+
+fn multistatus(res: &mut Response, responses: Vec<String>) {
+    res.status_code(StatusCode::MULTI_STATUS);
+    res.render(Text::Xml(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <D:multistatus xmlns:D=\"{}\" xmlns:C=\"{}\" xmlns:CS=\"{}\">{}</D:multistatus>",
+        DAV,
+        CALDAV,
+        CALENDARSERVER,
+        responses.concat()
+    )));
+}
+
+fn element(prop: &PropName, value: &str) -> String {
+    let (prefix, declaration) = match prop.namespace.as_str() {
+        DAV => ("D", String::new()),
+        CALDAV => ("C", String::new()),
+        CALENDARSERVER => ("CS", String::new()),
+        namespace => ("X", format!(" xmlns:X=\"{}\"", escape(namespace))),
+    };
+
+    if value.is_empty() {
+        format!("<{}:{}{}/>", prefix, prop.name, declaration)
+    } else {
+        format!("<{0}:{1}{2}>{3}</{0}:{1}>", prefix, prop.name, declaration, value)
+    }
+}
+
+fn escape(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+fn insert_header<K: salvo::http::header::IntoHeaderName>(res: &mut Response, name: K, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        res.headers_mut().insert(name, value);
+    }
+}
+
+// CalDAV clients only look at the status; the message is kept for debugging
+fn dav_error(e: StoreError) -> StatusError {
+    StatusError::from_code(e.status_code())
+        .unwrap_or_else(StatusError::internal_server_error)
+        .brief(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::Method;
+    use salvo::test::{RequestBuilder, ResponseExt, TestClient};
+
+    use super::*;
+    use crate::test_support::{unique, with_database};
+
+    fn dav(method: &str, path: &str) -> RequestBuilder {
+        RequestBuilder::new(format!("http://localhost{}", path), Method::from_bytes(method.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn put_propfind_and_report_round_trip() {
+        with_database(async {
+            let service = Service::new(router());
+            let name = format!("{}.ics", unique("caldav-test"));
+            let uid = format!("{}@example.com", unique("uid"));
+            let href = format!("{}{}", CALENDAR_HREF, name);
+            let ics = format!(
+                "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nBEGIN:VTODO\r\nUID:{}\r\nSUMMARY:{}\r\n\
+                 DESCRIPTION:From a calendar\r\nDUE;TZID=Europe/Berlin:20250115T090000\r\nSTATUS:NEEDS-ACTION\r\nEND:VTODO\r\nEND:VCALENDAR\r\n",
+                uid, name
+            );
+
+            let created = TestClient::put(format!("http://localhost{}", href))
+                .add_header(CONTENT_TYPE, "text/calendar", true)
+                .add_header(IF_NONE_MATCH, "*", true)
+                .body(ics.clone())
+                .send(&service)
+                .await;
+            assert_eq!(created.status_code, Some(StatusCode::CREATED));
+            let etag = created.headers().get(ETAG).unwrap().to_str().unwrap().to_string();
+
+            // Creating it again is refused by the precondition
+            let again = TestClient::put(format!("http://localhost{}", href))
+                .add_header(IF_NONE_MATCH, "*", true)
+                .body(ics)
+                .send(&service)
+                .await;
+            assert_eq!(again.status_code, Some(StatusCode::PRECONDITION_FAILED));
+
+            let mut listing = dav("PROPFIND", CALENDAR_HREF)
+                .add_header("Depth", "1", true)
+                .body(r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:prop><D:getetag/></D:prop></D:propfind>"#)
+                .send(&service)
+                .await;
+            assert_eq!(listing.status_code, Some(StatusCode::MULTI_STATUS));
+            let listing = listing.take_string().await.unwrap();
+            assert!(listing.contains(&format!("<D:href>{}</D:href>", href)), "{}", listing);
+            assert!(listing.contains(&escape(&etag)), "{}", listing);
+
+            let mut report = dav("REPORT", CALENDAR_HREF)
+                .body(format!(
+                    r#"<?xml version="1.0"?><C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+                    <D:prop><D:getetag/><C:calendar-data/></D:prop><D:href>{}</D:href></C:calendar-multiget>"#,
+                    href
+                ))
+                .send(&service)
+                .await;
+            assert_eq!(report.status_code, Some(StatusCode::MULTI_STATUS));
+            let report = report.take_string().await.unwrap();
+            assert!(report.contains(&format!("UID:{}", uid)), "{}", report);
+            assert!(report.contains("DESCRIPTION:From a calendar"), "{}", report);
+            // Stored in UTC
+            assert!(report.contains("DUE:20250115T080000Z"), "{}", report);
+
+            // Completing it with the current ETag marks the todo as done
+            let completed = format!(
+                "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VTODO\r\nUID:{}\r\nSUMMARY:{}\r\nSTATUS:COMPLETED\r\n\
+                 COMPLETED:20250116T100000Z\r\nEND:VTODO\r\nEND:VCALENDAR\r\n",
+                uid, name
+            );
+            let updated = TestClient::put(format!("http://localhost{}", href))
+                .add_header(IF_MATCH, etag.as_str(), true)
+                .body(completed)
+                .send(&service)
+                .await;
+            assert_eq!(updated.status_code, Some(StatusCode::NO_CONTENT));
+
+            let mut fetched = TestClient::get(format!("http://localhost{}", href)).send(&service).await;
+            let fetched = fetched.take_string().await.unwrap();
+            assert!(fetched.contains("STATUS:COMPLETED"), "{}", fetched);
+            assert!(fetched.contains("COMPLETED:20250116T100000Z"), "{}", fetched);
+            assert!(!fetched.contains("DUE"), "{}", fetched);
+            let todo = todo_store::fetch_todo_by_name(&name).await.unwrap().unwrap();
+            assert!(todo.done);
+
+            // Pruning its events leaves the ETag alone
+            let etag = TestClient::get(format!("http://localhost{}", href)).send(&service).await.headers()[ETAG].clone();
+            sqlx::query("DELETE FROM outbox WHERE todo_id = $1").bind(todo.id).execute(get_postgres()).await.unwrap();
+            let pruned = TestClient::get(format!("http://localhost{}", href)).send(&service).await;
+            assert_eq!(pruned.headers()[ETAG], etag);
+
+            let deleted = TestClient::delete(format!("http://localhost{}", href)).send(&service).await;
+            assert_eq!(deleted.status_code, Some(StatusCode::NO_CONTENT));
+            assert!(todo_store::fetch_todo_by_name(&name).await.unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,302 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use tokio::time::{sleep, timeout};
+
+use crate::backend_error::StoreError;
+use crate::config::DatabaseConfig;
+use crate::{get_postgres, metrics, shutdown};
+
+// Guards the todo store against database outages. After `failure_threshold`
+// consecutive connection failures the circuit opens and store operations fail
+// right away with 503 and `Retry-After` instead of waiting for the pool. A
+// background probe queries the primary every `probe_interval_secs`, opening the
+// circuit when it fails often enough and closing it as soon as one succeeds.
+// Reads failing with a transient error are repeated up to `query_retries`
+// times; writes never are, as a failed commit may still have been applied.
+
+// Keeps a probe from waiting on a pool that can't connect
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Doubled after every attempt
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+struct Breaker {
+    failure_threshold: u32,
+    probe_interval: Duration,
+    query_retries: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    consecutive_failures: u32,
+    open: bool,
+}
+
+static BREAKER: OnceCell<Breaker> = OnceCell::new();
+
+// Guards the store from now on and starts probing; without it operations run unguarded
+pub fn init(config: &DatabaseConfig) {
+    let breaker = Breaker {
+        failure_threshold: config.failure_threshold,
+        probe_interval: config.probe_interval(),
+        query_retries: config.query_retries,
+        state: Mutex::new(State::default()),
+    };
+    if BREAKER.set(breaker).is_ok() {
+        tokio::spawn(probe());
+    }
+}
+
+// Runs a read, repeating it after transient errors
+pub async fn read<T, F, Fut>(operation: &str, run: F) -> Result<T, StoreError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let Some(breaker) = BREAKER.get() else {
+        return Ok(run().await?);
+    };
+    breaker.check()?;
+
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut result = run().await;
+    breaker.record(result.as_ref().err());
+    for attempt in 1..=breaker.query_retries {
+        match &result {
+            Err(e) if is_retryable(e) && !breaker.is_open() => {
+                tracing::warn!(error = %e, operation, attempt, "Transient database error, retrying");
+                metrics::record_query_retry(operation);
+                sleep(delay).await;
+                delay *= 2;
+            }
+            _ => break,
+        }
+        result = run().await;
+        breaker.record(result.as_ref().err());
+    }
+    result.map_err(|e| breaker.store_error(e))
+}
+
+// Runs a write, usually a whole transaction, once
+pub async fn write<T, F>(run: F) -> Result<T, StoreError>
+where
+    F: Future<Output = Result<T, StoreError>>,
+{
+    let Some(breaker) = BREAKER.get() else {
+        return run.await;
+    };
+    breaker.check()?;
+
+    let result = run.await;
+    match result {
+        Err(StoreError::Database(e)) => {
+            breaker.record(Some(&e));
+            Err(breaker.store_error(e))
+        }
+        result => {
+            breaker.record(None);
+            result
+        }
+    }
+}
+
+// Fails right away while the circuit is open, for streamed reads that can't go through `read`
+pub fn check() -> Result<(), StoreError> {
+    BREAKER.get().map_or(Ok(()), Breaker::check)
+}
+
+// Counts the outcome of a streamed read, which can't be repeated once it started
+pub fn record(error: Option<&sqlx::Error>) {
+    if let Some(breaker) = BREAKER.get() {
+        breaker.record(error);
+    }
+}
+
+// Seconds clients are asked to wait while the database is unavailable
+pub fn retry_after() -> u64 {
+    BREAKER.get().map_or(1, |breaker| breaker.probe_interval.as_secs().max(1))
+}
+
+// Failures to reach the database at all, as opposed to errors of a query
+pub fn is_unavailable(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => true,
+        // Connection exceptions, and a server shutting down or still starting
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| code.starts_with("08") || code.starts_with("57P")),
+        _ => false,
+    }
+}
+
+// Errors a repeated read may not run into. A pool timeout already waited long enough.
+fn is_retryable(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => false,
+        // Serialization failures and deadlocks
+        sqlx::Error::Database(e) if e.code().is_some_and(|code| code == "40001" || code == "40P01") => true,
+        e => is_unavailable(e),
+    }
+}
+
+impl Breaker {
+    fn check(&self) -> Result<(), StoreError> {
+        if self.is_open() {
+            return Err(StoreError::Unavailable(retry_after()));
+        }
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open
+    }
+
+    // Any answer of the database counts as a success
+    fn record(&self, error: Option<&sqlx::Error>) {
+        let mut state = self.state.lock().unwrap();
+        match error {
+            Some(e) if is_unavailable(e) => {
+                state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+                if !state.open && state.consecutive_failures >= self.failure_threshold {
+                    state.open = true;
+                    metrics::set_circuit_open(true);
+                    tracing::error!(error = %e, failures = state.consecutive_failures, "Database unavailable, opening the circuit");
+                }
+            }
+            _ => {
+                state.consecutive_failures = 0;
+                if state.open {
+                    state.open = false;
+                    metrics::set_circuit_open(false);
+                    tracing::info!("Database reachable again, closing the circuit");
+                }
+            }
+        }
+    }
+
+    fn store_error(&self, e: sqlx::Error) -> StoreError {
+        if is_unavailable(&e) {
+            StoreError::Unavailable(retry_after())
+        } else {
+            StoreError::Database(e)
+        }
+    }
+}
+
+async fn probe() {
+    let Some(breaker) = BREAKER.get() else {
+        return;
+    };
+
+    while !shutdown::is_shutting_down() {
+        sleep(breaker.probe_interval).await;
+
+        let result = timeout(PROBE_TIMEOUT, sqlx::query("SELECT 1").execute(get_postgres())).await;
+        match result {
+            Ok(Ok(_)) => breaker.record(None),
+            Ok(Err(e)) => breaker.record(Some(&e)),
+            Err(_) => breaker.record(Some(&sqlx::Error::PoolTimedOut)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::error::Error as StdError;
+    use std::fmt;
+
+    use sqlx::error::{DatabaseError, ErrorKind};
+
+    use super::*;
+
+    // A database error with just an SQLSTATE
+    #[derive(Debug)]
+    struct Sqlstate(&'static str);
+
+    impl fmt::Display for Sqlstate {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl StdError for Sqlstate {}
+
+    impl DatabaseError for Sqlstate {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Sqlstate(code)))
+    }
+
+    fn breaker(failure_threshold: u32) -> Breaker {
+        Breaker {
+            failure_threshold,
+            probe_interval: Duration::from_secs(5),
+            query_retries: 2,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    #[test]
+    fn opens_after_the_threshold() {
+        let breaker = breaker(3);
+        for _ in 0..2 {
+            breaker.record(Some(&sqlx::Error::PoolTimedOut));
+        }
+        assert!(breaker.check().is_ok());
+
+        breaker.record(Some(&database_error("08006")));
+        assert!(matches!(breaker.check(), Err(StoreError::Unavailable(_))));
+    }
+
+    #[test]
+    fn any_answer_closes_it() {
+        let breaker = breaker(1);
+        breaker.record(Some(&sqlx::Error::PoolTimedOut));
+        assert!(breaker.is_open());
+
+        // A failing query still means the database answered
+        breaker.record(Some(&database_error("23505")));
+        assert!(!breaker.is_open());
+
+        breaker.record(Some(&sqlx::Error::PoolTimedOut));
+        breaker.record(None);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn repeats_only_transient_errors() {
+        assert!(is_retryable(&database_error("40001")));
+        assert!(is_retryable(&database_error("40P01")));
+        assert!(is_retryable(&database_error("57P01")));
+        assert!(!is_retryable(&sqlx::Error::PoolTimedOut));
+        assert!(!is_retryable(&database_error("23505")));
+        assert!(!is_retryable(&sqlx::Error::RowNotFound));
+    }
+}
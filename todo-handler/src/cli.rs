@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use sqlx::migrate::Migrate;
+
+use crate::backend_error::{BackendError, StoreError};
+use crate::backup::{self, RestoreMode};
+use crate::config::{Config, ConfigArgs};
+use crate::{get_postgres, todo_store, users, MIGRATOR};
+
+// Operator commands, so nobody has to touch the database by hand. Without a
+// command the handler serves as it always has.
+
+const SEED_TODOS: [(&str, &str); 3] = [
+    ("Read the README", "Endpoints, configuration and the admin commands"),
+    ("Try the API", "curl http://127.0.0.1:7878/v2/todos"),
+    ("Open the API docs", "Swagger UI is served at /docs"),
+];
+
+/// Todo API server; serves HTTP, gRPC and CalDAV unless another command is given.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
+    /// Print the effective configuration, with secrets redacted, and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Apply pending migrations and serve the APIs (the default)
+    Serve,
+    /// Apply, revert or list database migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Create a few sample todos; existing names are left alone
+    Seed,
+    /// Write a backup archive to a file, or to stdout without one
+    Backup {
+        path: Option<PathBuf>,
+    },
+    /// Restore a backup archive
+    Restore {
+        path: PathBuf,
+        /// `replace` or `merge`
+        #[arg(long)]
+        mode: RestoreMode,
+    },
+    /// Manage operators
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage API keys
+    #[command(subcommand)]
+    Apikey(ApiKeyCommand),
+    /// Validate the configuration without connecting to anything
+    CheckConfig,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateCommand {
+    /// Apply every pending migration
+    Up,
+    /// Revert the latest migration, or every migration newer than `--target`
+    Down {
+        /// Version to go back to; 0 reverts everything
+        #[arg(long)]
+        target: Option<i64>,
+    },
+    /// List migrations and whether they are applied
+    Status,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Create an operator
+    Create {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ApiKeyCommand {
+    /// Issue an API key for an operator; the key is printed once
+    Issue {
+        user: String,
+        /// Days until the key expires; it never does without
+        #[arg(long)]
+        expires_in_days: Option<u32>,
+    },
+}
+
+impl Command {
+    // Only `serve` brings the schema up to date on its own; the other commands
+    // leave that to `migrate up` and refuse to run against an older schema
+    pub fn applies_migrations(&self) -> bool {
+        matches!(self, Command::Serve)
+    }
+}
+
+pub fn check_config(config: &Config) -> Result<(), BackendError> {
+    println!(
+        "Configuration is valid: HTTP on {}, gRPC on {}, outbox sinks {}",
+        config.server.bind, config.server.grpc_bind, config.outbox.sinks
+    );
+    Ok(())
+}
+
+// Every command but `serve` and `check-config`, once the database is connected
+pub async fn run(command: Command) -> Result<(), BackendError> {
+    if !matches!(command, Command::Migrate(_)) {
+        ensure_schema_is_current().await?;
+    }
+
+    match command {
+        Command::Migrate(command) => migrate(command).await?,
+        Command::Seed => seed().await?,
+        Command::Backup { path: None } => {
+            let archive = backup::create_archive().await?;
+            println!("{}", serde_json::to_string_pretty(&archive)?);
+        }
+        Command::Backup { path: Some(path) } => {
+            let archive = backup::create_archive().await?;
+            std::fs::write(&path, serde_json::to_vec_pretty(&archive)?)?;
+            println!("Wrote {} todos to {}", archive.todos.len(), path.display());
+        }
+        Command::Restore { path, mode } => {
+            let archive = serde_json::from_slice(&std::fs::read(path)?)?;
+            let restored = backup::restore(archive, mode).await?;
+            println!(
+                "Restored {} todos ({} skipped), {} webhooks, {} users and {} events using {}",
+                restored.todos_restored, restored.todos_skipped, restored.webhooks_restored,
+                restored.users_restored, restored.events_restored, restored.mode
+            );
+        }
+        Command::User(UserCommand::Create { name }) => {
+            let user = users::create_user(&name).await?;
+            println!("Created user '{}' with id {}", user.name, user.id);
+        }
+        Command::Apikey(ApiKeyCommand::Issue { user, expires_in_days }) => {
+            let valid_for = expires_in_days.map(|days| chrono::Duration::days(days.into()));
+            let (api_key, key) = users::issue_api_key(&user, valid_for).await?;
+            match api_key.expires_at {
+                Some(expires_at) => eprintln!("Issued key {} ({}...) for '{}', valid until {}", api_key.id, api_key.prefix, user, expires_at),
+                None => eprintln!("Issued key {} ({}...) for '{}', valid until revoked", api_key.id, api_key.prefix, user),
+            }
+            eprintln!("Store it now, it can't be shown again:");
+            println!("{}", key);
+        }
+        Command::Serve | Command::CheckConfig => unreachable!("handled in main"),
+    }
+
+    Ok(())
+}
+
+async fn migrate(command: MigrateCommand) -> Result<(), BackendError> {
+    match command {
+        MigrateCommand::Up => {
+            MIGRATOR.run(get_postgres()).await?;
+            println!("Database is up to date");
+        }
+        MigrateCommand::Down { target } => {
+            let applied = applied_versions().await?;
+            let target = match target {
+                Some(target) => target,
+                // The newest applied migration goes, the one before it stays
+                None => {
+                    let mut versions: Vec<i64> = applied.into_iter().collect();
+                    versions.sort_unstable();
+                    versions.pop();
+                    versions.pop().unwrap_or(0)
+                }
+            };
+            MIGRATOR.undo(get_postgres(), target).await?;
+            println!("Reverted every migration newer than {}", target);
+        }
+        MigrateCommand::Status => {
+            let applied = applied_versions().await?;
+            for migration in MIGRATOR.iter().filter(|migration| !migration.migration_type.is_down_migration()) {
+                let state = if applied.contains(&migration.version) { "applied" } else { "pending" };
+                println!("{}  {:<8}  {}", migration.version, state, migration.description);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn applied_versions() -> Result<HashSet<i64>, BackendError> {
+    let mut conn = get_postgres().acquire().await?;
+    conn.ensure_migrations_table().await?;
+
+    Ok(conn.list_applied_migrations().await?.into_iter().map(|migration| migration.version).collect())
+}
+
+async fn ensure_schema_is_current() -> Result<(), BackendError> {
+    let applied = applied_versions().await?;
+    let pending = MIGRATOR.iter()
+        .filter(|migration| !migration.migration_type.is_down_migration() && !applied.contains(&migration.version))
+        .count();
+
+    match pending {
+        0 => Ok(()),
+        pending => Err(BackendError::SchemaBehind(format!(
+            "{} migration(s) pending, run `todo-handler migrate up` first", pending
+        ))),
+    }
+}
+
+async fn seed() -> Result<(), BackendError> {
+    let mut created = 0;
+    for (name, description) in SEED_TODOS {
+        match todo_store::create_todo(name, description).await {
+            Ok(_) => created += 1,
+            Err(StoreError::Conflict) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    println!("Created {} of {} sample todos", created, SEED_TODOS.len());
+    Ok(())
+}
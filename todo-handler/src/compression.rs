@@ -0,0 +1,135 @@
+use salvo::compression::{Compression, CompressionLevel};
+use salvo::http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use salvo::http::{Mime, ResBody};
+use salvo::prelude::*;
+
+use crate::config::CompressionConfig;
+
+// Compresses responses with zstd, brotli or gzip, whichever the client gives
+// the highest q-value in `Accept-Encoding`. Event streams are left out as compression would
+// hold events back until enough of them were buffered.
+
+const COMPRESSED_TYPES: [&str; 10] = [
+    "application/json",
+    "application/x-ndjson",
+    "application/xml",
+    "text/xml",
+    "text/html",
+    "text/plain",
+    "text/csv",
+    "text/markdown",
+    "text/calendar",
+    "application/javascript",
+];
+
+pub struct ResponseCompression {
+    compression: Compression,
+    content_types: Vec<Mime>,
+    min_length: usize,
+}
+
+impl ResponseCompression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let content_types: Vec<Mime> = COMPRESSED_TYPES.iter().filter_map(|content_type| content_type.parse().ok()).collect();
+        let compression = Compression::new()
+            .disable_all()
+            .enable_zstd(CompressionLevel::Default)
+            .enable_brotli(CompressionLevel::Default)
+            .enable_gzip(CompressionLevel::Default)
+            .content_types(&content_types)
+            .min_length(config.min_length);
+        ResponseCompression { compression, content_types, min_length: config.min_length }
+    }
+
+    // Whether the response was, or for another `Accept-Encoding` would have been, compressed
+    fn negotiated(&self, req: &Request, res: &Response) -> bool {
+        if res.headers().contains_key(CONTENT_ENCODING) {
+            return true;
+        }
+        if req.headers().contains_key(CONTENT_ENCODING)
+            || matches!(res.status_code, Some(StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT))
+        {
+            return false;
+        }
+
+        let compressible = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Mime>().ok())
+            .is_some_and(|content_type| {
+                self.content_types
+                    .iter()
+                    .any(|compressed| compressed.type_() == content_type.type_() && compressed.subtype() == content_type.subtype())
+            });
+        // Streamed bodies are compressed whatever their length
+        let long_enough = match &res.body {
+            ResBody::None => false,
+            ResBody::Once(bytes) => bytes.len() >= self.min_length,
+            ResBody::Chunks(chunks) => chunks.iter().map(|chunk| chunk.len()).sum::<usize>() >= self.min_length,
+            _ => true,
+        };
+        compressible && long_enough
+    }
+}
+
+#[handler]
+impl ResponseCompression {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        self.compression.handle(req, depot, res, ctrl).await;
+
+        // Caches must not hand a compressed body to clients that can't read it
+        if self.negotiated(req, res) && !varies_by_encoding(res) {
+            res.headers_mut().append(VARY, HeaderValue::from_static(ACCEPT_ENCODING.as_str()));
+        }
+    }
+}
+
+// Whether `Vary` already covers `Accept-Encoding`
+fn varies_by_encoding(res: &Response) -> bool {
+    res.headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::TestClient;
+
+    use super::*;
+
+    #[handler]
+    async fn long(res: &mut Response) {
+        res.add_header(VARY, "Origin", false).unwrap();
+        res.render(Text::Json(format!("[{}]", vec!["1"; 1000].join(","))));
+    }
+
+    #[handler]
+    async fn short(res: &mut Response) {
+        res.render(Text::Json("[]"));
+    }
+
+    #[tokio::test]
+    async fn varies_only_when_negotiated() {
+        let router = Router::new()
+            .hoop(ResponseCompression::new(&CompressionConfig::default()))
+            .push(Router::with_path("long").get(long))
+            .push(Router::with_path("short").get(short));
+        let service = Service::new(router);
+
+        for accept_encoding in ["gzip", "identity"] {
+            let res = TestClient::get("http://localhost/long").add_header(ACCEPT_ENCODING, accept_encoding, true).send(&service).await;
+            let vary: Vec<_> = res.headers().get_all(VARY).iter().collect();
+            assert_eq!(vary, ["Origin", "accept-encoding"]);
+        }
+
+        let res = TestClient::get("http://localhost/short").add_header(ACCEPT_ENCODING, "gzip", true).send(&service).await;
+        assert!(res.headers().get(VARY).is_none());
+    }
+}
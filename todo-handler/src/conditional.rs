@@ -0,0 +1,145 @@
+use chrono::{DateTime, Utc};
+use salvo::http::header::{HeaderValue, CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use salvo::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::backend_error::StoreError;
+use crate::schemas::TodoListResponse;
+use crate::todo_store;
+
+// Conditional GETs for the todo lists. The ETag hashes the JSON a client would
+// receive, so it changes with every todo; `Last-Modified` is the time of the
+// latest todo event. Clients sending either back get a 304 without a body while
+// the list stays the same.
+
+// Responses may be stored but are checked with the server before every use
+const REVALIDATE: &str = "no-cache";
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+// Renders every todo, or a 304 when the client's copy is still current
+pub async fn render_todo_list(req: &Request, res: &mut Response) -> Result<(), StoreError> {
+    let todos = todo_store::list_todos().await?;
+    let last_modified = todo_store::last_modified().await?;
+
+    let body = serde_json::to_string(&TodoListResponse { success: true, todos })
+        .map_err(|_| StoreError::Unexpected("Could not serialize the todo list"))?;
+    let etag = etag(body.as_bytes());
+
+    insert_header(res, ETAG, &etag);
+    insert_header(res, CACHE_CONTROL, REVALIDATE);
+    if let Some(last_modified) = last_modified {
+        insert_header(res, LAST_MODIFIED, &last_modified.format(HTTP_DATE).to_string());
+    }
+
+    if not_modified(req, &etag, last_modified) {
+        res.status_code(StatusCode::NOT_MODIFIED);
+    } else {
+        res.render(Text::Json(body));
+    }
+    Ok(())
+}
+
+// Weak, as compression changes the bytes but not the list they hold
+fn etag(body: &[u8]) -> String {
+    format!("W/\"{}\"", &hex::encode(Sha256::digest(body))[..32])
+}
+
+// `If-None-Match` wins over `If-Modified-Since`, which only has a precision of
+// seconds and goes back in time when a backup replaces the todos
+fn not_modified(req: &Request, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(tags) = req.header::<String>(IF_NONE_MATCH) {
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return tags.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+    }
+
+    let since = req
+        .header::<String>(IF_MODIFIED_SINCE)
+        .and_then(|since| DateTime::parse_from_rfc2822(&since).ok());
+    match (since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+fn insert_header<K: salvo::http::header::IntoHeaderName>(res: &mut Response, name: K, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        res.headers_mut().insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::HeaderName;
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::*;
+    use crate::test_support::{unique, with_database};
+
+    const URL: &str = "http://localhost/todos";
+
+    #[handler]
+    async fn todo_list(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+        render_todo_list(req, res).await
+    }
+
+    fn service() -> Service {
+        Service::new(Router::with_path("todos").get(todo_list))
+    }
+
+    // Sends `validator` of a fresh list back in `header`. Other tests change the todos
+    // too, so the list may be outdated by then and is fetched again a few times.
+    async fn revalidate(service: &Service, header: HeaderName, validator: HeaderName) -> Response {
+        let mut again = Response::new();
+        for _ in 0..10 {
+            let fresh = TestClient::get(URL).send(service).await;
+            let value = fresh.headers()[&validator].clone();
+            again = TestClient::get(URL).add_header(header.clone(), value, true).send(service).await;
+            if again.status_code == Some(StatusCode::NOT_MODIFIED) {
+                break;
+            }
+        }
+        again
+    }
+
+    #[test]
+    fn unchanged_list_is_not_modified_by_etag() {
+        with_database(async {
+            let todo = todo_store::create_todo(&unique("conditional-etag"), "").await.unwrap();
+            let mut res = revalidate(&service(), IF_NONE_MATCH, ETAG).await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_MODIFIED));
+            assert!(res.take_string().await.unwrap().is_empty());
+
+            todo_store::delete_todo(todo.id).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn unchanged_list_is_not_modified_by_date() {
+        with_database(async {
+            // Makes sure there is an event to date the list by
+            let todo = todo_store::create_todo(&unique("conditional-date"), "").await.unwrap();
+            let res = revalidate(&service(), IF_MODIFIED_SINCE, LAST_MODIFIED).await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_MODIFIED));
+
+            todo_store::delete_todo(todo.id).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn changed_list_is_sent_again() {
+        with_database(async {
+            let service = service();
+            let etag = TestClient::get(URL).send(&service).await.headers()[ETAG].clone();
+
+            let name = unique("conditional-change");
+            let todo = todo_store::create_todo(&name, "").await.unwrap();
+            let mut res = TestClient::get(URL).add_header(IF_NONE_MATCH, etag.clone(), true).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_ne!(res.headers()[ETAG], etag);
+            assert!(res.take_string().await.unwrap().contains(&name));
+
+            todo_store::delete_todo(todo.id).await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,803 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Args;
+use salvo::http::header::{HeaderName, HeaderValue};
+use salvo::http::Method;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::backend_error::BackendError;
+use crate::{cache, outbox};
+
+// Settings are layered: built-in defaults, then the TOML file, then `TODO_*`
+// environment variables, then command-line flags. The result is validated once
+// at startup so a bad value stops the handler before it binds anything.
+
+// Read when it exists and no other file was given
+const DEFAULT_CONFIG_FILE: &str = "todo-handler.toml";
+
+const REDACTED: &str = "redacted";
+
+// A century, far beyond any useful retention and far from overflowing
+const MAX_RETENTION_DAYS: u64 = 36_500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub outbox: OutboxConfig,
+    pub admin: AdminConfig,
+    pub telemetry: TelemetryConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub limits: LimitsConfig,
+    pub compression: CompressionConfig,
+    pub cache: CacheConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub grpc_bind: SocketAddr,
+    // How long in-flight requests and background work may take after SIGTERM
+    pub shutdown_timeout_secs: u64,
+    // Proxies in front of the server that append to `X-Forwarded-For`; 0 ignores the header
+    pub trusted_proxy_hops: usize,
+}
+
+// HTTPS on `server.bind` when both paths are set; gRPC stays plain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM files; the certificate file may hold the whole chain
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    // PEM bundle of the CAs client certificates must be signed by
+    pub client_ca_path: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    // How often the files are checked for changes
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    None,
+    Optional,
+    Required,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub idle_timeout_secs: u64,
+    pub acquire_timeout_secs: u64,
+    // Further attempts after the first failed connection
+    pub connect_retries: u32,
+    pub retry_delay_secs: u64,
+    // Read replica for list and lookup queries
+    pub replica_url: Option<String>,
+    // How long a caller's reads go to the primary after they changed something
+    pub read_your_writes_secs: u64,
+    // How long reads stay on the primary after the replica failed
+    pub replica_retry_secs: u64,
+    // Consecutive connection failures opening the circuit breaker
+    pub failure_threshold: u32,
+    // How often the background probe checks the primary
+    pub probe_interval_secs: u64,
+    // Further attempts of reads failing with a transient error
+    pub query_retries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxConfig {
+    // Comma separated, see `outbox::parse_sinks`
+    pub sinks: String,
+    // Days dispatched events are kept, 0 keeps them forever
+    pub retention_days: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // Accepted by the admin API besides the keys of `apikey issue`
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub log_format: LogFormat,
+    // `tracing_subscriber::EnvFilter` directives, e.g. `info,sqlx::query=debug`
+    pub log_filter: String,
+    // OTLP/gRPC collector receiving the spans, e.g. `http://localhost:4317`
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub per: RateLimitKey,
+    // Requests allowed per `period_secs`, also the largest burst
+    pub read_requests: u32,
+    pub write_requests: u32,
+    pub period_secs: u64,
+}
+
+// Whom a request with a valid API key is counted against; other requests count against their IP
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    User,
+    ApiKey,
+    Ip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // Exact origins such as `https://app.example.com`, or `*`; empty turns CORS off
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // Response headers browsers let scripts read
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // How long browsers may cache a preflight answer
+    pub max_age_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub content_security_policy: String,
+    // `Strict-Transport-Security` over TLS; 0 leaves it out
+    pub hsts_max_age_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_body_bytes: usize,
+    // Applies to `POST /admin/restore` instead, as archives carry the whole history
+    pub max_restore_bytes: usize,
+    // Nesting of arrays and objects in JSON bodies
+    pub max_json_depth: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Smaller bodies are sent as they are
+    pub min_length: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // `none` or `memory`, see `cache::parse_backend`
+    pub backend: String,
+    // Also how long other instances may serve a todo changed here
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+}
+
+// Flags that override the file and the environment
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    /// TOML configuration file [env: TODO_CONFIG] [default: todo-handler.toml when present]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// HTTP listen address [env: TODO_BIND_ADDR]
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub bind: Option<SocketAddr>,
+
+    /// gRPC listen address [env: TODO_GRPC_ADDR]
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub grpc_bind: Option<SocketAddr>,
+
+    /// Postgres connection URL [env: TODO_DATABASE_URL or DATABASE_URL]
+    #[arg(long, global = true, value_name = "URL")]
+    pub database_url: Option<String>,
+
+    /// Postgres read replica URL [env: TODO_DATABASE_REPLICA_URL or DATABASE_REPLICA_URL]
+    #[arg(long, global = true, value_name = "URL")]
+    pub database_replica_url: Option<String>,
+
+    /// Comma separated outbox sinks [env: TODO_OUTBOX_SINKS]
+    #[arg(long, global = true, value_name = "SINKS")]
+    pub outbox_sinks: Option<String>,
+
+    /// `pretty` or `json` logs [env: TODO_LOG_FORMAT]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 7878)),
+            grpc_bind: SocketAddr::from(([127, 0, 0, 1], 50051)),
+            shutdown_timeout_secs: 30,
+            trusted_proxy_hops: 0,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_path: None,
+            key_path: None,
+            client_ca_path: None,
+            client_auth: ClientAuth::None,
+            reload_interval_secs: 10,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            max_connections: 15,
+            min_connections: 2,
+            idle_timeout_secs: 60,
+            acquire_timeout_secs: 30,
+            connect_retries: 5,
+            retry_delay_secs: 3,
+            replica_url: None,
+            read_your_writes_secs: 5,
+            replica_retry_secs: 10,
+            failure_threshold: 5,
+            probe_interval_secs: 5,
+            query_retries: 2,
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig { sinks: "broadcast,webhook".to_string(), retention_days: 30 }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            log_format: LogFormat::Pretty,
+            log_filter: "info".to_string(),
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: strings(&["GET", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: strings(&["authorization", "content-type", "x-request-id"]),
+            exposed_headers: strings(&[
+                "x-request-id",
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+                "ratelimit-policy",
+                "retry-after",
+                "deprecation",
+                "link",
+                "etag",
+            ]),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: true,
+            content_security_policy: "default-src 'none'; frame-ancestors 'none'".to_string(),
+            hsts_max_age_secs: 31_536_000,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig { max_body_bytes: 10 * 1024 * 1024, max_restore_bytes: 256 * 1024 * 1024, max_json_depth: 32 }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig { enabled: true, min_length: 1024 }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { backend: "none".to_string(), ttl_secs: 5, max_entries: 10_000 }
+    }
+}
+
+impl OutboxConfig {
+    pub fn retention(&self) -> Option<Duration> {
+        // Too long to count is as good as forever
+        let secs = self.retention_days.checked_mul(24 * 60 * 60)?;
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+impl CacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            per: RateLimitKey::User,
+            read_requests: 600,
+            write_requests: 120,
+            period_secs: 60,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn period(&self) -> Duration {
+        Duration::from_secs(self.period_secs)
+    }
+}
+
+impl FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "user" => Ok(RateLimitKey::User),
+            "api_key" => Ok(RateLimitKey::ApiKey),
+            "ip" => Ok(RateLimitKey::Ip),
+            other => Err(format!("unknown rate limit key '{}', expected 'user', 'api_key' or 'ip'", other)),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}', expected 'pretty' or 'json'", other)),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(ClientAuth::None),
+            "optional" => Ok(ClientAuth::Optional),
+            "required" => Ok(ClientAuth::Required),
+            other => Err(format!("unknown client auth '{}', expected 'none', 'optional' or 'required'", other)),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn url(&self) -> &str {
+        // Checked by `Config::validate`
+        self.url.as_deref().unwrap_or_default()
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_secs)
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_delay_secs)
+    }
+
+    pub fn read_your_writes(&self) -> Duration {
+        Duration::from_secs(self.read_your_writes_secs)
+    }
+
+    pub fn replica_retry(&self) -> Duration {
+        Duration::from_secs(self.replica_retry_secs)
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_secs(self.probe_interval_secs)
+    }
+}
+
+impl Config {
+    pub fn load(args: &ConfigArgs) -> Result<Config, BackendError> {
+        let mut config = match config_file(args)? {
+            Some(path) => read_file(&path)?,
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), BackendError> {
+        env_override("TODO_BIND_ADDR", &mut self.server.bind)?;
+        env_override("TODO_GRPC_ADDR", &mut self.server.grpc_bind)?;
+        env_override("TODO_SHUTDOWN_TIMEOUT_SECS", &mut self.server.shutdown_timeout_secs)?;
+        env_override("TODO_TRUSTED_PROXY_HOPS", &mut self.server.trusted_proxy_hops)?;
+
+        for (name, path) in [
+            ("TODO_TLS_CERT_PATH", &mut self.tls.cert_path),
+            ("TODO_TLS_KEY_PATH", &mut self.tls.key_path),
+            ("TODO_TLS_CLIENT_CA_PATH", &mut self.tls.client_ca_path),
+        ] {
+            if let Some(value) = env_value(name) {
+                *path = Some(PathBuf::from(value));
+            }
+        }
+        env_override("TODO_TLS_CLIENT_AUTH", &mut self.tls.client_auth)?;
+        env_override("TODO_TLS_RELOAD_INTERVAL_SECS", &mut self.tls.reload_interval_secs)?;
+
+        // `DATABASE_URL` is still read, as before configuration files existed
+        for name in ["DATABASE_URL", "TODO_DATABASE_URL"] {
+            if let Some(url) = env_value(name) {
+                self.database.url = Some(url);
+            }
+        }
+        for name in ["DATABASE_REPLICA_URL", "TODO_DATABASE_REPLICA_URL"] {
+            if let Some(url) = env_value(name) {
+                self.database.replica_url = Some(url);
+            }
+        }
+        env_override("TODO_DB_MAX_CONNECTIONS", &mut self.database.max_connections)?;
+        env_override("TODO_DB_MIN_CONNECTIONS", &mut self.database.min_connections)?;
+        env_override("TODO_DB_IDLE_TIMEOUT_SECS", &mut self.database.idle_timeout_secs)?;
+        env_override("TODO_DB_ACQUIRE_TIMEOUT_SECS", &mut self.database.acquire_timeout_secs)?;
+        env_override("TODO_DB_CONNECT_RETRIES", &mut self.database.connect_retries)?;
+        env_override("TODO_DB_RETRY_DELAY_SECS", &mut self.database.retry_delay_secs)?;
+        env_override("TODO_DB_READ_YOUR_WRITES_SECS", &mut self.database.read_your_writes_secs)?;
+        env_override("TODO_DB_REPLICA_RETRY_SECS", &mut self.database.replica_retry_secs)?;
+        env_override("TODO_DB_FAILURE_THRESHOLD", &mut self.database.failure_threshold)?;
+        env_override("TODO_DB_PROBE_INTERVAL_SECS", &mut self.database.probe_interval_secs)?;
+        env_override("TODO_DB_QUERY_RETRIES", &mut self.database.query_retries)?;
+
+        env_override("TODO_OUTBOX_SINKS", &mut self.outbox.sinks)?;
+        env_override("TODO_OUTBOX_RETENTION_DAYS", &mut self.outbox.retention_days)?;
+
+        if let Some(token) = env_value("TODO_ADMIN_TOKEN") {
+            self.admin.token = Some(token);
+        }
+
+        // `RUST_LOG` is honoured like in most Rust services, `TODO_LOG` wins over it
+        for name in ["RUST_LOG", "TODO_LOG"] {
+            if let Some(filter) = env_value(name) {
+                self.telemetry.log_filter = filter;
+            }
+        }
+        env_override("TODO_LOG_FORMAT", &mut self.telemetry.log_format)?;
+        if let Some(endpoint) = env_value("TODO_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        env_override("TODO_SERVICE_NAME", &mut self.telemetry.service_name)?;
+
+        if let Some(origins) = env_value("TODO_CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins.split(',').map(|origin| origin.trim().to_string()).filter(|origin| !origin.is_empty()).collect();
+        }
+        env_override("TODO_CORS_ALLOW_CREDENTIALS", &mut self.cors.allow_credentials)?;
+        env_override("TODO_SECURITY_HEADERS_ENABLED", &mut self.security_headers.enabled)?;
+        env_override("TODO_MAX_BODY_BYTES", &mut self.limits.max_body_bytes)?;
+        env_override("TODO_MAX_RESTORE_BYTES", &mut self.limits.max_restore_bytes)?;
+        env_override("TODO_MAX_JSON_DEPTH", &mut self.limits.max_json_depth)?;
+        env_override("TODO_COMPRESSION_ENABLED", &mut self.compression.enabled)?;
+        env_override("TODO_COMPRESSION_MIN_LENGTH", &mut self.compression.min_length)?;
+        env_override("TODO_CACHE_BACKEND", &mut self.cache.backend)?;
+        env_override("TODO_CACHE_TTL_SECS", &mut self.cache.ttl_secs)?;
+
+        env_override("TODO_RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled)?;
+        env_override("TODO_RATE_LIMIT_PER", &mut self.rate_limit.per)?;
+        env_override("TODO_RATE_LIMIT_READ_REQUESTS", &mut self.rate_limit.read_requests)?;
+        env_override("TODO_RATE_LIMIT_WRITE_REQUESTS", &mut self.rate_limit.write_requests)?;
+        env_override("TODO_RATE_LIMIT_PERIOD_SECS", &mut self.rate_limit.period_secs)?;
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(bind) = args.bind {
+            self.server.bind = bind;
+        }
+        if let Some(grpc_bind) = args.grpc_bind {
+            self.server.grpc_bind = grpc_bind;
+        }
+        if let Some(url) = &args.database_url {
+            self.database.url = Some(url.clone());
+        }
+        if let Some(url) = &args.database_replica_url {
+            self.database.replica_url = Some(url.clone());
+        }
+        if let Some(sinks) = &args.outbox_sinks {
+            self.outbox.sinks = sinks.clone();
+        }
+        if let Some(log_format) = args.log_format {
+            self.telemetry.log_format = log_format;
+        }
+    }
+
+    fn validate(&self) -> Result<(), BackendError> {
+        let invalid = |message: String| Err(BackendError::ConfigError(message));
+
+        match self.database.url.as_deref() {
+            None | Some("") => {
+                return invalid("database.url is required (or set TODO_DATABASE_URL / DATABASE_URL)".to_string());
+            }
+            Some(url) if !url.starts_with("postgres://") && !url.starts_with("postgresql://") => {
+                return invalid("database.url must be a postgres:// URL".to_string());
+            }
+            Some(_) => {}
+        }
+        if let Some(url) = self.database.replica_url.as_deref() {
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                return invalid("database.replica_url must be a postgres:// URL".to_string());
+            }
+        }
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be at least 1".to_string());
+        }
+        if self.database.min_connections > self.database.max_connections {
+            return invalid(format!(
+                "database.min_connections ({}) is larger than database.max_connections ({})",
+                self.database.min_connections, self.database.max_connections
+            ));
+        }
+        if self.database.acquire_timeout_secs == 0 {
+            return invalid("database.acquire_timeout_secs must be at least 1".to_string());
+        }
+        if self.database.replica_retry_secs == 0 {
+            return invalid("database.replica_retry_secs must be at least 1".to_string());
+        }
+        if self.database.failure_threshold == 0 || self.database.probe_interval_secs == 0 {
+            return invalid("database.failure_threshold and database.probe_interval_secs must be at least 1".to_string());
+        }
+        if self.server.bind == self.server.grpc_bind {
+            return invalid(format!("server.bind and server.grpc_bind both use {}", self.server.bind));
+        }
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return invalid("tls.cert_path and tls.key_path must be set together".to_string());
+        }
+        if self.tls.client_auth != ClientAuth::None && self.tls.client_ca_path.is_none() {
+            return invalid("tls.client_auth needs tls.client_ca_path".to_string());
+        }
+        if self.tls.client_ca_path.is_some() && !self.tls.enabled() {
+            return invalid("tls.client_ca_path needs tls.cert_path and tls.key_path".to_string());
+        }
+        if self.tls.enabled() && self.tls.reload_interval_secs == 0 {
+            return invalid("tls.reload_interval_secs must be at least 1".to_string());
+        }
+        if self.admin.token.as_deref().is_some_and(str::is_empty) {
+            return invalid("admin.token must not be empty; leave it out to only accept API keys".to_string());
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.telemetry.log_filter) {
+            return invalid(format!("Invalid telemetry.log_filter '{}': {}", self.telemetry.log_filter, e));
+        }
+        match self.telemetry.otlp_endpoint.as_deref() {
+            Some(endpoint) if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") => {
+                return invalid("telemetry.otlp_endpoint must be an http:// or https:// URL".to_string());
+            }
+            _ => {}
+        }
+        if self.telemetry.service_name.trim().is_empty() {
+            return invalid("telemetry.service_name must not be empty".to_string());
+        }
+
+        if self.rate_limit.read_requests == 0 || self.rate_limit.write_requests == 0 {
+            return invalid("rate_limit.read_requests and rate_limit.write_requests must be at least 1; set rate_limit.enabled = false to turn limiting off".to_string());
+        }
+        if self.rate_limit.period_secs == 0 {
+            return invalid("rate_limit.period_secs must be at least 1".to_string());
+        }
+
+        let any_origin = self.cors.allowed_origins.iter().any(|origin| origin == "*");
+        if self.cors.allow_credentials && any_origin {
+            return invalid("cors.allow_credentials can't be combined with the '*' origin".to_string());
+        }
+        if any_origin && self.cors.allowed_origins.len() > 1 {
+            return invalid("cors.allowed_origins must list either '*' or exact origins".to_string());
+        }
+        if let Some(origin) = self.cors.allowed_origins.iter().find(|origin| {
+            *origin != "*" && (HeaderValue::from_str(origin).is_err() || !(origin.starts_with("http://") || origin.starts_with("https://")) || origin.ends_with('/'))
+        }) {
+            return invalid(format!("Invalid cors.allowed_origins entry '{}', expected e.g. https://app.example.com", origin));
+        }
+        if let Some(method) = self.cors.allowed_methods.iter().find(|method| Method::from_str(method).is_err()) {
+            return invalid(format!("Invalid cors.allowed_methods entry '{}'", method));
+        }
+        if let Some(header) = self.cors.allowed_headers.iter().chain(&self.cors.exposed_headers).find(|header| HeaderName::from_str(header).is_err()) {
+            return invalid(format!("Invalid header name '{}' in cors", header));
+        }
+        if HeaderValue::from_str(&self.security_headers.content_security_policy).is_err() {
+            return invalid("security_headers.content_security_policy is not a valid header value".to_string());
+        }
+        if self.limits.max_body_bytes == 0 || self.limits.max_restore_bytes == 0 || self.limits.max_json_depth == 0 {
+            return invalid("limits.max_body_bytes, limits.max_restore_bytes and limits.max_json_depth must be at least 1".to_string());
+        }
+
+        if self.cache.ttl_secs == 0 || self.cache.max_entries == 0 {
+            return invalid("cache.ttl_secs and cache.max_entries must be at least 1".to_string());
+        }
+
+        if self.outbox.retention_days > MAX_RETENTION_DAYS {
+            return invalid(format!("outbox.retention_days must be at most {}, or 0 to keep events forever", MAX_RETENTION_DAYS));
+        }
+        outbox::parse_sinks(&self.outbox.sinks)?;
+        cache::parse_backend(&self.cache)?;
+        Ok(())
+    }
+
+    // Copy safe to print, with passwords and tokens replaced
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        config.database.url = config.database.url.map(|url| redact_url(&url));
+        config.database.replica_url = config.database.replica_url.map(|url| redact_url(&url));
+        config.admin.token = config.admin.token.map(|_| REDACTED.to_string());
+        config
+    }
+
+    pub fn to_toml(&self) -> Result<String, BackendError> {
+        toml::to_string_pretty(self).map_err(|e| BackendError::ConfigError(format!("Could not print the configuration: {}", e)))
+    }
+}
+
+// `--config`, then `TODO_CONFIG`, then `todo-handler.toml` when it exists
+fn config_file(args: &ConfigArgs) -> Result<Option<PathBuf>, BackendError> {
+    let explicit = args.config.clone().or_else(|| env_value("TODO_CONFIG").map(PathBuf::from));
+
+    match explicit {
+        Some(path) if !path.exists() => {
+            Err(BackendError::ConfigError(format!("Configuration file {} does not exist", path.display())))
+        }
+        Some(path) => Ok(Some(path)),
+        None => Ok(Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists())),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Config, BackendError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| BackendError::ConfigError(format!("Could not read {}: {}", path.display(), e)))?;
+
+    toml::from_str(&contents).map_err(|e| BackendError::ConfigError(format!("Invalid {}: {}", path.display(), e)))
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_override<T: FromStr>(name: &str, target: &mut T) -> Result<(), BackendError>
+where
+    T::Err: Display,
+{
+    if let Some(value) = env_value(name) {
+        *target = value
+            .parse()
+            .map_err(|e| BackendError::ConfigError(format!("Invalid {} '{}': {}", name, value, e)))?;
+    }
+    Ok(())
+}
+
+// The password can be in the user info and, as libpq allows, in the query
+fn redact_url(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return REDACTED.to_string();
+    };
+    let is_secret = |key: &str| key.eq_ignore_ascii_case("password") || key.eq_ignore_ascii_case("sslpassword");
+
+    let mut redacted = false;
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some(REDACTED));
+        redacted = true;
+    }
+    if parsed.query_pairs().any(|(key, _)| is_secret(&key)) {
+        let pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .map(|(key, value)| {
+                let value = if is_secret(&key) { REDACTED.to_string() } else { value.into_owned() };
+                (key.into_owned(), value)
+            })
+            .collect();
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+        redacted = true;
+    }
+
+    if redacted {
+        parsed.to_string()
+    } else {
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_passwords_in_database_urls() {
+        assert_eq!(redact_url("postgres://todo:hunter2@db/todo"), format!("postgres://todo:{}@db/todo", REDACTED));
+        assert_eq!(
+            redact_url("postgres://todo@db/todo?sslmode=require&Password=hunter2&sslpassword=key"),
+            format!("postgres://todo@db/todo?sslmode=require&Password={0}&sslpassword={0}", REDACTED)
+        );
+        assert_eq!(redact_url("postgres://todo@db/todo?sslmode=require"), "postgres://todo@db/todo?sslmode=require");
+        assert_eq!(redact_url("not a url"), REDACTED);
+    }
+
+    #[test]
+    fn retention_is_bounded() {
+        let mut config = Config::default();
+        config.database.url = Some("postgres://db/todo".to_string());
+        config.outbox.retention_days = u64::MAX;
+        assert_eq!(config.outbox.retention(), None);
+        assert!(config.validate().is_err());
+
+        config.outbox.retention_days = MAX_RETENTION_DAYS;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.outbox.retention(), Some(Duration::from_secs(MAX_RETENTION_DAYS * 24 * 60 * 60)));
+    }
+}
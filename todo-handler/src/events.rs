@@ -0,0 +1,104 @@
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use salvo::oapi::ToSchema;
+use salvo::prelude::*;
+use salvo::sse::{SseEvent, SseKeepAlive};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{shutdown, Todo};
+
+// Slow subscribers that fall this far behind start missing events
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+static EVENTS: Lazy<broadcast::Sender<TodoEvent>> = Lazy::new(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema, async_graphql::Enum)]
+#[serde(rename_all = "snake_case")]
+#[salvo(schema(symbol = "TodoEventKind"))]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    MarkedDone,
+    Deleted,
+}
+
+impl TodoEventKind {
+    pub const ALL: [TodoEventKind; 4] = [
+        TodoEventKind::Created,
+        TodoEventKind::Updated,
+        TodoEventKind::MarkedDone,
+        TodoEventKind::Deleted,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TodoEventKind::Created => "created",
+            TodoEventKind::Updated => "updated",
+            TodoEventKind::MarkedDone => "marked_done",
+            TodoEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, async_graphql::SimpleObject)]
+#[salvo(schema(symbol = "TodoEvent"))]
+pub struct TodoEvent {
+    // Assigned by the outbox, stable across redeliveries
+    #[serde(default)]
+    pub event_id: i64,
+    pub kind: TodoEventKind,
+    pub todo_id: i32,
+    // Not present for deletions
+    pub todo: Option<Todo>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl TodoEvent {
+    pub fn new(kind: TodoEventKind, todo: &Todo) -> Self {
+        TodoEvent { event_id: 0, kind, todo_id: todo.id, todo: Some(todo.clone()), occurred_at: Utc::now() }
+    }
+
+    pub fn deleted(todo_id: i32) -> Self {
+        TodoEvent { event_id: 0, kind: TodoEventKind::Deleted, todo_id, todo: None, occurred_at: Utc::now() }
+    }
+}
+
+pub fn publish(event: TodoEvent) {
+    // Sending only fails when nobody is listening, which is fine
+    let _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<TodoEvent> {
+    EVENTS.subscribe()
+}
+
+/// Stream every todo change as server-sent events.
+///
+/// Each event is named after its kind and carries the `event_id` as its id.
+#[endpoint(
+    tags("todos"),
+    responses((status_code = 200, description = "`text/event-stream` of todo events", body = TodoEvent, content_type = "text/event-stream"))
+)]
+pub async fn event_stream(res: &mut Response) {
+    let events = stream::unfold(subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let sse_event = SseEvent::default()
+                        .name(event.kind.as_str())
+                        .id(event.event_id.to_string())
+                        .json(&event);
+                    return Some((sse_event, receiver));
+                }
+                // Clients can resync through `GET /todos` when ids jump
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    // End the stream on shutdown so it doesn't hold up draining connections
+    SseKeepAlive::new(events.take_until(shutdown::wait())).stream(res);
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::future::ready;
+
+use async_graphql::http::{GraphiQLSource, WebSocket as GraphQLWebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{ComplexObject, Context, ErrorExtensions, Object, Schema, Subscription};
+use futures_util::{SinkExt, Stream, StreamExt};
+use once_cell::sync::Lazy;
+use salvo::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL, UPGRADE};
+use salvo::prelude::*;
+use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::backend_error::StoreError;
+use crate::events::{self, TodoEvent};
+use crate::{shutdown, todo_store, Todo};
+
+// Queries are rejected before execution when they nest or cost more than this.
+// Both leave enough room for the introspection query GraphiQL sends.
+const MAX_QUERY_DEPTH: usize = 16;
+const MAX_QUERY_COMPLEXITY: usize = 512;
+
+pub type TodoSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+static SCHEMA: Lazy<TodoSchema> = Lazy::new(|| {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        // Without a cache, so they only batch and never serve outdated values
+        .data(DataLoader::new(ProjectLoader, tokio::spawn))
+        .data(DataLoader::new(SubtaskLoader, tokio::spawn))
+        .finish()
+});
+
+pub struct QueryRoot;
+
+pub struct MutationRoot;
+
+pub struct SubscriptionRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Every todo.
+    async fn todos(&self) -> async_graphql::Result<Vec<Todo>> {
+        todo_store::list_todos().await.map_err(graphql_error)
+    }
+
+    /// The todo with the given id, if it exists.
+    async fn todo(&self, id: i32) -> async_graphql::Result<Option<Todo>> {
+        todo_store::fetch_todo(id).await.map_err(graphql_error)
+    }
+}
+
+// Fields kept in the Markdown outline. Todos have no tags, so there are none to expose.
+// They are loaded for every todo of a response at once rather than one query per todo.
+#[ComplexObject]
+impl Todo {
+    /// The heading the todo is listed under in Markdown checklists, if any.
+    async fn project(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        ctx.data_unchecked::<DataLoader<ProjectLoader>>().load_one(self.id).await
+    }
+
+    /// The todos nested under this one in Markdown checklists, in their order.
+    async fn subtasks(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Todo>> {
+        let subtasks = ctx.data_unchecked::<DataLoader<SubtaskLoader>>().load_one(self.id).await?;
+        Ok(subtasks.unwrap_or_default())
+    }
+}
+
+// Batches the `project` lookups of a response
+struct ProjectLoader;
+
+// Batches the `subtasks` lookups of a response
+struct SubtaskLoader;
+
+impl Loader<i32> for ProjectLoader {
+    type Value = String;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, todo_ids: &[i32]) -> async_graphql::Result<HashMap<i32, String>> {
+        todo_store::fetch_projects(todo_ids).await.map_err(graphql_error)
+    }
+}
+
+impl Loader<i32> for SubtaskLoader {
+    type Value = Vec<Todo>;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, todo_ids: &[i32]) -> async_graphql::Result<HashMap<i32, Vec<Todo>>> {
+        todo_store::list_subtasks(todo_ids).await.map_err(graphql_error)
+    }
+}
+
+#[Object]
+impl MutationRoot {
+    async fn create_todo(&self, name: String, description: String) -> async_graphql::Result<Todo> {
+        todo_store::create_todo(&name, &description).await.map_err(graphql_error)
+    }
+
+    /// Replaces every field of a todo.
+    async fn update_todo(&self, id: i32, name: String, description: String, done: bool) -> async_graphql::Result<Todo> {
+        todo_store::update_todo(id, &name, &description, done).await.map_err(graphql_error)
+    }
+
+    /// Marks a todo as done.
+    async fn md_todo(&self, id: i32) -> async_graphql::Result<Todo> {
+        todo_store::mark_done(id).await.map_err(graphql_error)
+    }
+
+    /// Deletes a todo and returns its id.
+    async fn delete_todo(&self, id: i32) -> async_graphql::Result<i32> {
+        todo_store::delete_todo(id).await.map_err(graphql_error)?;
+        Ok(id)
+    }
+}
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every todo change, or only changes of the given todos.
+    async fn todo_changed(&self, todo_ids: Option<Vec<i32>>) -> impl Stream<Item = TodoEvent> {
+        futures_util::stream::unfold(events::subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |event| ready(todo_ids.as_ref().is_none_or(|ids| ids.contains(&event.todo_id))))
+    }
+}
+
+// Keeps the HTTP status of the store error available to clients as `extensions.code`
+fn graphql_error(e: StoreError) -> async_graphql::Error {
+    let status_code = e.status_code().as_u16();
+    e.extend_with(|_, extensions| extensions.set("code", status_code))
+}
+
+/// Execute a GraphQL query or mutation.
+///
+/// Subscriptions are served over a WebSocket on `GET /graphql`.
+#[endpoint(
+    tags("graphql"),
+    status_codes(200, 400),
+    request_body(content = serde_json::Value, description = "`{ \"query\": \"...\", \"operationName\": \"...\", \"variables\": {...} }`"),
+    responses((status_code = 200, description = "GraphQL response with `data` and `errors`", body = serde_json::Value))
+)]
+pub async fn graphql_request(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let request = req.parse_json::<async_graphql::Request>()
+        .await
+        .map_err(|_| StoreError::BadRequest("Invalid GraphQL request".to_string()))?;
+
+    res.render(Json(SCHEMA.execute(request).await));
+    Ok(())
+}
+
+/// Open a GraphQL subscription WebSocket, or the GraphiQL playground in debug builds.
+///
+/// Both the `graphql-transport-ws` and the legacy `graphql-ws` protocols are supported.
+#[endpoint(
+    tags("graphql"),
+    status_codes(101, 200, 400, 404),
+    responses(
+        (status_code = 101, description = "Switching to the WebSocket protocol"),
+        (status_code = 200, description = "GraphiQL playground", content_type = "text/html")
+    )
+)]
+pub async fn graphql_socket(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    let is_upgrade = req.headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+
+    if !is_upgrade {
+        if !cfg!(debug_assertions) {
+            return Err(StatusError::not_found());
+        }
+        res.render(Text::Html(
+            GraphiQLSource::build()
+                .endpoint("/graphql")
+                .subscription_endpoint("/graphql")
+                .finish()
+        ));
+        return Ok(());
+    }
+
+    // Use the first protocol offered by the client that we understand
+    let protocol = req.headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .and_then(|protocols| protocols.split(',').find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok()))
+        .ok_or_else(|| StatusError::bad_request().brief("Unsupported GraphQL WebSocket protocol"))?;
+
+    WebSocketUpgrade::new()
+        .upgrade(req, res, move |ws| serve_subscriptions(ws, protocol))
+        .await?;
+    res.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.sec_websocket_protocol()));
+    Ok(())
+}
+
+async fn serve_subscriptions(ws: WebSocket, protocol: WebSocketProtocols) {
+    let (mut sink, stream) = ws.split();
+
+    let input = stream
+        .take_while(|msg| ready(msg.is_ok()))
+        .filter_map(|msg| ready(msg.ok().filter(|msg| msg.is_text() || msg.is_binary()).map(Message::into_bytes)));
+    let mut output = GraphQLWebSocket::new(SCHEMA.clone(), input, protocol).take_until(Box::pin(shutdown::wait()));
+
+    while let Some(msg) = output.next().await {
+        let msg = match msg {
+            WsMessage::Text(text) => Message::text(text),
+            WsMessage::Close(code, reason) => Message::close_with(code, reason),
+        };
+        if sink.send(msg).await.is_err() {
+            break;
+        }
+    }
+
+    if shutdown::is_shutting_down() {
+        let _ = sink.send(Message::close_with(1001u16, "Server shutting down")).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{unique, with_database};
+
+    #[test]
+    fn runs_mutations_and_queries() {
+        with_database(async {
+            let name = unique("graphql-test");
+            let created = SCHEMA
+                .execute(format!(r#"mutation {{ createTodo(name: "{}", description: "From GraphQL") {{ id done }} }}"#, name))
+                .await;
+            assert!(created.errors.is_empty(), "{:?}", created.errors);
+            let created = created.data.into_json().unwrap();
+            let id = created["createTodo"]["id"].as_i64().unwrap();
+            assert_eq!(created["createTodo"]["done"], false);
+
+            let fetched = SCHEMA.execute(format!("{{ todo(id: {}) {{ name description project subtasks {{ id }} }} }}", id)).await;
+            assert!(fetched.errors.is_empty(), "{:?}", fetched.errors);
+            let fetched = fetched.data.into_json().unwrap();
+            assert_eq!(fetched["todo"]["name"], name.as_str());
+            assert_eq!(fetched["todo"]["description"], "From GraphQL");
+            assert_eq!(fetched["todo"]["project"], serde_json::Value::Null);
+            assert_eq!(fetched["todo"]["subtasks"], serde_json::json!([]));
+
+            let deleted = SCHEMA.execute(format!("mutation {{ deleteTodo(id: {}) }}", id)).await;
+            assert!(deleted.errors.is_empty(), "{:?}", deleted.errors);
+            let missing = SCHEMA.execute(format!("{{ todo(id: {}) {{ id }} }}", id)).await;
+            assert_eq!(missing.data.into_json().unwrap()["todo"], serde_json::Value::Null);
+        });
+    }
+
+    #[test]
+    fn loads_the_outline_of_every_todo() {
+        with_database(async {
+            let parent = todo_store::create_todo(&unique("graphql-parent"), "").await.unwrap();
+            let mut children = Vec::new();
+            for position in 0..2 {
+                let child = todo_store::create_todo(&unique("graphql-child"), "").await.unwrap();
+                sqlx::query("INSERT INTO todo_outline (todo_id, project, parent_id, position) VALUES ($1, 'Garden', $2, $3)")
+                    .bind(child.id)
+                    .bind(parent.id)
+                    .bind(position)
+                    .execute(crate::get_postgres())
+                    .await
+                    .unwrap();
+                children.push(child.id);
+            }
+
+            let response = SCHEMA.execute(format!("{{ todo(id: {}) {{ project subtasks {{ id project subtasks {{ id }} }} }} }}", parent.id)).await;
+            assert!(response.errors.is_empty(), "{:?}", response.errors);
+            let todo = &response.data.into_json().unwrap()["todo"];
+            assert_eq!(todo["project"], serde_json::Value::Null);
+            assert_eq!(
+                todo["subtasks"],
+                serde_json::json!(children.iter().map(|id| serde_json::json!({ "id": id, "project": "Garden", "subtasks": [] })).collect::<Vec<_>>())
+            );
+
+            for id in children.into_iter().chain([parent.id]) {
+                todo_store::delete_todo(id).await.unwrap();
+            }
+        });
+    }
+
+    // Rejected before anything runs, so no database is needed
+    #[tokio::test]
+    async fn rejects_deep_queries() {
+        let query = format!("{{ todos {{ {}id{} }} }}", "subtasks { ".repeat(MAX_QUERY_DEPTH), " }".repeat(MAX_QUERY_DEPTH));
+        let response = SCHEMA.execute(query).await;
+        assert!(response.errors.iter().any(|e| e.message.contains("nested too deep")), "{:?}", response.errors);
+    }
+
+    #[tokio::test]
+    async fn rejects_complex_queries() {
+        let fields: String = (0..MAX_QUERY_COMPLEXITY).map(|i| format!("t{}: todos {{ id }} ", i)).collect();
+        let response = SCHEMA.execute(format!("{{ {} }}", fields)).await;
+        assert!(response.errors.iter().any(|e| e.message.contains("too complex")), "{:?}", response.errors);
+    }
+}
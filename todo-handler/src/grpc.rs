@@ -0,0 +1,243 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+
+use crate::backend_error::{BackendError, StoreError};
+use crate::events::{self, TodoEventKind};
+use crate::{circuit_breaker, health, shutdown, telemetry, todo_store, Todo};
+
+pub mod proto {
+    tonic::include_proto!("todo.v1");
+}
+
+use proto::todo_service_server::{TodoService, TodoServiceServer};
+
+// `TodoService` from proto/todo.proto, backed by the same store as the HTTP API
+pub struct GrpcTodoService;
+
+// Serves the gRPC API on its own listener until shutdown, then lets calls in
+// progress finish for at most `grace`
+pub async fn serve(addr: SocketAddr, grace: Duration) -> Result<(), BackendError> {
+    let _alive = health::GRPC_SERVER.mark_alive();
+
+    let server = tonic::transport::Server::builder()
+        .trace_fn(|request| {
+            let span = tracing::info_span!(
+                "grpc_request",
+                method = request.uri().path(),
+                request_id = %telemetry::request_id(request.headers()),
+            );
+            telemetry::continue_trace(&span, request.headers());
+            span
+        })
+        .add_service(TodoServiceServer::new(GrpcTodoService))
+        .serve_with_shutdown(addr, shutdown::wait());
+
+    tokio::select! {
+        result = server => result?,
+        _ = shutdown::deadline(grace) => tracing::warn!("gRPC calls still running after the shutdown timeout were cut off"),
+    }
+    Ok(())
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::TodoEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl TodoService for GrpcTodoService {
+    async fn list(&self, _request: Request<proto::ListTodosRequest>) -> Result<Response<proto::ListTodosResponse>, Status> {
+        let todos = todo_store::list_todos().await?;
+
+        Ok(Response::new(proto::ListTodosResponse {
+            todos: todos.into_iter().map(proto::Todo::from).collect(),
+        }))
+    }
+
+    async fn get(&self, request: Request<proto::GetTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let todo_id = request.into_inner().id;
+
+        let todo = todo_store::fetch_todo(todo_id).await?.ok_or(StoreError::NotFound(todo_id))?;
+
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn create(&self, request: Request<proto::CreateTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let request = request.into_inner();
+
+        let todo = todo_store::create_todo(&request.name, &request.description).await?;
+
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn update(&self, request: Request<proto::UpdateTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let request = request.into_inner();
+
+        let todo = todo_store::update_todo(request.id, &request.name, &request.description, request.done).await?;
+
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn mark_done(&self, request: Request<proto::MarkDoneRequest>) -> Result<Response<proto::Todo>, Status> {
+        let todo = todo_store::mark_done(request.into_inner().id).await?;
+
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn delete(&self, request: Request<proto::DeleteTodoRequest>) -> Result<Response<proto::DeleteTodoResponse>, Status> {
+        todo_store::delete_todo(request.into_inner().id).await?;
+
+        Ok(Response::new(proto::DeleteTodoResponse {}))
+    }
+
+    type WatchStream = WatchStream;
+
+    async fn watch(&self, request: Request<proto::WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let todo_ids = request.into_inner().todo_ids;
+
+        let events = stream::unfold(Some(events::subscribe()), move |receiver| {
+            let todo_ids = todo_ids.clone();
+            async move {
+                let mut receiver = receiver?;
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) if todo_ids.is_empty() || todo_ids.contains(&event.todo_id) => {
+                            return Some((Ok(event.into()), Some(receiver)));
+                        }
+                        Ok(_) => continue,
+                        // End the stream so the caller reloads instead of silently missing changes
+                        Err(RecvError::Lagged(missed)) => {
+                            let status = Status::data_loss(format!("Watcher fell behind and missed {} events", missed));
+                            return Some((Err(status), None));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(events.take_until(shutdown::wait()))))
+    }
+}
+
+impl From<StoreError> for Status {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::BadRequest(_) => Status::invalid_argument(e.to_string()),
+            StoreError::NotFound(_) => Status::not_found(e.to_string()),
+            StoreError::Conflict => Status::already_exists(e.to_string()),
+            StoreError::Unauthorized(_) => Status::unauthenticated(e.to_string()),
+            StoreError::PayloadTooLarge(_) | StoreError::RateLimited(_) => Status::resource_exhausted(e.to_string()),
+            StoreError::Unavailable(_) => Status::unavailable(e.to_string()),
+            StoreError::Database(ref error) if circuit_breaker::is_unavailable(error) => Status::unavailable(e.to_string()),
+            StoreError::Unexpected(_) | StoreError::Database(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<Todo> for proto::Todo {
+    fn from(todo: Todo) -> Self {
+        proto::Todo {
+            id: todo.id,
+            name: todo.name,
+            description: todo.description,
+            done: todo.done,
+        }
+    }
+}
+
+impl From<events::TodoEvent> for proto::TodoEvent {
+    fn from(event: events::TodoEvent) -> Self {
+        let kind = match event.kind {
+            TodoEventKind::Created => proto::TodoEventKind::Created,
+            TodoEventKind::Updated => proto::TodoEventKind::Updated,
+            TodoEventKind::MarkedDone => proto::TodoEventKind::MarkedDone,
+            TodoEventKind::Deleted => proto::TodoEventKind::Deleted,
+        };
+
+        proto::TodoEvent {
+            event_id: event.event_id,
+            kind: kind.into(),
+            todo_id: event.todo_id,
+            todo: event.todo.map(proto::Todo::from),
+            occurred_at: event.occurred_at.to_rfc3339(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::test_support::{broadcast_events, unique, with_database};
+
+    #[test]
+    fn serves_every_call() {
+        with_database(async {
+            broadcast_events().await;
+            let service = GrpcTodoService;
+            let mut watch = service.watch(Request::new(proto::WatchRequest { todo_ids: Vec::new() })).await.unwrap().into_inner();
+
+            let name = unique("grpc-test");
+            let request = proto::CreateTodoRequest { name: name.clone(), description: "From gRPC".to_string() };
+            let created = service.create(Request::new(request)).await.unwrap().into_inner();
+            assert!(!created.done);
+
+            let request = proto::UpdateTodoRequest { id: created.id, name: name.clone(), description: "Changed".to_string(), done: false };
+            let updated = service.update(Request::new(request)).await.unwrap().into_inner();
+            assert_eq!(updated.description, "Changed");
+
+            let done = service.mark_done(Request::new(proto::MarkDoneRequest { id: created.id })).await.unwrap().into_inner();
+            assert!(done.done);
+
+            let fetched = service.get(Request::new(proto::GetTodoRequest { id: created.id })).await.unwrap().into_inner();
+            assert_eq!(fetched, done);
+            let listed = service.list(Request::new(proto::ListTodosRequest {})).await.unwrap().into_inner();
+            assert!(listed.todos.contains(&done));
+
+            service.delete(Request::new(proto::DeleteTodoRequest { id: created.id })).await.unwrap();
+            let missing = service.get(Request::new(proto::GetTodoRequest { id: created.id })).await.unwrap_err();
+            assert_eq!(missing.code(), tonic::Code::NotFound);
+
+            // Other tests' events are watched too
+            let mut kinds = Vec::new();
+            while kinds.last() != Some(&proto::TodoEventKind::Deleted) {
+                let event = timeout(Duration::from_secs(10), watch.next()).await.unwrap().unwrap().unwrap();
+                if event.todo_id == created.id {
+                    kinds.push(event.kind());
+                }
+            }
+            assert_eq!(
+                kinds,
+                [
+                    proto::TodoEventKind::Created,
+                    proto::TodoEventKind::Updated,
+                    proto::TodoEventKind::MarkedDone,
+                    proto::TodoEventKind::Deleted
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn store_errors_map_to_status_codes() {
+        let cases = [
+            (StoreError::BadRequest("bad".to_string()), tonic::Code::InvalidArgument),
+            (StoreError::NotFound(1), tonic::Code::NotFound),
+            (StoreError::Conflict, tonic::Code::AlreadyExists),
+            (StoreError::Unauthorized("no key"), tonic::Code::Unauthenticated),
+            (StoreError::PayloadTooLarge(10), tonic::Code::ResourceExhausted),
+            (StoreError::RateLimited(1), tonic::Code::ResourceExhausted),
+            (StoreError::Unavailable(1), tonic::Code::Unavailable),
+            (StoreError::Database(sqlx::Error::PoolTimedOut), tonic::Code::Unavailable),
+            (StoreError::Database(sqlx::Error::RowNotFound), tonic::Code::Internal),
+            (StoreError::Unexpected("oops"), tonic::Code::Internal),
+        ];
+        for (error, code) in cases {
+            assert_eq!(Status::from(error).code(), code);
+        }
+    }
+}
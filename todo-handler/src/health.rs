@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use salvo::prelude::*;
+use sqlx::migrate::Migrate;
+use tokio::time::timeout;
+
+use crate::schemas::{HealthCheck, HealthResponse};
+use crate::{get_postgres, shutdown, MIGRATOR};
+
+// `/healthz` answers as long as the process serves requests. `/readyz` checks
+// what requests depend on and turns unavailable as soon as shutdown starts, so
+// orchestrators stop routing traffic here while connections drain.
+
+// Keeps `/readyz` from hanging when the pool is exhausted or Postgres is gone
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub static OUTBOX_DISPATCHER: Worker = Worker::new();
+pub static OUTBOX_BROADCASTER: Worker = Worker::new();
+pub static GRPC_SERVER: Worker = Worker::new();
+
+// A background task that reports whether it is still running
+pub struct Worker {
+    alive: AtomicBool,
+    // Cleared for workers the configuration turns off
+    enabled: AtomicBool,
+}
+
+// Marks its worker as stopped when dropped, including when the task panics
+pub struct AliveGuard(&'static Worker);
+
+impl Worker {
+    const fn new() -> Self {
+        Worker { alive: AtomicBool::new(false), enabled: AtomicBool::new(true) }
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn mark_alive(&'static self) -> AliveGuard {
+        self.alive.store(true, Ordering::SeqCst);
+        AliveGuard(self)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether the process is up.
+#[endpoint(
+    tags("health"),
+    status_codes(200),
+    responses((status_code = 200, description = "The process is up", body = HealthResponse))
+)]
+pub async fn healthz(res: &mut Response) {
+    res.render(Json(HealthResponse { status: "ok", checks: Vec::new() }));
+}
+
+/// Whether the handler can serve requests, with the result of every check.
+///
+/// Checks that Postgres answers within 2 seconds, every migration is applied, the outbox
+/// dispatcher and broadcaster and the gRPC server are running and no shutdown is in progress.
+#[endpoint(
+    tags("health"),
+    status_codes(200, 503),
+    responses(
+        (status_code = 200, description = "Ready for traffic", body = HealthResponse),
+        (status_code = 503, description = "At least one check failed", body = HealthResponse)
+    )
+)]
+pub async fn readyz(res: &mut Response) {
+    let (database, migrations) = check_database().await;
+    let checks = vec![
+        database,
+        migrations,
+        check_worker("outbox_dispatcher", &OUTBOX_DISPATCHER),
+        check_worker("outbox_broadcaster", &OUTBOX_BROADCASTER),
+        check_worker("grpc_server", &GRPC_SERVER),
+        HealthCheck {
+            name: "shutdown",
+            ok: !shutdown::is_shutting_down(),
+            detail: if shutdown::is_shutting_down() { "shutting down" } else { "not shutting down" }.to_string(),
+        },
+    ];
+
+    let ready = checks.iter().all(|check| check.ok);
+    if !ready {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(HealthResponse { status: if ready { "ok" } else { "unavailable" }, checks }));
+}
+
+// Both checks share one connection; migrations can't be checked without it
+async fn check_database() -> (HealthCheck, HealthCheck) {
+    let pool = get_postgres();
+    let pool_state = format!("{} connections, {} idle", pool.size(), pool.num_idle());
+
+    let applied = timeout(DATABASE_TIMEOUT, async {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await?;
+        Ok::<_, sqlx::Error>(conn.list_applied_migrations().await)
+    })
+    .await;
+
+    let failed = |detail: String| {
+        (
+            HealthCheck { name: "database", ok: false, detail },
+            HealthCheck { name: "migrations", ok: false, detail: "database unavailable".to_string() },
+        )
+    };
+    let applied = match applied {
+        Err(_) => return failed(format!("no answer within {}s ({})", DATABASE_TIMEOUT.as_secs(), pool_state)),
+        Ok(Err(e)) => return failed(e.to_string()),
+        Ok(Ok(applied)) => applied,
+    };
+    let database = HealthCheck { name: "database", ok: true, detail: pool_state };
+
+    let migrations = match applied {
+        Ok(applied) => {
+            let applied: HashSet<i64> = applied.into_iter().map(|migration| migration.version).collect();
+            let pending = MIGRATOR.iter()
+                .filter(|migration| !migration.migration_type.is_down_migration() && !applied.contains(&migration.version))
+                .count();
+            HealthCheck {
+                name: "migrations",
+                ok: pending == 0,
+                detail: if pending == 0 { "up to date".to_string() } else { format!("{} pending", pending) },
+            }
+        }
+        Err(e) => HealthCheck { name: "migrations", ok: false, detail: e.to_string() },
+    };
+
+    (database, migrations)
+}
+
+fn check_worker(name: &'static str, worker: &Worker) -> HealthCheck {
+    if !worker.enabled.load(Ordering::SeqCst) {
+        return HealthCheck { name, ok: true, detail: "disabled".to_string() };
+    }
+    HealthCheck {
+        name,
+        ok: worker.is_alive(),
+        detail: if worker.is_alive() { "running" } else { "stopped" }.to_string(),
+    }
+}
@@ -0,0 +1,433 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::backend_error::StoreError;
+
+// Just enough of iCalendar (RFC 5545) to exchange todos as VTODO components.
+// Times with a TZID are converted to UTC when it names an IANA time zone, and
+// kept as floating wall-clock times like times without a zone otherwise.
+
+const PRODID: &str = "-//todo-handler//Todos//EN";
+
+// Content lines longer than this are folded
+const MAX_LINE_OCTETS: usize = 75;
+
+#[derive(Debug, Default)]
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+#[derive(Debug)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+// A todo as stored in a VTODO: SUMMARY, DESCRIPTION, STATUS/COMPLETED and DUE
+#[derive(Debug, Clone)]
+pub struct CalendarTodo {
+    pub uid: String,
+    pub summary: String,
+    pub description: String,
+    pub done: bool,
+    pub due: Option<Due>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub stamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Due {
+    pub at: DateTime<Utc>,
+    // `DUE;VALUE=DATE`, a whole day rather than a point in time
+    pub is_date: bool,
+    // A wall-clock time in whatever zone the reader is in; `at` holds it as if it were UTC
+    pub floating: bool,
+}
+
+impl Component {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|property| property.name == name)
+    }
+
+    // Unescaped value of a TEXT property
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.property(name).map(|property| unescape(&property.value))
+    }
+
+    // Every VTODO nested anywhere below this component
+    pub fn todos(&self) -> Vec<&Component> {
+        let mut todos = Vec::new();
+        for component in &self.components {
+            if component.name == "VTODO" {
+                todos.push(component);
+            } else {
+                todos.extend(component.todos());
+            }
+        }
+        todos
+    }
+}
+
+impl Property {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn date_time(&self) -> Result<Due, StoreError> {
+        let invalid = || StoreError::BadRequest(format!("Invalid {} value '{}'", self.name, self.value));
+        let value = self.value.trim();
+
+        if self.param("VALUE").is_some_and(|kind| kind.eq_ignore_ascii_case("DATE")) || value.len() == 8 {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+            let at = date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?.and_utc();
+            return Ok(Due { at, is_date: true, floating: false });
+        }
+
+        let local = NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S").map_err(|_| invalid())?;
+        if value.ends_with('Z') {
+            return Ok(Due { at: local.and_utc(), is_date: false, floating: false });
+        }
+
+        // Zones defined only by the calendar's VTIMEZONE, such as Windows names, stay floating
+        match self.param("TZID").and_then(|tzid| tzid.trim_start_matches('/').parse::<Tz>().ok()) {
+            Some(zone) => {
+                // The earlier of two repeated times, and skipped times as if the clocks hadn't changed yet
+                let at = zone
+                    .from_local_datetime(&local)
+                    .earliest()
+                    .or_else(|| zone.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest())
+                    .ok_or_else(invalid)?;
+                Ok(Due { at: at.with_timezone(&Utc), is_date: false, floating: false })
+            }
+            None => Ok(Due { at: local.and_utc(), is_date: false, floating: true }),
+        }
+    }
+}
+
+// Parses an iCalendar stream into its top-level components, usually one VCALENDAR
+pub fn parse(input: &str) -> Result<Vec<Component>, StoreError> {
+    let mut calendars = Vec::new();
+    let mut open: Vec<Component> = Vec::new();
+
+    for line in unfold(input) {
+        let property = parse_line(&line)?;
+        match property.name.as_str() {
+            "BEGIN" => open.push(Component {
+                name: property.value.trim().to_ascii_uppercase(),
+                ..Default::default()
+            }),
+            "END" => {
+                let component = open.pop()
+                    .filter(|component| component.name.eq_ignore_ascii_case(property.value.trim()))
+                    .ok_or_else(|| StoreError::BadRequest(format!("Unexpected END:{}", property.value)))?;
+                match open.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None => calendars.push(component),
+                }
+            }
+            _ => open.last_mut()
+                .ok_or_else(|| StoreError::BadRequest(format!("Property {} outside of a component", property.name)))?
+                .properties
+                .push(property),
+        }
+    }
+
+    if let Some(component) = open.last() {
+        return Err(StoreError::BadRequest(format!("Missing END:{}", component.name)));
+    }
+    if calendars.is_empty() {
+        return Err(StoreError::BadRequest("No iCalendar data".to_string()));
+    }
+    Ok(calendars)
+}
+
+impl CalendarTodo {
+    pub fn from_component(component: &Component) -> Result<Self, StoreError> {
+        let summary = component.text("SUMMARY").unwrap_or_default();
+        let completed_at = component.property("COMPLETED")
+            .map(|completed| completed.date_time().map(|completed| completed.at))
+            .transpose()?;
+        let done = completed_at.is_some()
+            || component.property("STATUS").is_some_and(|status| status.value.eq_ignore_ascii_case("COMPLETED"));
+        let due = component.property("DUE").map(Property::date_time).transpose()?;
+        let stamp = component.property("DTSTAMP")
+            .map(|stamp| stamp.date_time().map(|stamp| stamp.at))
+            .transpose()?
+            .unwrap_or_else(Utc::now);
+
+        Ok(CalendarTodo {
+            uid: component.text("UID").unwrap_or_default(),
+            summary,
+            description: component.text("DESCRIPTION").unwrap_or_default(),
+            done,
+            due,
+            completed_at,
+            stamp,
+        })
+    }
+
+    fn write(&self, out: &mut String) {
+        write_line(out, "BEGIN:VTODO");
+        write_line(out, &format!("UID:{}", escape(&self.uid)));
+        write_line(out, &format!("DTSTAMP:{}", format_date_time(self.stamp)));
+        write_line(out, &format!("SUMMARY:{}", escape(&self.summary)));
+        if !self.description.is_empty() {
+            write_line(out, &format!("DESCRIPTION:{}", escape(&self.description)));
+        }
+        write_line(out, if self.done { "STATUS:COMPLETED" } else { "STATUS:NEEDS-ACTION" });
+        if let (true, Some(completed_at)) = (self.done, self.completed_at) {
+            write_line(out, &format!("COMPLETED:{}", format_date_time(completed_at)));
+        }
+        match self.due {
+            Some(Due { at, is_date: true, .. }) => write_line(out, &format!("DUE;VALUE=DATE:{}", at.format("%Y%m%d"))),
+            Some(Due { at, floating: true, .. }) => write_line(out, &format!("DUE:{}", at.format("%Y%m%dT%H%M%S"))),
+            Some(Due { at, .. }) => write_line(out, &format!("DUE:{}", format_date_time(at))),
+            None => {}
+        }
+        write_line(out, "END:VTODO");
+    }
+}
+
+// A VCALENDAR holding one VTODO per todo
+pub fn calendar(todos: &[CalendarTodo]) -> String {
+    let mut out = String::new();
+    write_line(&mut out, "BEGIN:VCALENDAR");
+    write_line(&mut out, "VERSION:2.0");
+    write_line(&mut out, &format!("PRODID:{}", PRODID));
+    for todo in todos {
+        todo.write(&mut out);
+    }
+    write_line(&mut out, "END:VCALENDAR");
+    out
+}
+
+fn format_date_time(at: DateTime<Utc>) -> String {
+    at.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// Joins folded continuation lines, which start with a space or tab
+fn unfold(input: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in input.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+// `NAME;PARAM=value;PARAM="quoted:value":VALUE`
+fn parse_line(line: &str) -> Result<Property, StoreError> {
+    let invalid = || StoreError::BadRequest(format!("Invalid iCalendar line '{}'", line));
+
+    let mut in_quotes = false;
+    let colon = line
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ':' && !in_quotes
+        })
+        .map(|(index, _)| index)
+        .ok_or_else(invalid)?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+
+    let mut parts = head.split(';');
+    let name = parts.next().filter(|name| !name.is_empty()).ok_or_else(invalid)?;
+    let params = parts
+        .map(|param| {
+            let (key, value) = param.split_once('=').ok_or_else(invalid)?;
+            Ok((key.to_ascii_uppercase(), value.trim_matches('"').to_string()))
+        })
+        .collect::<Result<_, StoreError>>()?;
+
+    Ok(Property {
+        name: name.to_ascii_uppercase(),
+        params,
+        value: value.to_string(),
+    })
+}
+
+fn write_line(out: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            out.push_str("\r\n ");
+            octets = 1;
+        }
+        out.push(c);
+        octets += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vtodo(lines: &str) -> CalendarTodo {
+        let input = format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VTODO\r\nUID:test@example.com\r\n{}END:VTODO\r\nEND:VCALENDAR\r\n", lines);
+        let calendars = parse(&input).unwrap();
+        CalendarTodo::from_component(calendars[0].todos()[0]).unwrap()
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_a_vtodo() {
+        let todo = vtodo(
+            "SUMMARY:Buy milk\\, eggs\r\nDESCRIPTION:Two lines\\nand a \\; semicolon\r\nSTATUS:COMPLETED\r\n\
+             COMPLETED:20250301T101500Z\r\nDTSTAMP:20250301T120000Z\r\n",
+        );
+
+        assert_eq!(todo.uid, "test@example.com");
+        assert_eq!(todo.summary, "Buy milk, eggs");
+        assert_eq!(todo.description, "Two lines\nand a ; semicolon");
+        assert!(todo.done);
+        assert_eq!(todo.completed_at, Some(utc("2025-03-01T10:15:00Z")));
+        assert_eq!(todo.stamp, utc("2025-03-01T12:00:00Z"));
+        assert_eq!(todo.due, None);
+    }
+
+    #[test]
+    fn unfolds_lines_and_reads_quoted_parameters() {
+        let todo = vtodo("SUMMARY:A long\r\n  summary\r\nDESCRIPTION;ALTREP=\"cid:part1@example.com\":Text\r\n");
+
+        assert_eq!(todo.summary, "A long summary");
+        assert_eq!(todo.description, "Text");
+    }
+
+    #[test]
+    fn reads_due_dates_and_times() {
+        let date = vtodo("DUE;VALUE=DATE:20250315\r\n").due.unwrap();
+        assert_eq!(date, Due { at: utc("2025-03-15T00:00:00Z"), is_date: true, floating: false });
+
+        let utc_time = vtodo("DUE:20250315T090000Z\r\n").due.unwrap();
+        assert_eq!(utc_time, Due { at: utc("2025-03-15T09:00:00Z"), is_date: false, floating: false });
+    }
+
+    #[test]
+    fn converts_times_with_a_tzid() {
+        // Central European Time, one hour ahead of UTC in winter and two in summer
+        let winter = vtodo("DUE;TZID=Europe/Berlin:20250115T090000\r\n").due.unwrap();
+        assert_eq!(winter, Due { at: utc("2025-01-15T08:00:00Z"), is_date: false, floating: false });
+
+        let summer = vtodo("DUE;TZID=\"Europe/Berlin\":20250715T090000\r\n").due.unwrap();
+        assert_eq!(summer.at, utc("2025-07-15T07:00:00Z"));
+
+        // 02:30 doesn't exist when the clocks go forward
+        let skipped = vtodo("DUE;TZID=Europe/Berlin:20250330T023000\r\n").due.unwrap();
+        assert_eq!(skipped.at, utc("2025-03-30T01:30:00Z"));
+    }
+
+    #[test]
+    fn keeps_floating_times() {
+        let floating = vtodo("DUE:20250315T090000\r\n").due.unwrap();
+        assert_eq!(floating, Due { at: utc("2025-03-15T09:00:00Z"), is_date: false, floating: true });
+
+        // Only defined by a VTIMEZONE, which isn't read
+        let custom = vtodo("DUE;TZID=W. Europe Standard Time:20250315T090000\r\n").due.unwrap();
+        assert!(custom.floating);
+        assert_eq!(custom.at, utc("2025-03-15T09:00:00Z"));
+    }
+
+    #[test]
+    fn rejects_broken_input() {
+        assert!(parse("BEGIN:VCALENDAR\r\nBEGIN:VTODO\r\nEND:VCALENDAR\r\n").is_err());
+        assert!(parse("BEGIN:VCALENDAR\r\n").is_err());
+        assert!(parse("SUMMARY:outside\r\n").is_err());
+        assert!(parse("").is_err());
+
+        let calendars = parse("BEGIN:VCALENDAR\r\nBEGIN:VTODO\r\nDUE:tomorrow\r\nEND:VTODO\r\nEND:VCALENDAR\r\n").unwrap();
+        assert!(CalendarTodo::from_component(calendars[0].todos()[0]).is_err());
+    }
+
+    #[test]
+    fn serializes_and_parses_back() {
+        let todos = [
+            CalendarTodo {
+                uid: "a@example.com".to_string(),
+                summary: "Comma, semicolon; backslash \\ and a summary long enough to be folded across several lines ✓".to_string(),
+                description: "First line\nsecond line".to_string(),
+                done: true,
+                due: Some(Due { at: utc("2025-03-15T00:00:00Z"), is_date: true, floating: false }),
+                completed_at: Some(utc("2025-03-14T18:00:00Z")),
+                stamp: utc("2025-03-14T18:00:00Z"),
+            },
+            CalendarTodo {
+                uid: "b@example.com".to_string(),
+                summary: "Floating".to_string(),
+                description: String::new(),
+                done: false,
+                due: Some(Due { at: utc("2025-03-15T09:30:00Z"), is_date: false, floating: true }),
+                completed_at: None,
+                stamp: utc("2025-03-14T18:00:00Z"),
+            },
+            CalendarTodo {
+                uid: "c@example.com".to_string(),
+                summary: "In UTC".to_string(),
+                description: String::new(),
+                done: false,
+                due: Some(Due { at: utc("2025-03-15T09:30:00Z"), is_date: false, floating: false }),
+                completed_at: None,
+                stamp: utc("2025-03-14T18:00:00Z"),
+            },
+        ];
+
+        let ics = calendar(&todos);
+        assert!(ics.lines().all(|line| line.len() <= MAX_LINE_OCTETS + 1));
+        assert!(ics.contains("DUE;VALUE=DATE:20250315\r\n"));
+        assert!(ics.contains("DUE:20250315T093000\r\n"));
+        assert!(ics.contains("DUE:20250315T093000Z\r\n"));
+
+        let calendars = parse(&ics).unwrap();
+        let parsed: Vec<CalendarTodo> = calendars[0].todos().into_iter().map(|todo| CalendarTodo::from_component(todo).unwrap()).collect();
+        assert_eq!(parsed.len(), todos.len());
+        for (parsed, todo) in parsed.iter().zip(&todos) {
+            assert_eq!(parsed.uid, todo.uid);
+            assert_eq!(parsed.summary, todo.summary);
+            assert_eq!(parsed.description, todo.description);
+            assert_eq!(parsed.done, todo.done);
+            assert_eq!(parsed.due, todo.due);
+            assert_eq!(parsed.completed_at, todo.completed_at);
+            assert_eq!(parsed.stamp, todo.stamp);
+        }
+    }
+}
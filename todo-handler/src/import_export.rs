@@ -0,0 +1,766 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use salvo::http::header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
+use salvo::http::mime;
+use salvo::prelude::*;
+use serde::Deserialize;
+use sqlx::prelude::FromRow;
+use sqlx::PgConnection;
+
+use crate::backend_error::StoreError;
+use crate::caldav;
+use crate::ical::{self, CalendarTodo, Due};
+use crate::markdown::{self, ChecklistItem, OutlineTodo};
+use crate::schemas::{ImportItem, ImportResponse, ImportStatus};
+use crate::{circuit_breaker, get_postgres, replica, todo_store, Todo};
+
+// One-shot file exports of every todo and imports of uploaded files.
+// Imports go through the same validation as the other APIs, item by item.
+
+// Imports larger than this are rejected
+const MAX_UPLOAD_SIZE: usize = 10 * 1024 * 1024;
+
+const EXPORT_QUERY: &str = "SELECT t.id, t.name, t.description, t.done, c.due, c.due_is_date, c.due_is_floating, o.project
+    FROM todos t LEFT JOIN caldav_todos c ON c.todo_id = t.id LEFT JOIN todo_outline o ON o.todo_id = t.id ORDER BY t.id";
+
+const OUTLINE_QUERY: &str = "SELECT t.id, t.name, t.description, t.done, o.project, o.parent_id
+    FROM todos t LEFT JOIN todo_outline o ON o.todo_id = t.id ORDER BY o.position NULLS LAST, t.id";
+
+const FLOATING_DUE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+const CSV_HEADER: &str = "id,name,description,done,due\n";
+
+#[derive(FromRow, Debug)]
+struct OutlineRow {
+    id: i32,
+    name: String,
+    description: String,
+    done: bool,
+    project: Option<String>,
+    parent_id: Option<i32>,
+}
+
+#[derive(FromRow, Debug)]
+struct ExportRow {
+    id: i32,
+    name: String,
+    description: String,
+    done: bool,
+    due: Option<DateTime<Utc>>,
+    due_is_date: Option<bool>,
+    due_is_floating: Option<bool>,
+    project: Option<String>,
+}
+
+// Line-based export formats, written row by row as the rows arrive
+#[derive(Debug, Clone, Copy)]
+enum LineFormat {
+    Csv,
+    Ndjson,
+    TodoTxt,
+}
+
+// A todo read from an uploaded file, not yet validated
+#[derive(Debug)]
+struct ImportedTodo {
+    name: String,
+    description: String,
+    done: bool,
+    due: Option<Due>,
+    // The Markdown heading the todo is listed under
+    project: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NdjsonTodo {
+    name: Option<String>,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    done: bool,
+    due: Option<String>,
+}
+
+/// Download every todo as an iCalendar (RFC 5545) file of VTODOs.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 429, 500),
+    responses((status_code = 200, description = "A VCALENDAR with one VTODO per todo", content_type = "text/calendar", body = String))
+)]
+pub async fn export_ics(res: &mut Response) -> Result<(), StoreError> {
+    let todos = caldav::calendar_todos().await?;
+
+    file_headers(res, "text/calendar; charset=utf-8", "todos.ics");
+    res.body(ical::calendar(&todos).into());
+    Ok(())
+}
+
+/// Import todos from an iCalendar file, creating or updating them by UID.
+///
+/// The file is sent as the body or as a `multipart/form-data` upload.
+/// VTODOs whose name is already taken by another todo are skipped.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 400, 413, 429, 500),
+    request_body(content = String, content_type = "text/calendar", description = "VCALENDAR with VTODO components"),
+    responses((status_code = 200, description = "What happened to every VTODO", body = ImportResponse))
+)]
+pub async fn import_ics(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let upload = read_upload(req, MAX_UPLOAD_SIZE).await?;
+    let calendars = ical::parse(&upload)?;
+
+    let mut items = Vec::new();
+    for (index, component) in calendars.iter().flat_map(ical::Component::todos).enumerate() {
+        let uid = component.text("UID").filter(|uid| !uid.trim().is_empty());
+        let result = match &uid {
+            Some(_) => import_vtodo(component).await,
+            None => Err(StoreError::BadRequest("Missing UID".to_string())),
+        };
+        items.push(import_item(index, uid, result));
+    }
+
+    res.render(Json(ImportResponse::new(items, false)));
+    Ok(())
+}
+
+/// Download every todo as CSV with the columns `id,name,description,done,due`.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 429, 500, 503),
+    responses((status_code = 200, description = "One row per todo, after a header row", content_type = "text/csv", body = String))
+)]
+pub async fn export_csv(res: &mut Response) -> Result<(), StoreError> {
+    stream_export(res, LineFormat::Csv).await
+}
+
+/// Download every todo as newline-delimited JSON, one todo per line.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 429, 500, 503),
+    responses((status_code = 200, description = "One JSON object per line", content_type = "application/x-ndjson", body = String))
+)]
+pub async fn export_ndjson(res: &mut Response) -> Result<(), StoreError> {
+    stream_export(res, LineFormat::Ndjson).await
+}
+
+/// Download every todo in the todo.txt format.
+///
+/// Done todos start with `x`, due dates are written as `due:YYYY-MM-DD` and
+/// the Markdown heading of a todo as `+project`.
+/// todo.txt has no room for descriptions, so they are left out.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 429, 500, 503),
+    responses((status_code = 200, description = "One todo per line", content_type = "text/plain", body = String))
+)]
+pub async fn export_todo_txt(res: &mut Response) -> Result<(), StoreError> {
+    stream_export(res, LineFormat::TodoTxt).await
+}
+
+/// Create todos from a CSV file with a header row.
+///
+/// Only the `name` column is required; `description`, `done` and `due` are optional and `id` is ignored.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 400, 413, 429, 500),
+    parameters(("dry_run" = Option<bool>, Query, description = "Only report what would be created")),
+    request_body(content = String, content_type = "text/csv", description = "CSV file, as the body or a multipart upload"),
+    responses((status_code = 200, description = "What happened to every row", body = ImportResponse))
+)]
+pub async fn import_csv(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let upload = read_upload(req, MAX_UPLOAD_SIZE).await?;
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(upload.as_bytes());
+    let headers = reader.headers()
+        .map_err(|e| StoreError::BadRequest(format!("Invalid CSV header: {}", e)))?
+        .clone();
+    let column = |name: &str| headers.iter().position(|header| header.trim().eq_ignore_ascii_case(name));
+    let name_column = column("name").ok_or_else(|| StoreError::BadRequest("Missing 'name' column".to_string()))?;
+    let (description_column, done_column, due_column) = (column("description"), column("done"), column("due"));
+
+    let todos = reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(|e| StoreError::BadRequest(format!("Invalid CSV row: {}", e)))?;
+            let field = |column: Option<usize>| column.and_then(|column| record.get(column)).unwrap_or_default();
+            Ok(ImportedTodo {
+                name: field(Some(name_column)).to_string(),
+                description: field(description_column).to_string(),
+                done: parse_done(field(done_column))?,
+                due: parse_due(field(due_column))?,
+                project: None,
+            })
+        })
+        .collect();
+
+    render_import(req, res, todos).await
+}
+
+/// Create todos from newline-delimited JSON.
+///
+/// Every line is an object with `name` and optionally `description`, `done` and `due`, as written by the export.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 400, 413, 429, 500),
+    parameters(("dry_run" = Option<bool>, Query, description = "Only report what would be created")),
+    request_body(content = String, content_type = "application/x-ndjson", description = "NDJSON file, as the body or a multipart upload"),
+    responses((status_code = 200, description = "What happened to every line", body = ImportResponse))
+)]
+pub async fn import_ndjson(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let upload = read_upload(req, MAX_UPLOAD_SIZE).await?;
+
+    let todos = upload
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let todo = serde_json::from_str::<NdjsonTodo>(line)
+                .map_err(|e| StoreError::BadRequest(format!("Invalid JSON line: {}", e)))?;
+            Ok(ImportedTodo {
+                name: todo.name.unwrap_or_default(),
+                description: todo.description,
+                done: todo.done,
+                due: parse_due(todo.due.as_deref().unwrap_or_default())?,
+                project: None,
+            })
+        })
+        .collect();
+
+    render_import(req, res, todos).await
+}
+
+/// Create todos from a todo.txt file.
+///
+/// `x` marks a todo as done and `due:YYYY-MM-DD` sets its due date. Priorities,
+/// `+project` and `@context` stay part of the name; creation and completion dates are dropped.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 400, 413, 429, 500),
+    parameters(("dry_run" = Option<bool>, Query, description = "Only report what would be created")),
+    request_body(content = String, content_type = "text/plain", description = "todo.txt file, as the body or a multipart upload"),
+    responses((status_code = 200, description = "What happened to every line", body = ImportResponse))
+)]
+pub async fn import_todo_txt(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let upload = read_upload(req, MAX_UPLOAD_SIZE).await?;
+
+    let todos = upload
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_todo_txt_line)
+        .collect();
+
+    render_import(req, res, todos).await
+}
+
+/// Download every todo as a Markdown checklist.
+///
+/// Projects become headings and subtasks nested items, as last imported from Markdown.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 429, 500),
+    responses((status_code = 200, description = "Markdown document", content_type = "text/markdown", body = String))
+)]
+pub async fn export_markdown(res: &mut Response) -> Result<(), StoreError> {
+    let todos = load_outline().await?;
+
+    file_headers(res, "text/markdown; charset=utf-8", "todos.md");
+    res.body(markdown::render(&todos).into());
+    Ok(())
+}
+
+/// Create or update todos from the `- [ ]` / `- [x]` checklists of a Markdown document.
+///
+/// Items are matched to todos by name. Headings become projects, nested items subtasks
+/// and text indented under an item its description. Importing an export changes nothing.
+#[endpoint(
+    tags("import/export"),
+    status_codes(200, 400, 413, 429, 500),
+    request_body(content = String, content_type = "text/markdown", description = "Markdown document, as the body or a multipart upload"),
+    responses((status_code = 200, description = "What happened to every checklist item", body = ImportResponse))
+)]
+pub async fn import_markdown(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let upload = read_upload(req, MAX_UPLOAD_SIZE).await?;
+    let checklist = markdown::parse(&upload);
+
+    let mut names = HashSet::new();
+    let mut todo_ids: Vec<Option<i32>> = Vec::with_capacity(checklist.len());
+    let mut items = Vec::with_capacity(checklist.len());
+    for (index, item) in checklist.iter().enumerate() {
+        let result = match names.insert(item.name.as_str()) {
+            true => {
+                let parent_id = item.parent.and_then(|parent| todo_ids[parent]);
+                import_checklist_item(item, parent_id, index).await
+            }
+            false => Err(StoreError::Conflict),
+        };
+        todo_ids.push(result.as_ref().ok().map(|(todo, _)| todo.id));
+        items.push(import_item(index, None, result));
+    }
+
+    res.render(Json(ImportResponse::new(items, false)));
+    Ok(())
+}
+
+// Every todo in the order of the outline
+#[tracing::instrument(skip_all)]
+async fn load_outline() -> Result<Vec<OutlineTodo>, StoreError> {
+    let rows = circuit_breaker::read("load_outline", || sqlx::query_as::<_, OutlineRow>(OUTLINE_QUERY).fetch_all(get_postgres())).await?;
+
+    let todos = rows
+        .into_iter()
+        .map(|row| OutlineTodo {
+            id: row.id,
+            name: row.name,
+            description: row.description,
+            done: row.done,
+            project: row.project,
+            parent_id: row.parent_id,
+        })
+        .collect();
+    Ok(todos)
+}
+
+// Changes the todo and its place in the outline in one transaction
+#[tracing::instrument(skip_all)]
+async fn import_checklist_item(item: &ChecklistItem, parent_id: Option<i32>, position: usize) -> Result<(Todo, ImportStatus), StoreError> {
+    let existing = todo_store::fetch_todo_by_name(&item.name).await?;
+    let outline = (item.project.clone(), parent_id, position as i32);
+
+    circuit_breaker::write(async {
+        let mut tx = todo_store::begin().await?;
+        let (todo, status) = match existing {
+            Some(todo) if todo.description == item.description && todo.done == item.done => {
+                // Unchanged todos are left alone so re-imports don't emit events
+                let current: Option<(Option<String>, Option<i32>, i32)> =
+                    sqlx::query_as("SELECT project, parent_id, position FROM todo_outline WHERE todo_id = $1")
+                        .bind(todo.id)
+                        .fetch_optional(&mut *tx)
+                        .await?;
+                if current.as_ref() == Some(&outline) {
+                    return Ok((todo, ImportStatus::Unchanged));
+                }
+                (todo, ImportStatus::Updated)
+            }
+            Some(todo) => {
+                let todo = todo_store::update_in(&mut tx, todo.id, &item.name, &item.description, item.done).await?;
+                (todo, ImportStatus::Updated)
+            }
+            None => {
+                let imported = ImportedTodo {
+                    name: item.name.clone(),
+                    description: item.description.clone(),
+                    done: item.done,
+                    due: None,
+                    project: None,
+                };
+                (insert_imported(&mut tx, &imported).await?, ImportStatus::Created)
+            }
+        };
+
+        sqlx::query(
+            "INSERT INTO todo_outline (todo_id, project, parent_id, position) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (todo_id) DO UPDATE SET project = EXCLUDED.project, parent_id = EXCLUDED.parent_id, position = EXCLUDED.position"
+        )
+        .bind(todo.id)
+        .bind(&outline.0)
+        .bind(outline.1)
+        .bind(outline.2)
+        .execute(&mut *tx)
+        .await?;
+
+        todo_store::commit(tx, &[todo.id]).await?;
+        Ok((todo, status))
+    })
+    .await
+}
+
+async fn import_vtodo(component: &ical::Component) -> Result<(Todo, ImportStatus), StoreError> {
+    let todo = CalendarTodo::from_component(component)?;
+
+    let (todo, created) = caldav::store_by_uid(&todo).await?;
+    Ok((todo, if created { ImportStatus::Created } else { ImportStatus::Updated }))
+}
+
+// Creates every parsed todo, or with `?dry_run=true` only checks whether it could be created
+async fn render_import(req: &Request, res: &mut Response, todos: Vec<Result<ImportedTodo, StoreError>>) -> Result<(), StoreError> {
+    let dry_run = req.query::<bool>("dry_run").unwrap_or(false);
+    let mut names = HashSet::new();
+
+    let mut items = Vec::with_capacity(todos.len());
+    for (index, todo) in todos.into_iter().enumerate() {
+        let result = match todo {
+            Ok(todo) if dry_run => preview_import(todo, &mut names).await,
+            Ok(todo) => create_imported(todo).await,
+            Err(e) => Err(e),
+        };
+        items.push(import_item(index, None, result));
+    }
+
+    res.render(Json(ImportResponse::new(items, dry_run)));
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn create_imported(todo: ImportedTodo) -> Result<(Todo, ImportStatus), StoreError> {
+    circuit_breaker::write(async {
+        let mut tx = todo_store::begin().await?;
+        let created = insert_imported(&mut tx, &todo).await?;
+        todo_store::commit(tx, &[created.id]).await?;
+        Ok((created, ImportStatus::Created))
+    })
+    .await
+}
+
+// Creates a todo with everything a file can set, within the caller's transaction
+async fn insert_imported(conn: &mut PgConnection, todo: &ImportedTodo) -> Result<Todo, StoreError> {
+    let mut created = todo_store::create_in(conn, &todo.name, &todo.description).await?;
+    if todo.done {
+        created = todo_store::mark_done_in(conn, created.id).await?;
+    }
+    if todo.due.is_some() {
+        caldav::set_due(conn, created.id, todo.due).await?;
+    }
+    if todo.project.is_some() {
+        sqlx::query(
+            "INSERT INTO todo_outline (todo_id, project, position)
+            SELECT $1, $2, COALESCE(MAX(position) + 1, 0) FROM todo_outline"
+        )
+        .bind(created.id)
+        .bind(&todo.project)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(created)
+}
+
+// Runs the checks of `create_todo`, also against names earlier in the same file
+async fn preview_import(todo: ImportedTodo, names: &mut HashSet<String>) -> Result<(Todo, ImportStatus), StoreError> {
+    todo_store::validate_name(&todo.name)?;
+    if !names.insert(todo.name.clone()) || todo_store::name_taken(&todo.name).await? {
+        return Err(StoreError::Conflict);
+    }
+
+    let preview = Todo {
+        id: 0,
+        name: todo.name,
+        description: todo.description,
+        done: todo.done,
+    };
+    Ok((preview, ImportStatus::Created))
+}
+
+// Duplicate names are expected when importing the same file twice, so they are skipped rather than failed
+fn import_item(index: usize, uid: Option<String>, result: Result<(Todo, ImportStatus), StoreError>) -> ImportItem {
+    let (status, todo, error) = match result {
+        Ok((todo, status)) => (status, Some(todo), None),
+        Err(e @ StoreError::Conflict) => (ImportStatus::Skipped, None, Some(e.to_string())),
+        Err(e) => (ImportStatus::Failed, None, Some(e.to_string())),
+    };
+
+    ImportItem { index, uid, status, todo, error }
+}
+
+// `x 2025-01-02 2025-01-01 (A) Call mom +family @phone due:2025-01-05`
+fn parse_todo_txt_line(line: &str) -> Result<ImportedTodo, StoreError> {
+    let mut rest = line.trim();
+
+    let done = rest.starts_with("x ");
+    if done {
+        // Completion date, then creation date
+        rest = skip_date(skip_date(rest[2..].trim_start()));
+    }
+
+    let mut words = Vec::new();
+    if let Some(priority) = rest.get(..4).filter(|priority| is_priority(priority)) {
+        words.push(priority.trim_end());
+        rest = rest[4..].trim_start();
+    }
+    let rest = skip_date(rest);
+
+    let mut due = None;
+    for word in rest.split_whitespace() {
+        match word.strip_prefix("due:") {
+            Some(date) => due = parse_due(date)?,
+            None => words.push(word),
+        }
+    }
+
+    // The first `+project` also places the todo under that heading in Markdown
+    let project = words
+        .iter()
+        .find_map(|word| word.strip_prefix('+').filter(|project| !project.is_empty()))
+        .map(str::to_string);
+
+    Ok(ImportedTodo {
+        name: words.join(" "),
+        description: String::new(),
+        done,
+        due,
+        project,
+    })
+}
+
+// `(A) ` to `(Z) `
+fn is_priority(text: &str) -> bool {
+    matches!(text.as_bytes(), [b'(', b'A'..=b'Z', b')', b' '])
+}
+
+fn skip_date(text: &str) -> &str {
+    match text.get(..10).map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d")) {
+        Some(Ok(_)) if text[10..].is_empty() || text[10..].starts_with(' ') => text[10..].trim_start(),
+        _ => text,
+    }
+}
+
+fn parse_done(value: &str) -> Result<bool, StoreError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "false" | "0" | "no" => Ok(false),
+        "true" | "1" | "yes" | "x" => Ok(true),
+        _ => Err(StoreError::BadRequest(format!("Invalid 'done' value '{}'", value))),
+    }
+}
+
+// `YYYY-MM-DD` for a due day, an RFC 3339 date-time, or `YYYY-MM-DDTHH:MM:SS` for a time without a zone
+fn parse_due(value: &str) -> Result<Option<Due>, StoreError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let at = date.and_hms_opt(0, 0, 0).map(|at| at.and_utc());
+        return Ok(at.map(|at| Due { at, is_date: true, floating: false }));
+    }
+    if let Ok(local) = NaiveDateTime::parse_from_str(value, FLOATING_DUE_FORMAT) {
+        return Ok(Some(Due { at: local.and_utc(), is_date: false, floating: true }));
+    }
+    let at = DateTime::parse_from_rfc3339(value)
+        .map_err(|_| StoreError::BadRequest(format!("Invalid due date '{}'", value)))?;
+    Ok(Some(Due { at: at.with_timezone(&Utc), is_date: false, floating: false }))
+}
+
+// Sends the export while rows are still being read, so large tables aren't held in memory.
+// The first row is read before answering, so an unreachable database still gets an error
+// status. A failure after that aborts the response instead of ending the file early.
+#[tracing::instrument(skip_all, fields(format = ?format))]
+async fn stream_export(res: &mut Response, format: LineFormat) -> Result<(), StoreError> {
+    circuit_breaker::check()?;
+    let mut rows = sqlx::query_as::<_, ExportRow>(EXPORT_QUERY).fetch(replica::read_pool());
+    let first = rows.try_next().await;
+    circuit_breaker::record(first.as_ref().err());
+    let first = first?;
+
+    let (content_type, file_name) = match format {
+        LineFormat::Csv => ("text/csv; charset=utf-8", "todos.csv"),
+        LineFormat::Ndjson => ("application/x-ndjson", "todos.ndjson"),
+        LineFormat::TodoTxt => ("text/plain; charset=utf-8", "todo.txt"),
+    };
+    file_headers(res, content_type, file_name);
+
+    let header = match format {
+        LineFormat::Csv => Some(Ok::<_, sqlx::Error>(CSV_HEADER.to_string())),
+        _ => None,
+    };
+    let rest = rows.map_err(|e| {
+        circuit_breaker::record(Some(&e));
+        tracing::error!(error = %e, "Export failed while streaming, aborting the response");
+        e
+    });
+    let lines = stream::iter(first.map(Ok)).chain(rest).map_ok(move |row| format.line(&row));
+
+    res.stream(stream::iter(header).chain(lines));
+    Ok(())
+}
+
+impl LineFormat {
+    fn line(self, row: &ExportRow) -> String {
+        let due = row.due.map(|at| match (row.due_is_date, row.due_is_floating) {
+            (Some(true), _) => at.format("%Y-%m-%d").to_string(),
+            (_, Some(true)) => at.format(FLOATING_DUE_FORMAT).to_string(),
+            _ => at.to_rfc3339(),
+        });
+
+        match self {
+            LineFormat::Csv => format!(
+                "{},{},{},{},{}\n",
+                row.id,
+                csv_field(&row.name),
+                csv_field(&row.description),
+                row.done,
+                due.unwrap_or_default()
+            ),
+            LineFormat::Ndjson => format!(
+                "{}\n",
+                serde_json::json!({
+                    "id": row.id,
+                    "name": row.name,
+                    "description": row.description,
+                    "done": row.done,
+                    "due": due
+                })
+            ),
+            LineFormat::TodoTxt => {
+                let mut line = if row.done { "x ".to_string() } else { String::new() };
+                line.push_str(&row.name.split_whitespace().collect::<Vec<_>>().join(" "));
+                if let Some(project) = row.project.as_deref().map(project_tag) {
+                    if !line.split(' ').any(|word| word == project) {
+                        line.push(' ');
+                        line.push_str(&project);
+                    }
+                }
+                if let Some(due) = row.due {
+                    line.push_str(&format!(" due:{}", due.format("%Y-%m-%d")));
+                }
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+// `+project` for a Markdown heading; todo.txt words can't hold spaces, so they become `_`
+fn project_tag(project: &str) -> String {
+    format!("+{}", project.split_whitespace().collect::<Vec<_>>().join("_"))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// The uploaded file, either the raw body or the first file of a multipart form
+pub async fn read_upload(req: &mut Request, max_size: usize) -> Result<String, StoreError> {
+    let is_multipart = req.content_type().is_some_and(|content_type| content_type.type_() == mime::MULTIPART);
+
+    let bytes = if is_multipart {
+        let file = req.first_file()
+            .await
+            .ok_or_else(|| StoreError::BadRequest("Missing file upload".to_string()))?;
+        if file.size() > max_size as u64 {
+            return Err(StoreError::PayloadTooLarge(max_size));
+        }
+        tokio::fs::read(file.path())
+            .await
+            .map_err(|_| StoreError::Unexpected("Uploaded file could not be read"))?
+    } else {
+        req.payload_with_max_size(max_size)
+            .await
+            .map_err(|_| StoreError::BadRequest(format!("Upload is missing or larger than {} bytes", max_size)))?
+            .to_vec()
+    };
+
+    String::from_utf8(bytes).map_err(|_| StoreError::BadRequest("Upload is not valid UTF-8".to_string()))
+}
+
+pub fn file_headers(res: &mut Response, content_type: &'static str, file_name: &str) {
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(disposition) = format!("attachment; filename=\"{}\"", file_name).parse() {
+        res.headers_mut().insert(CONTENT_DISPOSITION, disposition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::StatusCode;
+    use salvo::prelude::*;
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::*;
+    use crate::test_support::{unique, with_database};
+
+    fn export_row(name: &str, done: bool, project: Option<&str>) -> ExportRow {
+        ExportRow {
+            id: 1,
+            name: name.to_string(),
+            description: String::new(),
+            done,
+            due: Some("2025-03-01T00:00:00Z".parse().unwrap()),
+            due_is_date: Some(true),
+            due_is_floating: Some(false),
+            project: project.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn todo_txt_keeps_priority_projects_and_contexts() {
+        let line = LineFormat::TodoTxt.line(&export_row("(A) Call mom @phone", false, Some("Home Office")));
+        assert_eq!(line, "(A) Call mom @phone +Home_Office due:2025-03-01\n");
+
+        let todo = parse_todo_txt_line(&line).unwrap();
+        assert_eq!(todo.name, "(A) Call mom @phone +Home_Office");
+        assert_eq!(todo.project.as_deref(), Some("Home_Office"));
+        assert!(!todo.done);
+        assert_eq!(todo.due.map(|due| due.at.date_naive()), NaiveDate::from_ymd_opt(2025, 3, 1));
+
+        // Exporting what was imported gives the same line
+        let again = LineFormat::TodoTxt.line(&export_row(&todo.name, todo.done, todo.project.as_deref()));
+        assert_eq!(again, line);
+    }
+
+    #[test]
+    fn todo_txt_reads_done_todos_back() {
+        let line = LineFormat::TodoTxt.line(&export_row("(B) Pay rent +home", true, Some("home")));
+        assert_eq!(line, "x (B) Pay rent +home due:2025-03-01\n");
+
+        let todo = parse_todo_txt_line(&line).unwrap();
+        assert!(todo.done);
+        assert_eq!(todo.name, "(B) Pay rent +home");
+        assert_eq!(todo.project.as_deref(), Some("home"));
+    }
+
+    #[test]
+    fn reimporting_markdown_changes_nothing() {
+        with_database(async {
+            let service = Service::new(
+                Router::new()
+                    .push(Router::with_path("import").post(import_markdown))
+                    .push(Router::with_path("export.txt").get(export_todo_txt)),
+            );
+            let project = unique("project");
+            let (parent, child) = (unique("(A) parent @desk"), unique("child"));
+            let document = format!("# {}\n\n- [ ] {}\n  Notes\n  - [x] {}\n", project, parent, child);
+
+            let import = || async {
+                let mut res = TestClient::post("http://localhost/import")
+                    .add_header("content-type", "text/markdown", true)
+                    .body(document.clone())
+                    .send(&service)
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+                res.take_json::<serde_json::Value>().await.unwrap()
+            };
+
+            let first = import().await;
+            assert_eq!(first["created"], 2);
+            let second = import().await;
+            assert_eq!((second["created"].clone(), second["updated"].clone(), second["unchanged"].clone()), (0.into(), 0.into(), 2.into()));
+
+            // todo.txt names the heading as a project
+            let export = TestClient::get("http://localhost/export.txt").send(&service).await.take_string().await.unwrap();
+            assert!(export.contains(&format!("{} +{}\n", parent, project)), "{}", export);
+            assert!(export.contains(&format!("x {} +{}\n", child, project)), "{}", export);
+        });
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_are_limited_too() {
+        let service = Service::new(Router::with_path("import").post(import_csv));
+        let file = "name\n".to_string() + &"x".repeat(MAX_UPLOAD_SIZE);
+        let body = format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"todos.csv\"\r\nContent-Type: text/csv\r\n\r\n{}\r\n--boundary--\r\n",
+            file
+        );
+
+        let res = TestClient::post("http://localhost/import")
+            .add_header("content-type", "multipart/form-data; boundary=boundary", true)
+            .body(body)
+            .send(&service)
+            .await;
+
+        assert_eq!(res.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+}
@@ -1,16 +1,56 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}};
-
-use backend_error::BackendError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use backend_error::{BackendError, StoreError};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use salvo::oapi::ToSchema;
+use salvo::http::header::{HeaderValue, LINK};
+use salvo::conn::Acceptor;
 use salvo::prelude::*;
-use serde::{Serialize, Deserialize};
-use serde_json::json;
-use sqlx::{prelude::FromRow, PgPool};
+use schemas::{ErrorResponse, MessageResponse, TodoListResponse, TodoPayload, TodoResponse, UpdateTodoPayload};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use sqlx::{migrate::Migrator, prelude::FromRow, PgPool};
 use once_cell::sync::OnceCell;
 
 mod pool_sqlx;
 mod backend_error;
-
-#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+mod admin;
+mod api_docs;
+mod backup;
+mod cache;
+mod caldav;
+mod circuit_breaker;
+mod cli;
+mod compression;
+mod conditional;
+mod config;
+mod events;
+mod graphql;
+mod grpc;
+mod health;
+mod ical;
+mod import_export;
+mod markdown;
+mod metrics;
+mod outbox;
+mod rate_limit;
+mod replica;
+mod schemas;
+mod security;
+mod shutdown;
+mod telemetry;
+mod tls;
+mod todo_store;
+mod users;
+mod v2;
+mod webhooks;
+mod ws;
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone, ToSchema, async_graphql::SimpleObject)]
+#[salvo(schema(symbol = "Todo"))]
+#[graphql(complex)]
 struct Todo {
     id: i32,
     name: String,
@@ -18,478 +58,507 @@ struct Todo {
     done: bool,
 }
 
+#[allow(dead_code)]
 struct OperationLock { // TODO not implemented yet
     mutex_lock: Mutex<()>
 }
 
 static DB_POOL: OnceCell<Arc<PgPool>> = OnceCell::new();
 
+static MIGRATOR: Migrator = sqlx::migrate!();
+
 #[tokio::main]
 async fn main() -> Result<(), BackendError> {
-    // Get the database URL from the environment variables
-    let database_url = match std::env::var("DATABASE_URL") {
-        Ok(k) => k,
-        Err(e) => return Err(BackendError::EnvError(format!("Environment variable DATABASE_URL fetching returned: {:?}", e)))
-    };
-    
+    let cli = Cli::parse();
+
+    // Defaults, then the configuration file, `TODO_*` variables and flags
+    let config = Config::load(&cli.config)?;
+    if cli.print_config {
+        print!("{}", config.redacted().to_toml()?);
+        return Ok(());
+    }
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::CheckConfig = command {
+        return cli::check_config(&config);
+    }
+
+    let telemetry = telemetry::init(&config.telemetry)?;
+
     // Establish a connection to the database
-    let pool = pool_sqlx::establish_connection(&database_url).await?;
+    let pool = pool_sqlx::establish_connection(&config.database).await?;
+    metrics::set_pool_limit(config.database.max_connections);
+
+    // `serve` brings the schema up to date; other commands check it themselves
+    if command.applies_migrations() {
+        MIGRATOR.run(&pool).await?;
+    }
 
     // Create an Arc-wrapped pool and set it in the DB_POOL static variable
     DB_POOL.set(
         Arc::new(pool)
     ).unwrap();
 
-    // Create a router and add the routes to it
-    let router = Router::with_path("todos")
-        .get(display_todos)
-        .post(create_todo)
-        .push(
-            Router::with_path("todo")
-                .get(display_one)
-                .delete(delete_todo)    
-                .put(update_todo)
-                .patch(md_todo)
-        );
-
-    // Start the server and bind it to the specified address
-    Server::new(TcpListener::new("127.0.0.1:7878").bind().await).serve(router).await;
+    let result = match command {
+        Command::Serve => serve(config).await,
+        command => cli::run(command).await,
+    };
 
-    Ok(())
+    telemetry.shutdown();
+    result
 }
 
-pub fn get_postgres() -> &'static PgPool {
-    DB_POOL.get().unwrap()
-}
+// Serves HTTP, CalDAV and gRPC until one of the servers stops
+async fn serve(config: Config) -> Result<(), BackendError> {
+    // Pick the outbox sinks, defaulting to the in-process broadcast and webhooks
+    let outbox_sinks = outbox::parse_sinks(&config.outbox.sinks)?;
 
-#[handler]
-async fn display_todos(res: &mut Response) {   
+    // Fail fast while the database is down and notice when it is back
+    circuit_breaker::init(&config.database);
 
-    match sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos")
-        .fetch_all(get_postgres())
-        .await
-    {
-        Ok(data) => {
-            res.render(Json(json!({
-                "success": true,
-                "todos": data
-            })));
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
+    // Serve list and lookup queries from the read replica, if there is one
+    if let Some(url) = config.database.replica_url.as_deref() {
+        replica::init(pool_sqlx::replica_pool(&config.database, url)?, &config.database);
     }
-}
 
-#[handler]
-async fn display_one(req: &mut Request, res: &mut Response) {
-    
-    // Extract the "id" parameter from the request URL
-    let todo_id = match req.query::<i32>("id") {
-        Some(id) => id,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'id' query parameter"
-            })));
-            return;
-        }
+    // Drain the outbox and finish webhook deliveries interrupted by a restart in the background
+    webhooks::resume_pending().await?;
+    let dispatcher = tokio::spawn(outbox::run_dispatcher(outbox_sinks.dispatched, config.outbox.retention()));
+
+    // Every instance feeds its own subscribers, whichever instance recorded the event
+    let broadcaster = if outbox_sinks.broadcast {
+        Some(tokio::spawn(outbox::run_broadcaster()))
+    } else {
+        health::OUTBOX_BROADCASTER.disable();
+        None
     };
 
-    // Execute the SQL query to fetch the todo item with the given id from the database
-    match sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos WHERE id = $1")
-        .bind(todo_id)
-        .fetch_optional(get_postgres())
-        .await
-    {
-        Ok(Some(todo)) => {
-            res.render(Json(json!({
-                "success": true,
-                "todo": todo
-            })));
-        }
-        Ok(None) => {
-            res.status_code(StatusCode::NOT_FOUND);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Todo with id '{}' not found", todo_id)
-            })));
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
+    // Generate the OpenAPI document; `every_route_is_documented` keeps it complete
+    let (router, documented) = api_docs::with_docs(api_router(config.admin.token.clone()));
+
+    // CalDAV uses WebDAV methods that OpenAPI can't describe
+    let router = router.push(caldav::router());
+
+    // Trace, count and time every request under the route it matched, including unmatched ones
+    let routes = api_docs::RouteTemplates::new(documented.into_iter().chain(caldav::ROUTE_TEMPLATES.map(String::from)));
+    let mut service = Service::new(router)
+        .hoop(telemetry::RequestTracing::new(routes.clone()))
+        .hoop(metrics::RequestMetrics::new(routes));
+
+    if config.compression.enabled {
+        service = service.hoop(compression::ResponseCompression::new(&config.compression));
     }
-}
 
-#[handler]
-async fn create_todo(req: &mut Request, res: &mut Response) {
-
-    // Parse the JSON payload from the request into a `HashMap<String, String>`
-    let request_data = match req.parse_json::<HashMap<String, String>>().await {
-        Ok(data) => data,
-        Err(_) => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Invalid JSON payload"
-            })));
-            return;
-        }
-    };
-    
-    // Extract the `name` value from the request_data
-    let todo_name = match request_data.get("name") {
-        Some(name) if !name.trim().is_empty() => name,
-        _ => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing or empty 'name' field"
-            })));
-            return;
-        }
-    };
+    // Every response gets the security headers, and CORS headers so browsers can read even refusals
+    if config.security_headers.enabled {
+        service = service.hoop(security::SecurityHeaders::new(&config.security_headers, config.tls.enabled()));
+    }
+    if let Some(cors) = security::CrossOrigin::new(&config.cors) {
+        service = service.hoop(cors);
+    }
 
-    // Check if a todo with the same name already exists
-    match sqlx::query("SELECT 1 FROM todos WHERE name = $1")
-        .bind(todo_name)
-        .fetch_optional(get_postgres())
-        .await
-    {
-        Ok(Some(_)) => {
-            res.status_code(StatusCode::CONFLICT);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Todo with that name already exists"
-            })));
-            return;
-        },
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-            return;
-        },
-        Ok(None) => {}
+    // Refused requests are still traced and counted
+    if config.rate_limit.enabled {
+        service = service.hoop(rate_limit::RateLimiter::new(config.rate_limit.clone(), config.server.trusted_proxy_hops));
+    }
+    // `Request::parse_json` and `payload` read up to this size. The setting is process-wide,
+    // so it is made once here rather than by each hoop reading bodies.
+    salvo::http::request::set_secure_max_size(config.limits.max_body_bytes);
+    service = service.hoop(security::BodyLimits::new(config.limits.clone()));
+
+    // Callers read their own changes from the primary
+    if config.database.replica_url.is_some() {
+        service = service.hoop(replica::TrackWrites::new(config.server.trusted_proxy_hops));
     }
 
-    // Extract the `description` value from the request_data
-    let todo_desc = match request_data.get("description") {
-        Some(desc) => desc,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'description' field"
-            })));
-            return;
+    // Repeated reads of todos are answered from the cache unless a request asks for fresh data
+    if let Some(backend) = cache::parse_backend(&config.cache)? {
+        cache::init(backend, config.cache.ttl());
+        service = service.hoop(cache::request_policy);
+    }
+
+    // Read the certificate before anything starts, so a broken one stops the handler right away
+    let tls_configs = config.tls.enabled().then(|| tls::config_stream(config.tls.clone())).transpose()?;
+    let grace = config.server.shutdown_timeout();
+
+    // On SIGTERM or SIGINT stop accepting connections and give requests in flight until the deadline
+    tokio::spawn(async {
+        shutdown::signal().await;
+        tracing::info!("Shutting down, draining connections");
+        shutdown::trigger();
+    });
+
+    // Start the server and bind it to the specified address, with TLS when a certificate is configured
+    let http = async {
+        let listener = TcpListener::new(config.server.bind);
+        match tls_configs {
+            Some(tls_configs) => serve_http(listener.rustls(tls_configs).bind().await, service, grace).await,
+            None => serve_http(listener.bind().await, service, grace).await,
         }
     };
 
-    // Insert the new todo and return it
-    match sqlx::query_as::<_, Todo>(
-        "INSERT INTO todos (name, description) VALUES ($1, $2) RETURNING id, name, description, done"
-    )
-    .bind(todo_name)
-    .bind(todo_desc)
-    .fetch_one(get_postgres())
-    .await
-    {
-        Ok(todo) => {
-            res.status_code(StatusCode::CREATED);
-            res.render(Json(json!({
-                "success": true,
-                "todo": todo
-            })));
-        },
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
+    // A failing gRPC server takes the HTTP server down with it
+    let grpc = async {
+        let result = grpc::serve(config.server.grpc_bind, grace).await;
+        shutdown::trigger();
+        result
+    };
+    let (_, grpc_result) = tokio::join!(http, grpc);
+
+    // Dispatch what the last requests recorded, wait for webhook attempts in progress, then disconnect,
+    // each within what the draining left of the shutdown timeout
+    outbox::stop();
+    if tokio::time::timeout(shutdown::remaining(grace), dispatcher).await.is_err() {
+        tracing::warn!("Outbox events still undispatched after the shutdown timeout are left for the next start");
+    }
+    webhooks::finish_deliveries(shutdown::remaining(grace)).await;
+    // Subscribers are gone by now; stopping the broadcaster returns its connections
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.abort();
+        let _ = broadcaster.await;
     }
+    get_postgres().close().await;
+
+    grpc_result
+}
+
+// Every route of the documented API, which excludes CalDAV
+fn api_router(admin_token: Option<String>) -> Router {
+    // Metrics reveal routes, traffic and the number of todos, so they need admin credentials too
+    let metrics_router = Router::with_path("metrics")
+        .hoop(admin::AdminAuth::new(admin_token.clone()))
+        .get(metrics::metrics);
+
+    let todos_router = Router::with_path("todos")
+        .push(
+            // v1 routes, superseded by `/v2/todos`
+            Router::new()
+                .hoop(v1_deprecation)
+                .get(display_todos)
+                .post(create_todo)
+                .push(
+                    Router::with_path("todo")
+                        .get(display_one)
+                        .delete(delete_todo)
+                        .put(update_todo)
+                        .patch(md_todo)
+                )
+        )
+        .push(Router::with_path("export.ics").get(import_export::export_ics))
+        .push(Router::with_path("export.csv").get(import_export::export_csv))
+        .push(Router::with_path("export.ndjson").get(import_export::export_ndjson))
+        .push(Router::with_path("export.txt").get(import_export::export_todo_txt))
+        .push(Router::with_path("export.md").get(import_export::export_markdown))
+        .push(
+            Router::with_path("import")
+                .post(import_export::import_ics)
+                .push(Router::with_path("csv").post(import_export::import_csv))
+                .push(Router::with_path("ndjson").post(import_export::import_ndjson))
+                .push(Router::with_path("todotxt").post(import_export::import_todo_txt))
+                .push(Router::with_path("markdown").post(import_export::import_markdown))
+        )
+        .push(Router::with_path("ws").get(ws::todo_socket))
+        .push(Router::with_path("events").get(events::event_stream));
+
+    let webhooks_router = Router::with_path("webhooks")
+        .get(webhooks::list_subscriptions)
+        .post(webhooks::create_subscription)
+        .push(Router::with_path("webhook").delete(webhooks::delete_subscription))
+        .push(Router::with_path("deliveries").get(webhooks::list_deliveries));
+
+    let graphql_router = Router::with_path("graphql")
+        .get(graphql::graphql_socket)
+        .post(graphql::graphql_request);
+
+    Router::new()
+        .push(todos_router)
+        .push(v2::router())
+        .push(webhooks_router)
+        .push(graphql_router)
+        .push(admin::router(admin_token))
+        .push(Router::with_path("healthz").get(health::healthz))
+        .push(Router::with_path("readyz").get(health::readyz))
+        .push(metrics_router)
+}
+
+// Serves HTTP/1.1 and HTTP/2 until shutdown, then stops gracefully within `grace`
+async fn serve_http<A: Acceptor + Send>(acceptor: A, service: Service, grace: Duration) {
+    let server = Server::new(acceptor);
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown::wait().await;
+        handle.stop_graceful(shutdown::remaining(grace));
+    });
+
+    server.serve(service).await;
+}
+
+pub fn get_postgres() -> &'static PgPool {
+    DB_POOL.get().unwrap()
 }
 
+// Marks every v1 response as deprecated and points clients at v2
 #[handler]
-async fn md_todo(req: &mut Request, res: &mut Response) {
-    
-    // Extract the "id" parameter from the request URL
-    let todo_id = match req.query::<i32>("id") {
-        Some(id) => id,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'id' query parameter"
-            })));
-            return;
-        }
-    };
+async fn v1_deprecation(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    ctrl.call_next(req, depot, res).await;
 
-    // Check if the todo exists
-    match sqlx::query("SELECT 1 FROM todos WHERE id = $1")
-        .bind(todo_id)
-        .fetch_optional(get_postgres())
-        .await
-    {
-        Ok(None) => {
-            res.status_code(StatusCode::NOT_FOUND);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Todo with id {} does not exist", todo_id)
-            })));
-            return;
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-            return;
-        }
-        Ok(Some(_)) => {}
-    }
+    res.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+    res.headers_mut().insert(LINK, HeaderValue::from_static("</v2/todos>; rel=\"successor-version\""));
+}
 
-    // Mark the todo as done and fetch it
-    match sqlx::query_as::<_, Todo>(
-        "UPDATE todos SET done = true WHERE id = $1 RETURNING id, name, description, done"
+/// List every todo; `If-None-Match` or `If-Modified-Since` return 304 while it is unchanged.
+#[endpoint(
+    tags("todos"),
+    status_codes(200, 304, 429, 500),
+    responses(
+        (status_code = 200, description = "All todos", body = TodoListResponse),
+        (status_code = 304, description = "The list is unchanged")
     )
-    .bind(todo_id)
-    .fetch_optional(get_postgres())
-    .await
-    {
-        Ok(Some(todo)) => {
-            res.render(Json(json!({
-                "success": true,
-                "todo": todo
-            })));
-        }
-        Ok(None) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Todo was marked as done but could not be retrieved"
-            })));
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
-    }
+)]
+async fn display_todos(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    conditional::render_todo_list(req, res).await
 }
 
-#[handler]
-async fn delete_todo(req: &mut Request, res: &mut Response) {
-    
+/// Fetch one todo.
+#[endpoint(
+    tags("todos"),
+    status_codes(200, 400, 404, 429, 500),
+    parameters(("id" = i32, Query, description = "Todo id")),
+    responses((status_code = 200, description = "The todo", body = TodoResponse))
+)]
+async fn display_one(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+
     // Extract the "id" parameter from the request URL
-    let todo_id = match req.query::<i32>("id") {
-        Some(id) => id,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'id' query parameter"
-            })));
-            return;
-        }
-    };
+    let todo_id = query_id(req)?;
 
-    // Check if the todo exists
-    match sqlx::query("SELECT 1 FROM todos WHERE id = $1")
-        .bind(todo_id)
-        .fetch_optional(get_postgres())
-        .await
-    {
-        Ok(None) => {
+    match todo_store::fetch_todo(todo_id).await? {
+        Some(todo) => res.render(Json(TodoResponse::new(todo))),
+        None => {
             res.status_code(StatusCode::NOT_FOUND);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Todo with id {} does not exist", todo_id)
-            })));
-            return;
+            res.render(Json(ErrorResponse::new(format!("Todo with id '{}' not found", todo_id))));
         }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-            return;
-        }
-        Ok(Some(_)) => {}
     }
+    Ok(())
+}
 
-    // Delete the todo
-    match sqlx::query("DELETE FROM todos WHERE id = $1")
-        .bind(todo_id)
-        .execute(get_postgres())
-        .await
-    {
-        Ok(result) => {
-            if result.rows_affected() == 1 {
-                res.render(Json(json!({
-                    "success": true,
-                    "message": format!("Todo with id {} successfully deleted", todo_id)
-                })));
-            } else {
-                // Rare but possible case
-                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-                res.render(Json(json!({
-                    "success": false,
-                    "error": "Todo was not deleted due to an unknown error"
-                })));
-            }
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
-    }
+/// Create a todo.
+#[endpoint(
+    tags("todos"),
+    status_codes(201, 400, 409, 413, 429, 500),
+    request_body = TodoPayload,
+    responses((status_code = 201, description = "The created todo", body = TodoResponse))
+)]
+async fn create_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+
+    let todo = create_from_payload(req).await?;
+
+    res.status_code(StatusCode::CREATED);
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
 }
 
-#[handler]
-async fn update_todo(req: &mut Request, res: &mut Response) {
+/// Mark a todo as done.
+#[endpoint(
+    tags("todos"),
+    status_codes(200, 400, 404, 429, 500),
+    parameters(("id" = i32, Query, description = "Todo id")),
+    responses((status_code = 200, description = "The updated todo", body = TodoResponse))
+)]
+async fn md_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
 
     // Extract the "id" parameter from the request URL
-    let todo_id = match req.query::<i32>("id") {
-        Some(id) => id,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'id' query parameter"
-            })));
-            return;
-        }
-    };
+    let todo_id = query_id(req)?;
+
+    let todo = todo_store::mark_done(todo_id).await?;
+
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
+}
+
+/// Delete a todo.
+#[endpoint(
+    tags("todos"),
+    status_codes(200, 400, 404, 429, 500),
+    parameters(("id" = i32, Query, description = "Todo id")),
+    responses((status_code = 200, description = "The todo was deleted", body = MessageResponse))
+)]
+async fn delete_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+
+    // Extract the "id" parameter from the request URL
+    let todo_id = query_id(req)?;
+
+    todo_store::delete_todo(todo_id).await?;
+
+    res.render(Json(MessageResponse::new(format!("Todo with id {} successfully deleted", todo_id))));
+    Ok(())
+}
+
+/// Replace every field of a todo.
+#[endpoint(
+    tags("todos"),
+    status_codes(200, 400, 404, 413, 429, 500),
+    parameters(("id" = i32, Query, description = "Todo id")),
+    request_body = UpdateTodoPayload,
+    responses((status_code = 200, description = "The updated todo", body = TodoResponse))
+)]
+async fn update_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+
+    // Extract the "id" parameter from the request URL
+    let todo_id = query_id(req)?;
+
+    let updated_todo = update_from_payload(todo_id, req).await?;
+
+    res.render(Json(TodoResponse::new(updated_todo)));
+    Ok(())
+}
+
+// Request handling shared by the v1 and v2 routes
+
+async fn create_from_payload(req: &mut Request) -> Result<Todo, StoreError> {
+
+    // Parse the JSON payload from the request
+    let payload = parse_payload::<TodoPayload>(req).await?;
+
+    // Extract the `name` value from the payload
+    let todo_name = payload.name.unwrap_or_default();
+    todo_store::validate_name(&todo_name)?;
+
+    // Check if a todo with the same name already exists
+    if todo_store::name_taken(&todo_name).await? {
+        return Err(StoreError::Conflict);
+    }
+
+    // Extract the `description` value from the payload
+    let todo_desc = payload.description
+        .ok_or_else(|| StoreError::BadRequest("Missing 'description' field".to_string()))?;
+
+    todo_store::create_todo(&todo_name, &todo_desc).await
+}
+
+async fn update_from_payload(todo_id: i32, req: &mut Request) -> Result<Todo, StoreError> {
 
     // Check if the todo exists
-    match sqlx::query("SELECT 1 FROM todos WHERE id = $1")
-        .bind(todo_id)
-        .fetch_optional(get_postgres())
-        .await
-    {
-        Ok(None) => {
-            res.status_code(StatusCode::NOT_FOUND);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Todo with id {} does not exist", todo_id)
-            })));
-            return;
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-            return;
-        }
-        Ok(Some(_)) => {}
+    if !todo_store::todo_exists(todo_id).await? {
+        return Err(StoreError::NotFound(todo_id));
     }
 
-    // Parse the JSON payload from the request into a HashMap
-    let request_data = match req.parse_json::<HashMap<String, String>>().await {
-        Ok(data) => data,
-        Err(_) => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Invalid JSON payload"
-            })));
-            return;
-        }
-    };
+    // Parse the JSON payload from the request
+    let payload = parse_payload::<UpdateTodoPayload>(req).await?;
 
     // Extract and validate the "name" field
-    let name = match request_data.get("name") {
-        Some(name) if !name.trim().is_empty() => name,
-        _ => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing or empty 'name' field"
-            })));
-            return;
-        }
-    };
+    let name = payload.name.unwrap_or_default();
+    todo_store::validate_name(&name)?;
 
     // Extract the "description" field
-    let description = match request_data.get("description") {
-        Some(desc) => desc,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing 'description' field"
-            })));
-            return;
-        }
-    };
+    let description = payload.description
+        .ok_or_else(|| StoreError::BadRequest("Missing 'description' field".to_string()))?;
 
     // Extract and parse the "done" field
-    let done: bool = match request_data.get("done").and_then(|d| d.parse().ok()) {
-        Some(done) => done,
-        None => {
-            res.status_code(StatusCode::BAD_REQUEST);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Missing or invalid 'done' field"
-            })));
+    let done: bool = payload.done
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(|| StoreError::BadRequest("Missing or invalid 'done' field".to_string()))?;
+
+    todo_store::update_todo(todo_id, &name, &description, done).await
+}
+
+fn query_id(req: &Request) -> Result<i32, StoreError> {
+    req.query::<i32>("id")
+        .ok_or_else(|| StoreError::BadRequest("Missing 'id' query parameter".to_string()))
+}
+
+async fn parse_payload<T: DeserializeOwned>(req: &mut Request) -> Result<T, StoreError> {
+    req.parse_json::<T>()
+        .await
+        .map_err(|_| StoreError::BadRequest("Invalid JSON payload".to_string()))
+}
+
+// Tests against the database in `TEST_DATABASE_URL`, which are skipped without it
+#[cfg(test)]
+mod test_support {
+    use std::future::Future;
+
+    use once_cell::sync::Lazy;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    // Shared by every database test, as pooled connections belong to the runtime that opened them
+    static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().expect("Failed to start the test runtime"));
+
+    // Runs `test` once the schema is migrated, unless no test database is configured
+    pub fn with_database<F: Future<Output = ()>>(test: F) {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL is not set, skipping");
             return;
-        }
-    };
+        };
 
-    // Update the todo in the database and fetch it
-    match sqlx::query_as::<_, Todo>(
-        "UPDATE todos SET name = $1, description = $2, done = $3 WHERE id = $4 RETURNING id, name, description, done"
-    )
-    .bind(name)
-    .bind(description)
-    .bind(done)
-    .bind(todo_id)
-    .fetch_optional(get_postgres())
-    .await
-    {
-        Ok(Some(updated_todo)) => {
-            res.render(Json(json!({
-                "success": true,
-                "todo": updated_todo
-            })));
-        }
-        Ok(None) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": "Todo was not updated due to an unknown error"
-            })));
-        }
-        Err(e) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-            res.render(Json(json!({
-                "success": false,
-                "error": format!("Database error: {}", e)
-            })));
-        }
+        RUNTIME.block_on(async {
+            if DB_POOL.get().is_none() {
+                let pool = PgPool::connect(&url).await.expect("Failed to connect to TEST_DATABASE_URL");
+                MIGRATOR.run(&pool).await.expect("Failed to migrate the test database");
+                let _ = DB_POOL.set(Arc::new(pool));
+            }
+            test.await
+        })
+    }
+
+    // Feeds committed events to this process's subscribers, as `serve` does
+    pub async fn broadcast_events() {
+        static STARTED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+        STARTED
+            .get_or_init(|| async {
+                let mut received = events::subscribe();
+                tokio::spawn(outbox::run_broadcaster());
+
+                // It skips what was committed before it started, so probe until an event comes through
+                loop {
+                    let mut conn = get_postgres().acquire().await.unwrap();
+                    outbox::record(&mut conn, &events::TodoEvent::deleted(PROBE_TODO_ID)).await.unwrap();
+                    let probe = async {
+                        while !received.recv().await.is_ok_and(|event| event.todo_id == PROBE_TODO_ID) {}
+                    };
+                    if tokio::time::timeout(Duration::from_millis(200), probe).await.is_ok() {
+                        break;
+                    }
+                }
+                sqlx::query("DELETE FROM outbox WHERE todo_id = $1").bind(PROBE_TODO_ID).execute(get_postgres()).await.unwrap();
+            })
+            .await;
+    }
+
+    // No todo has this id
+    const PROBE_TODO_ID: i32 = -1;
+
+    // A name no other test run uses
+    pub fn unique(prefix: &str) -> String {
+        format!("{}-{:016x}", prefix, rand::random::<u64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Routes added with `#[handler]` instead of `#[endpoint]` would be missing from `/openapi.json`
+    #[test]
+    fn every_route_is_documented() {
+        let undocumented = api_docs::undocumented_handlers(&api_router(None));
+        assert!(undocumented.is_empty(), "Undocumented handlers: {}", undocumented.join(", "));
+
+        // CalDAV isn't documented, which the check must notice
+        assert!(!api_docs::undocumented_handlers(&caldav::router()).is_empty());
+    }
+
+    #[test]
+    fn requests_are_labelled_by_their_route() {
+        let (_, documented) = api_docs::with_docs(api_router(None));
+        let routes = api_docs::RouteTemplates::new(documented.into_iter().chain(caldav::ROUTE_TEMPLATES.map(String::from)));
+
+        assert_eq!(routes.find("/v2/todos/42").as_deref(), Some("/v2/todos/{id}"));
+        assert_eq!(routes.find("/todos/export.ics").as_deref(), Some("/todos/export.ics"));
+        assert_eq!(routes.find("/caldav/todos/abc.ics").as_deref(), Some("/caldav/todos/{name}"));
+        assert_eq!(routes.find("/docs/index.html").as_deref(), Some("/docs/{**rest}"));
+        assert_eq!(routes.find("/nowhere"), None);
     }
 }
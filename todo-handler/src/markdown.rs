@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+// Markdown checklists (`- [ ]` / `- [x]`) as an outline of todos. Headings
+// are projects, nested items are subtasks and text indented under an item is
+// its description. `render` writes what `parse` reads back unchanged.
+
+const INDENT: &str = "  ";
+
+#[derive(Debug)]
+pub struct ChecklistItem {
+    pub name: String,
+    pub description: String,
+    pub done: bool,
+    pub project: Option<String>,
+    // Index of the enclosing item
+    pub parent: Option<usize>,
+}
+
+// A todo in export order, with its place in the outline
+#[derive(Debug)]
+pub struct OutlineTodo {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub done: bool,
+    pub project: Option<String>,
+    pub parent_id: Option<i32>,
+}
+
+// Reads every checklist item; other lists, paragraphs and code blocks are ignored
+pub fn parse(input: &str) -> Vec<ChecklistItem> {
+    let mut items: Vec<ChecklistItem> = Vec::new();
+    let mut project = None;
+    // Indentation and index of the items enclosing the current line
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut blank_lines = 0;
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        let line = line.replace('\t', "    ");
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        if trimmed.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+
+        if let Some(heading) = heading(trimmed) {
+            project = Some(heading.to_string()).filter(|heading| !heading.is_empty());
+            open.clear();
+        } else {
+            while open.last().is_some_and(|&(open_indent, _)| open_indent >= indent) {
+                open.pop();
+            }
+
+            if let Some((done, name)) = checklist_item(trimmed) {
+                items.push(ChecklistItem {
+                    name: name.trim().to_string(),
+                    description: String::new(),
+                    done,
+                    project: project.clone(),
+                    parent: open.last().map(|&(_, index)| index),
+                });
+                open.push((indent, items.len() - 1));
+            } else if let Some(&(_, index)) = open.last() {
+                let description = &mut items[index].description;
+                if !description.is_empty() {
+                    description.push_str(&"\n".repeat(blank_lines + 1));
+                }
+                description.push_str(&unescape(trimmed.trim_end()));
+            }
+        }
+        blank_lines = 0;
+    }
+
+    items
+}
+
+// Todos without a project come first, then one heading per project in order of appearance
+pub fn render(todos: &[OutlineTodo]) -> String {
+    let mut projects: Vec<Option<&str>> = vec![None];
+    for todo in todos {
+        if !projects.contains(&todo.project.as_deref()) {
+            projects.push(todo.project.as_deref());
+        }
+    }
+
+    let mut out = String::new();
+    let mut rendered = HashSet::new();
+    for project in projects {
+        let members: Vec<&OutlineTodo> = todos.iter().filter(|todo| todo.project.as_deref() == project).collect();
+        if members.is_empty() {
+            continue;
+        }
+
+        if let Some(project) = project {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("# {}\n\n", project));
+        }
+
+        // Subtasks of todos in other projects are shown at the top level
+        let ids: HashSet<i32> = members.iter().map(|todo| todo.id).collect();
+        for todo in members.iter().filter(|todo| todo.parent_id.is_none_or(|parent_id| !ids.contains(&parent_id))) {
+            render_item(&mut out, todo, &members, 0, &mut rendered);
+        }
+    }
+
+    out
+}
+
+fn render_item(out: &mut String, todo: &OutlineTodo, members: &[&OutlineTodo], depth: usize, rendered: &mut HashSet<i32>) {
+    if !rendered.insert(todo.id) {
+        return;
+    }
+
+    let name = todo.name.split_whitespace().collect::<Vec<_>>().join(" ");
+    out.push_str(&format!("{}- [{}] {}\n", INDENT.repeat(depth), if todo.done { "x" } else { " " }, name));
+
+    for line in todo.description.trim().lines() {
+        if line.trim().is_empty() {
+            out.push('\n');
+        } else {
+            out.push_str(&format!("{}{}\n", INDENT.repeat(depth + 1), escape(line.trim())));
+        }
+    }
+
+    for child in members.iter().filter(|child| child.parent_id == Some(todo.id)) {
+        render_item(out, child, members, depth + 1, rendered);
+    }
+}
+
+// `# Heading` to `###### Heading`
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = line[level..].strip_prefix(' ').or(Some(&line[level..]).filter(|text| text.is_empty()))?;
+    (1..=6).contains(&level).then(|| text.trim().trim_end_matches('#').trim_end())
+}
+
+// `- [ ] name`, `* [x] name` or `1. [ ] name`
+fn checklist_item(line: &str) -> Option<(bool, &str)> {
+    let rest = match line.strip_prefix(['-', '*', '+']) {
+        Some(rest) => rest,
+        None => {
+            let digits = line.chars().take_while(char::is_ascii_digit).count();
+            line[digits..].strip_prefix(['.', ')']).filter(|_| digits > 0)?
+        }
+    };
+    let rest = rest.strip_prefix(' ')?.trim_start();
+
+    let done = match rest.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    let name = &rest[3..];
+    (name.is_empty() || name.starts_with(' ')).then_some((done, name))
+}
+
+// Description lines that would otherwise be read as headings, list items or code fences
+fn escape(line: &str) -> String {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let ordered_marker = digits > 0 && line[digits..].starts_with(['.', ')']);
+
+    if line.starts_with(['-', '*', '+', '#', '\\', '`', '~']) || ordered_marker {
+        format!("\\{}", line)
+    } else {
+        line.to_string()
+    }
+}
+
+fn unescape(line: &str) -> String {
+    match line.strip_prefix('\\') {
+        Some(rest) if rest.starts_with(['-', '*', '+', '#', '\\', '`', '~']) || rest.starts_with(|c: char| c.is_ascii_digit()) => {
+            rest.to_string()
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todo(id: i32, name: &str, description: &str, project: Option<&str>, parent_id: Option<i32>) -> OutlineTodo {
+        OutlineTodo {
+            id,
+            name: name.to_string(),
+            description: description.to_string(),
+            done: id % 2 == 0,
+            project: project.map(str::to_string),
+            parent_id,
+        }
+    }
+
+    #[test]
+    fn parses_what_it_renders() {
+        let todos = vec![
+            todo(1, "(A) Call mom +family @phone", "", None, None),
+            todo(2, "Plan trip", "- not an item\n\n# not a heading", Some("Holidays"), None),
+            todo(3, "Book flights @laptop", "", Some("Holidays"), Some(2)),
+        ];
+        let document = render(&todos);
+
+        let items = parse(&document);
+        assert_eq!(items.len(), 3);
+        for (item, todo) in items.iter().zip(&todos) {
+            assert_eq!(item.name, todo.name);
+            assert_eq!(item.description, todo.description);
+            assert_eq!(item.done, todo.done);
+            assert_eq!(item.project, todo.project);
+        }
+        assert_eq!(items[2].parent, Some(1));
+    }
+
+    #[test]
+    fn reads_headings_nesting_and_descriptions() {
+        let items = parse("Intro\n\n- [ ] Loose\n\n## Work ##\n\n1. [x] Report\n   Due friday\n   * [ ] Draft\n- plain item\n```\n- [ ] in code\n```\n");
+        let summary: Vec<_> = items.iter().map(|item| (item.name.as_str(), item.done, item.project.as_deref(), item.parent)).collect();
+        assert_eq!(summary, [("Loose", false, None, None), ("Report", true, Some("Work"), None), ("Draft", false, Some("Work"), Some(1))]);
+        assert_eq!(items[1].description, "Due friday");
+    }
+}
@@ -0,0 +1,222 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use prometheus::core::Collector;
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use salvo::http::header::{HeaderValue, CONTENT_TYPE};
+use salvo::prelude::*;
+use sqlx::pool::PoolConnection;
+use sqlx::{PgPool, Postgres};
+use tokio::time::timeout;
+
+use crate::api_docs::RouteTemplates;
+use crate::get_postgres;
+
+// Prometheus metrics served at `/metrics`. Requests are labelled with the
+// route they matched, such as `/v2/todos/{id}`, so ids don't create new series.
+// Pool and todo gauges are read when scraped, except the pool's waiters,
+// which are counted as tasks wait in `acquire`.
+
+// Requests that match no known route share one label
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// The todo counts are skipped rather than delaying the scrape
+const SCRAPE_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests by method, matched route and status code"),
+        &["method", "route", "status"],
+    ))
+});
+
+static HTTP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "Time from receiving a request to having its response ready"),
+        &["method", "route"],
+    ))
+});
+
+static QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("db_query_duration_seconds", "Duration of todo store operations, including their transaction"),
+        &["operation"],
+    ))
+});
+
+static POOL_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new("db_pool_connections", "Database pool connections by state (open, idle, in_use)"),
+        &["state"],
+    ))
+});
+
+static POOL_WAITERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new("db_pool_waiters", "Tasks waiting for a connection, by pool (primary, replica)"),
+        &["pool"],
+    ))
+});
+
+static POOL_MAX_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("db_pool_max_connections", "Upper limit of the database pool"))
+});
+
+static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("rate_limited_requests_total", "Requests refused with 429 by class (read, write)"),
+        &["class"],
+    ))
+});
+
+static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("cache_lookups_total", "Read-through cache lookups by query and result (hit, miss, bypass)"),
+        &["query", "result"],
+    ))
+});
+
+static DB_READS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("db_reads_total", "Todo store reads by the database serving them (primary, replica)"),
+        &["pool"],
+    ))
+});
+
+static DB_CIRCUIT_OPEN: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("db_circuit_open", "1 while the circuit breaker fails store operations fast, 0 otherwise"))
+});
+
+static DB_QUERY_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("db_query_retries_total", "Reads repeated after a transient database error, by operation"),
+        &["operation"],
+    ))
+});
+
+static TODOS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(Opts::new("todos", "Todos by state (open, done)"), &["state"]))
+});
+
+fn register<T: Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+    let metric = metric.expect("Metric definitions are valid");
+    REGISTRY.register(Box::new(metric.clone())).expect("Metric names are unique");
+    metric
+}
+
+// Records the duration of a store operation when dropped
+pub fn query_timer(operation: &str) -> HistogramTimer {
+    QUERY_DURATION.with_label_values(&[operation]).start_timer()
+}
+
+pub fn record_rate_limited(class: &str) {
+    RATE_LIMITED.with_label_values(&[class]).inc();
+}
+
+pub fn record_cache_lookup(query: &str, result: &str) {
+    CACHE_LOOKUPS.with_label_values(&[query, result]).inc();
+}
+
+pub fn record_read(pool: &str) {
+    DB_READS.with_label_values(&[pool]).inc();
+}
+
+pub fn set_circuit_open(open: bool) {
+    DB_CIRCUIT_OPEN.set(i64::from(open));
+}
+
+pub fn record_query_retry(operation: &str) {
+    DB_QUERY_RETRIES.with_label_values(&[operation]).inc();
+}
+
+pub fn set_pool_limit(max_connections: u32) {
+    POOL_MAX_CONNECTIONS.set(max_connections.into());
+}
+
+// Takes a connection from `pool`, counted in `db_pool_waiters` while waiting for it
+pub async fn acquire(pool: &PgPool, name: &str) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+    struct Waiting(IntGauge);
+
+    impl Drop for Waiting {
+        fn drop(&mut self) {
+            self.0.dec();
+        }
+    }
+
+    let waiting = Waiting(POOL_WAITERS.with_label_values(&[name]));
+    waiting.0.inc();
+    pool.acquire().await
+}
+
+// Counts and times every request under the route it matched
+pub struct RequestMetrics {
+    routes: RouteTemplates,
+}
+
+impl RequestMetrics {
+    pub fn new(routes: RouteTemplates) -> Self {
+        RequestMetrics { routes }
+    }
+}
+
+#[handler]
+impl RequestMetrics {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let route = self.routes.find(req.uri().path());
+
+        ctrl.call_next(req, depot, res).await;
+
+        // Salvo only sets the 404 of unmatched requests after the hoops ran
+        let default_status = if route.is_none() { StatusCode::NOT_FOUND } else { StatusCode::OK };
+        let status = res.status_code.unwrap_or(default_status).as_u16().to_string();
+        let route = route.as_deref().unwrap_or(UNMATCHED_ROUTE);
+        HTTP_REQUESTS.with_label_values(&[&method, route, &status]).inc();
+        HTTP_DURATION.with_label_values(&[&method, route]).observe(started.elapsed().as_secs_f64());
+    }
+}
+
+/// Metrics in the Prometheus text format.
+///
+/// HTTP request counts and latencies per route, store operation durations, database pool
+/// connections and waiters and the number of open and done todos. Requires the admin token
+/// or an API key.
+#[endpoint(
+    tags("metrics"),
+    status_codes(200, 401),
+    responses((status_code = 200, description = "Prometheus text exposition format", content_type = "text/plain", body = String))
+)]
+pub async fn metrics(res: &mut Response) {
+    let pool = get_postgres();
+    let idle = pool.num_idle() as i64;
+    let open = i64::from(pool.size());
+    POOL_CONNECTIONS.with_label_values(&["open"]).set(open);
+    POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    POOL_CONNECTIONS.with_label_values(&["in_use"]).set(open - idle);
+
+    let counts = timeout(
+        SCRAPE_QUERY_TIMEOUT,
+        sqlx::query_as::<_, (i64, i64)>("SELECT count(*) FILTER (WHERE NOT done), count(*) FILTER (WHERE done) FROM todos")
+            .fetch_one(pool),
+    )
+    .await;
+    if let Ok(Ok((open_todos, done_todos))) = counts {
+        TODOS.with_label_values(&["open"]).set(open_todos);
+        TODOS.with_label_values(&["done"]).set(done_todos);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if encoder.encode(&REGISTRY.gather(), &mut body).is_err() {
+        res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    }
+
+    if let Ok(content_type) = HeaderValue::from_str(encoder.format_type()) {
+        res.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    res.body(body.into());
+}
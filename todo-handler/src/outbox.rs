@@ -0,0 +1,482 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use salvo::async_trait;
+use sqlx::postgres::PgListener;
+use sqlx::PgConnection;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use crate::backend_error::BackendError;
+use crate::events::{self, TodoEvent};
+use crate::{get_postgres, health, metrics, webhooks};
+
+// Events are written to the `outbox` table in the same transaction as the todo
+// change. Every instance follows the outbox to feed its own broadcast channel,
+// while the durable sinks are drained in id order by a single dispatcher. Ids are taken when an
+// event is inserted rather than when it commits, so the dispatcher only goes past
+// a hole in the ids once every transaction that might still fill it has ended,
+// see `Watermark`. Each event remembers
+// the sinks that accepted it in `delivered_to`, so a failing sink is retried on
+// its own while the others carry on, and it is marked as dispatched once every
+// sink accepted it. Delivery is at-least-once and sinks should use
+// `TodoEvent::event_id` to drop duplicates. Dispatched events are deleted after
+// `outbox.retention_days`.
+
+const BATCH_SIZE: i64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Tells the broadcasters of every instance that events were committed
+const NOTIFY_CHANNEL: &str = "todo_outbox";
+
+// Only one dispatcher drains the outbox when several handlers share a database
+const DISPATCHER_LOCK_KEY: i64 = 0x746f_646f_6f75_7462;
+
+// The ids up to which the outbox is final, no transaction can still add an event below it.
+// Events are recorded after their todo change, so their transaction already has an id
+// when it takes one from the sequence; once every transaction running when a hole was
+// seen has ended, the hole is either filled or left by a rollback for good.
+#[derive(Default)]
+struct Watermark {
+    settled: i64,
+    // The transaction horizon of the snapshot that saw a hole and the highest id it saw
+    hole: Option<(String, i64)>,
+}
+
+impl Watermark {
+    async fn advance(&mut self, conn: &mut PgConnection) -> Result<i64, sqlx::Error> {
+        // Ids without holes are final right away
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM outbox WHERE id > $1 ORDER BY id LIMIT $2")
+            .bind(self.settled)
+            .bind(BATCH_SIZE)
+            .fetch_all(&mut *conn)
+            .await?;
+        for id in ids {
+            if id != self.settled + 1 {
+                break;
+            }
+            self.settled = id;
+        }
+
+        if self.hole.is_none() {
+            let (seen, horizon): (Option<i64>, String) =
+                sqlx::query_as("SELECT max(id), pg_snapshot_xmax(pg_current_snapshot())::TEXT FROM outbox")
+                    .fetch_one(&mut *conn)
+                    .await?;
+            self.hole = seen.filter(|&seen| seen > self.settled).map(|seen| (horizon, seen));
+        }
+
+        if let Some((horizon, seen)) = &self.hole {
+            let ended: bool = sqlx::query_scalar("SELECT pg_snapshot_xmin(pg_current_snapshot()) >= $1::TEXT::xid8")
+                .bind(horizon)
+                .fetch_one(&mut *conn)
+                .await?;
+            if ended {
+                self.settled = self.settled.max(*seen);
+                self.hole = None;
+            }
+        }
+        Ok(self.settled)
+    }
+}
+
+static WAKE_UP: Lazy<Notify> = Lazy::new(Notify::new);
+
+// Set on shutdown once no more events can be recorded
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn publish(&self, event: &TodoEvent) -> Result<(), BackendError>;
+}
+
+pub struct WebhookSink;
+
+pub struct StdoutSink;
+
+pub struct FileSink {
+    // `file:<path>`, telling several files apart in `delivered_to`
+    name: String,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl EventSink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn publish(&self, event: &TodoEvent) -> Result<(), BackendError> {
+        webhooks::dispatch(event).await
+    }
+}
+
+#[async_trait]
+impl EventSink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    async fn publish(&self, event: &TodoEvent) -> Result<(), BackendError> {
+        println!("{}", serde_json::to_string(event)?);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn publish(&self, event: &TodoEvent) -> Result<(), BackendError> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+pub struct Sinks {
+    // Whether every instance feeds its in-process channel used by the WebSocket, SSE, GraphQL and gRPC subscriptions
+    pub broadcast: bool,
+    // Handed each event once, by the single dispatcher
+    pub dispatched: Vec<Box<dyn EventSink>>,
+}
+
+// Parses a comma separated sink list such as `broadcast,webhook,file:/var/log/todo-events.jsonl`
+pub fn parse_sinks(spec: &str) -> Result<Sinks, BackendError> {
+    let mut broadcast = false;
+    let mut dispatched: Vec<Box<dyn EventSink>> = Vec::new();
+    for sink in spec.split(',').map(str::trim).filter(|sink| !sink.is_empty()) {
+        let listed_twice = match sink.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => {
+                dispatched.push(Box::new(FileSink { name: sink.to_string(), path: PathBuf::from(path) }));
+                false
+            }
+            None if sink == "broadcast" => std::mem::replace(&mut broadcast, true),
+            None if sink == "webhook" => {
+                dispatched.push(Box::new(WebhookSink));
+                false
+            }
+            None if sink == "stdout" => {
+                dispatched.push(Box::new(StdoutSink));
+                false
+            }
+            _ => return Err(BackendError::ConfigError(format!("Unknown outbox sink '{}'", sink))),
+        };
+        if listed_twice {
+            return Err(BackendError::ConfigError(format!("Outbox sink '{}' is listed twice", sink)));
+        }
+    }
+
+    // A sink listed twice would count as delivered after the first one
+    for (index, sink) in dispatched.iter().enumerate() {
+        if dispatched[..index].iter().any(|earlier| earlier.name() == sink.name()) {
+            return Err(BackendError::ConfigError(format!("Outbox sink '{}' is listed twice", sink.name())));
+        }
+    }
+    // Otherwise every event would count as delivered without going anywhere
+    if !broadcast && dispatched.is_empty() {
+        return Err(BackendError::ConfigError("outbox.sinks must name at least one sink".to_string()));
+    }
+    Ok(Sinks { broadcast, dispatched })
+}
+
+// Stores an event as part of the caller's transaction; call `wake_up` after committing.
+// The notification reaches the broadcasters once the transaction commits.
+pub async fn record(conn: &mut PgConnection, event: &TodoEvent) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "WITH event AS (INSERT INTO outbox (event_type, todo_id, payload) VALUES ($1, $2, $3) RETURNING id) \
+         SELECT pg_notify($4, id::TEXT) FROM event"
+    )
+    .bind(event.kind.as_str())
+    .bind(event.todo_id)
+    .bind(sqlx::types::Json(event))
+    .bind(NOTIFY_CHANNEL)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub fn wake_up() {
+    WAKE_UP.notify_one();
+}
+
+// Makes the dispatcher return once the outbox is empty
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    WAKE_UP.notify_one();
+}
+
+// Publishes the events recorded by any instance to the subscribers of this one, in id
+// order and starting with the ones committed after it started. Runs until aborted.
+pub async fn run_broadcaster() {
+    let _alive = health::OUTBOX_BROADCASTER.mark_alive();
+    let mut listener = listen().await;
+    let mut watermark = Watermark::default();
+    let mut published = None;
+
+    loop {
+        match broadcast(&mut watermark, &mut published).await {
+            Ok(count) if count as i64 == BATCH_SIZE => {}
+            // Wait for a notification, polling now and then in case one was missed
+            Ok(_) => match listener.as_mut() {
+                Some(notifications) => {
+                    if let Ok(Err(e)) = timeout(POLL_INTERVAL, notifications.recv()).await {
+                        tracing::warn!(error = %e, "Error waiting for outbox notifications, polling instead");
+                        listener = None;
+                    }
+                }
+                None => {
+                    sleep(POLL_INTERVAL).await;
+                    listener = listen().await;
+                }
+            },
+            Err(e) => {
+                tracing::warn!(error = %e, "Error broadcasting outbox events, retrying");
+                sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+// Holds one connection of the pool for as long as it listens
+async fn listen() -> Option<PgListener> {
+    let listener = async {
+        let mut listener = PgListener::connect_with(get_postgres()).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+        Ok::<_, sqlx::Error>(listener)
+    };
+    listener
+        .await
+        .inspect_err(|e| tracing::warn!(error = %e, "Could not listen for outbox notifications, polling instead"))
+        .ok()
+}
+
+// Publishes one batch of settled events after `published` and returns how many
+async fn broadcast(watermark: &mut Watermark, published: &mut Option<i64>) -> Result<usize, BackendError> {
+    let mut conn = metrics::acquire(get_postgres(), "primary").await?;
+    let settled = watermark.advance(&mut conn).await?;
+    let Some(after) = *published else {
+        *published = Some(settled);
+        return Ok(0);
+    };
+
+    let rows: Vec<(i64, sqlx::types::Json<TodoEvent>)> =
+        sqlx::query_as("SELECT id, payload FROM outbox WHERE id > $1 AND id <= $2 ORDER BY id LIMIT $3")
+            .bind(after)
+            .bind(settled)
+            .bind(BATCH_SIZE)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    let count = rows.len();
+    for (event_id, sqlx::types::Json(mut event)) in rows {
+        event.event_id = event_id;
+        events::publish(event);
+        *published = Some(event_id);
+    }
+    Ok(count)
+}
+
+// Dispatches events until `stop`, deleting the ones older than `retention` now and then
+pub async fn run_dispatcher(sinks: Vec<Box<dyn EventSink>>, retention: Option<Duration>) {
+    let _alive = health::OUTBOX_DISPATCHER.mark_alive();
+    let mut next_prune = Instant::now();
+    let mut watermark = Watermark::default();
+
+    loop {
+        let stopping = STOPPING.load(Ordering::SeqCst);
+        match drain(&sinks, &mut watermark).await {
+            Ok(0) if stopping => return,
+            // Wait for new events, polling now and then to pick up ones written by other instances
+            Ok(0) => {
+                if let Some(retention) = retention.filter(|_| Instant::now() >= next_prune) {
+                    next_prune = Instant::now() + PRUNE_INTERVAL;
+                    match prune(retention).await {
+                        Ok(0) => {}
+                        Ok(deleted) => tracing::info!(deleted, "Deleted dispatched outbox events past their retention"),
+                        Err(e) => tracing::warn!(error = %e, "Error deleting old outbox events"),
+                    }
+                }
+                let _ = timeout(POLL_INTERVAL, WAKE_UP.notified()).await;
+            }
+            Ok(_) => {}
+            // Undispatched events stay in the outbox for the next start
+            Err(e) if stopping => {
+                tracing::warn!(error = %e, "Error dispatching outbox events, leaving them for the next start");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Error dispatching outbox events, retrying");
+                sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+// Hands every sink one batch of the events it hasn't accepted yet and returns how
+// many events were handed over. A sink stops at its first failing event so later
+// events never overtake it; that error is returned after the other sinks ran.
+async fn drain(sinks: &[Box<dyn EventSink>], watermark: &mut Watermark) -> Result<usize, BackendError> {
+    // A session lock rather than a transaction, so none stays open while the sinks run
+    let mut conn = metrics::acquire(get_postgres(), "primary").await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+        .bind(DISPATCHER_LOCK_KEY)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !locked {
+        return Ok(0);
+    }
+
+    let result = match watermark.advance(&mut conn).await {
+        Ok(settled) => drain_locked(&mut conn, sinks, settled).await,
+        Err(e) => Err(e.into()),
+    };
+
+    let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(DISPATCHER_LOCK_KEY)
+        .execute(&mut *conn)
+        .await;
+    if let Err(e) = unlocked {
+        // Closing the connection releases the lock as well
+        tracing::warn!(error = %e, "Could not release the outbox lock, closing its connection");
+        drop(conn.detach());
+    }
+    result
+}
+
+async fn drain_locked(conn: &mut PgConnection, sinks: &[Box<dyn EventSink>], settled: i64) -> Result<usize, BackendError> {
+    let mut handed_over = 0;
+    let mut failure = None;
+
+    for sink in sinks {
+        let rows: Vec<(i64, sqlx::types::Json<TodoEvent>)> = sqlx::query_as(
+            "SELECT id, payload FROM outbox WHERE dispatched_at IS NULL AND NOT ($1 = ANY(delivered_to)) AND id <= $2 ORDER BY id LIMIT $3"
+        )
+        .bind(sink.name())
+        .bind(settled)
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut accepted = Vec::with_capacity(rows.len());
+        for (event_id, sqlx::types::Json(mut event)) in rows {
+            event.event_id = event_id;
+            if let Err(e) = sink.publish(&event).await {
+                tracing::warn!(sink = sink.name(), event_id, error = %e, "Outbox sink failed");
+                failure.get_or_insert(e);
+                break;
+            }
+            accepted.push(event_id);
+        }
+
+        if !accepted.is_empty() {
+            sqlx::query("UPDATE outbox SET delivered_to = array_append(delivered_to, $1) WHERE id = ANY($2)")
+                .bind(sink.name())
+                .bind(&accepted)
+                .execute(&mut *conn)
+                .await?;
+            handed_over += accepted.len();
+        }
+    }
+
+    let names: Vec<&str> = sinks.iter().map(|sink| sink.name()).collect();
+    sqlx::query("UPDATE outbox SET dispatched_at = now() WHERE dispatched_at IS NULL AND delivered_to @> $1::TEXT[] AND id <= $2")
+        .bind(&names)
+        .bind(settled)
+        .execute(&mut *conn)
+        .await?;
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(handed_over),
+    }
+}
+
+// Deletes dispatched events older than `retention`. The latest event stays, as
+// `todo_store::last_modified` reads its time.
+async fn prune(retention: Duration) -> Result<u64, BackendError> {
+    // Nothing is that old
+    let Some(cutoff) = chrono::Duration::from_std(retention).ok().and_then(|retention| Utc::now().checked_sub_signed(retention)) else {
+        return Ok(0);
+    };
+    let result = sqlx::query("DELETE FROM outbox WHERE dispatched_at < $1 AND id < (SELECT max(id) FROM outbox)")
+        .bind(cutoff)
+        .execute(get_postgres())
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::with_database;
+
+    #[test]
+    fn file_sinks_are_told_apart_by_path() {
+        let sinks = parse_sinks("broadcast, file:/tmp/a.jsonl,file:/tmp/b.jsonl").unwrap();
+        let names: Vec<&str> = sinks.dispatched.iter().map(|sink| sink.name()).collect();
+        assert!(sinks.broadcast);
+        assert_eq!(names, ["file:/tmp/a.jsonl", "file:/tmp/b.jsonl"]);
+    }
+
+    #[test]
+    fn rejects_sinks_listed_twice() {
+        assert!(parse_sinks("webhook,broadcast,webhook").is_err());
+        assert!(parse_sinks("broadcast,webhook,broadcast").is_err());
+        assert!(parse_sinks("file:/tmp/a.jsonl,file:/tmp/a.jsonl").is_err());
+        assert!(parse_sinks("kafka").is_err());
+    }
+
+    #[test]
+    fn rejects_an_empty_sink_list() {
+        assert!(parse_sinks("").is_err());
+        assert!(parse_sinks(" , ").is_err());
+        assert!(parse_sinks("stdout").is_ok());
+    }
+
+    // An event committed after a later one must not be overtaken
+    #[test]
+    fn waits_for_holes_to_close() {
+        with_database(async {
+            const RECORD: &str = "INSERT INTO outbox (event_type, todo_id, payload) VALUES ('deleted', -1, '{}') RETURNING id";
+
+            let mut open = get_postgres().begin().await.unwrap();
+            // Take a transaction id first, as a todo change would
+            sqlx::query("SELECT pg_current_xact_id()").execute(&mut *open).await.unwrap();
+            let first: i64 = sqlx::query_scalar(RECORD).fetch_one(&mut *open).await.unwrap();
+            let mut conn = get_postgres().acquire().await.unwrap();
+            let second: i64 = sqlx::query_scalar(RECORD).fetch_one(&mut *conn).await.unwrap();
+
+            let mut watermark = Watermark::default();
+            assert!(watermark.advance(&mut conn).await.unwrap() < first);
+
+            open.commit().await.unwrap();
+            // Transactions of other tests may hold the watermark back for a moment
+            for _ in 0..50 {
+                if watermark.advance(&mut conn).await.unwrap() >= second {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            assert!(watermark.settled >= second);
+
+            sqlx::query("DELETE FROM outbox WHERE id = ANY($1)").bind(vec![first, second]).execute(&mut *conn).await.unwrap();
+        });
+    }
+}
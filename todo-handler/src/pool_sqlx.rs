@@ -1,30 +1,45 @@
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
 use std::time::Duration;
+
+use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
 use tokio::time::sleep;
 
-pub async fn establish_connection(database_url: &str) -> Result<Pool<Postgres>, sqlx::Error> {
-    let max_retries = 5;
-    let retry_delay = Duration::from_secs(3);
+use crate::config::DatabaseConfig;
+
+// Reads fall back to the primary rather than waiting long for a replica connection
+const REPLICA_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn establish_connection(config: &DatabaseConfig) -> Result<Pool<Postgres>, sqlx::Error> {
+    let max_retries = config.connect_retries;
+    let retry_delay = config.retry_delay();
 
     for retry_count in 0..=max_retries {
         match PgPoolOptions::new()
-            .max_connections(15)
-            .min_connections(2)
-            .idle_timeout(Some(Duration::from_secs(60)))
-            .acquire_timeout(Duration::from_secs(30))
-            .connect(database_url)
+            .max_connections(config.max_connections)
+            .min_connections(config.min_connections)
+            .idle_timeout(Some(config.idle_timeout()))
+            .acquire_timeout(config.acquire_timeout())
+            .connect(config.url())
             .await
         {
             Ok(pool) => return Ok(pool),
             Err(e) if retry_count < max_retries => {
-                println!("Error connecting to database: {:?}, retrying... (Attempt {} of {})", e, retry_count + 1, max_retries);
+                tracing::warn!(error = %e, attempt = retry_count + 1, max_retries, "Error connecting to database, retrying");
                 sleep(retry_delay).await;
             },
             Err(e) => {
-                println!("Error connecting to database after {} attempts: {:?}", max_retries, e);
+                tracing::error!(error = %e, max_retries, "Error connecting to database, giving up");
                 return Err(e);
             }
         }
     }
     unreachable!()
-}
\ No newline at end of file
+}
+
+// Connects on first use, so a replica that is down doesn't keep the handler from starting
+pub fn replica_pool(config: &DatabaseConfig, url: &str) -> Result<Pool<Postgres>, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .idle_timeout(Some(config.idle_timeout()))
+        .acquire_timeout(REPLICA_ACQUIRE_TIMEOUT)
+        .connect_lazy(url)
+}
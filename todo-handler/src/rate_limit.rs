@@ -0,0 +1,256 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use salvo::http::header::HeaderValue;
+use salvo::http::Method;
+use salvo::prelude::*;
+
+use crate::backend_error::StoreError;
+use crate::config::{RateLimitConfig, RateLimitKey};
+use crate::metrics;
+use crate::users::{self, Credentials};
+
+// Token buckets per client, with separate limits for reads and writes. A bucket
+// holds up to the limit and refills at the limit per period, so bursts pass as
+// long as the average stays below it. Buckets live in memory, so every instance
+// counts on its own.
+
+// Probes and scrapes are never limited
+const EXEMPT_PATHS: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+// The least recently used buckets are dropped past this size
+const MAX_BUCKETS: usize = 10_000;
+
+// API key lookups, unknown keys included, are reused for a while so limiting
+// costs no query per request. Keys are cached by their hash.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+const MAX_CACHED_KEYS: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Class {
+    Read,
+    Write,
+    // Looking up an API key that isn't cached, counted against the address
+    KeyLookup,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Client {
+    User(i32),
+    ApiKey(i32),
+    Ip(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// What the `RateLimit-*` headers report after taking a token
+struct Decision {
+    limit: u32,
+    remaining: u32,
+    // Seconds until the bucket is full again
+    reset: u64,
+    // Set when the request was refused
+    retry_after: Option<u64>,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    trusted_proxy_hops: usize,
+    buckets: Mutex<LruCache<(Client, Class), Bucket>>,
+    keys: Mutex<LruCache<String, (Option<Credentials>, Instant)>>,
+}
+
+impl Class {
+    fn of(method: &Method) -> Self {
+        match method.as_str() {
+            "GET" | "HEAD" | "OPTIONS" | "PROPFIND" | "REPORT" => Class::Read,
+            _ => Class::Write,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Class::Read => "read",
+            Class::Write => "write",
+            Class::KeyLookup => "key_lookup",
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, trusted_proxy_hops: usize) -> Self {
+        RateLimiter {
+            config,
+            trusted_proxy_hops,
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_BUCKETS).unwrap())),
+            keys: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_CACHED_KEYS).unwrap())),
+        }
+    }
+
+    fn limit(&self, class: Class) -> u32 {
+        match class {
+            Class::Read => self.config.read_requests,
+            Class::Write | Class::KeyLookup => self.config.write_requests,
+        }
+    }
+
+    // Tokens per second
+    fn rate(&self, class: Class) -> f64 {
+        f64::from(self.limit(class)) / self.config.period().as_secs_f64()
+    }
+
+    fn take(&self, client: Client, class: Class) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.get_or_insert_mut((client, class), || Bucket { tokens: f64::from(self.limit(class)), updated: now });
+        bucket.tokens = self.refill(bucket, class, now);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let limit = self.limit(class);
+        let rate = self.rate(class);
+        Decision {
+            limit,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((f64::from(limit) - bucket.tokens) / rate).ceil() as u64,
+            retry_after: (!allowed).then(|| ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64),
+        }
+    }
+
+    // Tokens `bucket` holds at `now`
+    fn refill(&self, bucket: &Bucket, class: Class, now: Instant) -> f64 {
+        let refilled = bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate(class);
+        refilled.min(f64::from(self.limit(class)))
+    }
+
+    // The key's owner or the key itself when it is valid, the client's address otherwise
+    async fn client(&self, req: &Request) -> Client {
+        let ip = client_ip(req, self.trusted_proxy_hops);
+        if self.config.per != RateLimitKey::Ip {
+            if let Some(credentials) = self.credentials(req, ip).await {
+                return match self.config.per {
+                    RateLimitKey::ApiKey => Client::ApiKey(credentials.key_id),
+                    _ => Client::User(credentials.user_id),
+                };
+            }
+        }
+        Client::Ip(ip)
+    }
+
+    async fn credentials(&self, req: &Request, ip: IpAddr) -> Option<Credentials> {
+        let key = users::bearer_token(req.headers())?;
+        let hash = users::hash_key(key);
+
+        if let Some((credentials, checked)) = self.keys.lock().unwrap().get(&hash) {
+            if checked.elapsed() < KEY_CACHE_TTL {
+                return credentials.clone();
+            }
+        }
+
+        // Made-up keys would otherwise each cost a query
+        if self.take(Client::Ip(ip), Class::KeyLookup).retry_after.is_some() {
+            return None;
+        }
+
+        // Without the database the request is counted against its address
+        let credentials = match users::authenticate(key).await {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                tracing::warn!(error = %e, "Could not look up the API key of a request, limiting it by address");
+                return None;
+            }
+        };
+
+        self.keys.lock().unwrap().put(hash, (credentials.clone(), Instant::now()));
+        credentials
+    }
+}
+
+// The address of the client. Behind `trusted_proxy_hops` proxies each appending
+// to `X-Forwarded-For`, that is the entry the outermost proxy added; anything to
+// its left was sent by the client and can't be trusted.
+pub fn client_ip(req: &Request, trusted_proxy_hops: usize) -> IpAddr {
+    // Proxies may add their own header line instead of appending to the last one
+    let forwarded_for: Vec<&str> = req.headers().get_all("X-Forwarded-For").iter().filter_map(|value| value.to_str().ok()).collect();
+    let forwarded = (trusted_proxy_hops > 0 && !forwarded_for.is_empty())
+        .then(|| forwarded_client(&forwarded_for.join(","), trusted_proxy_hops))
+        .flatten();
+
+    forwarded
+        .or_else(|| req.remote_addr().clone().into_std().map(|addr| addr.ip()))
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+// The `hops`th address from the right, or the leftmost one when there are fewer
+fn forwarded_client(forwarded_for: &str, hops: usize) -> Option<IpAddr> {
+    let addresses: Vec<&str> = forwarded_for.split(',').map(str::trim).collect();
+    let index = addresses.len().saturating_sub(hops);
+    addresses[index].parse().ok()
+}
+
+#[handler]
+impl RateLimiter {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        if EXEMPT_PATHS.contains(&req.uri().path()) {
+            return;
+        }
+
+        let class = Class::of(req.method());
+        let client = self.client(req).await;
+        let decision = self.take(client, class);
+
+        let headers = res.headers_mut();
+        headers.insert("RateLimit-Limit", decision.limit.into());
+        headers.insert("RateLimit-Remaining", decision.remaining.into());
+        headers.insert("RateLimit-Reset", decision.reset.into());
+        if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", decision.limit, self.config.period_secs)) {
+            headers.insert("RateLimit-Policy", policy);
+        }
+
+        if let Some(retry_after) = decision.retry_after {
+            metrics::record_rate_limited(class.as_str());
+            StoreError::RateLimited(retry_after).write(req, depot, res).await;
+            ctrl.skip_rest();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_is_read_from_the_right() {
+        let forwarded_for = "1.1.1.1, 10.0.0.1, 10.0.0.2";
+
+        assert_eq!(forwarded_client(forwarded_for, 1), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(forwarded_client(forwarded_for, 2), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(forwarded_client(forwarded_for, 3), Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(forwarded_client(forwarded_for, 5), Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(forwarded_client("spoofed, 10.0.0.2", 1), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(forwarded_client("10.0.0.1, spoofed", 1), None);
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let limiter = RateLimiter::new(RateLimitConfig::default(), 0);
+
+        for n in 0..MAX_BUCKETS as u32 + 10 {
+            limiter.take(Client::Ip(IpAddr::V4(Ipv4Addr::from(n))), Class::Write);
+        }
+
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS);
+    }
+}
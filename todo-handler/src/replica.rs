@@ -0,0 +1,273 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use lru::LruCache;
+use once_cell::sync::OnceCell;
+use salvo::prelude::*;
+use sqlx::{PgConnection, PgPool};
+
+use crate::config::DatabaseConfig;
+use crate::{circuit_breaker, get_postgres, metrics, rate_limit, users};
+
+// Sends the todo store's reads to a read replica while writes go to the
+// primary. Reads go to the primary instead for `read_your_writes_secs` after
+// the caller's last committed change, so callers see
+// their changes despite replication lag, and for `replica_retry_secs` after the
+// replica failed. Callers are told apart by the hash of their API key or by
+// their address; writes are only known to the instance that handled them.
+
+// Past this many callers, those that wrote least recently are dropped
+const MAX_TRACKED_CALLERS: usize = 10_000;
+
+struct Replica {
+    pool: PgPool,
+    read_your_writes: Duration,
+    retry: Duration,
+    // Until when reads skip the replica after it failed
+    down_until: Mutex<Option<Instant>>,
+    // When each caller last changed something
+    writes: Mutex<LruCache<String, Instant>>,
+    // When anyone last changed something through this instance
+    last_write: Mutex<Option<Instant>>,
+}
+
+// The HTTP request being handled
+struct Caller {
+    key: String,
+}
+
+static REPLICA: OnceCell<Replica> = OnceCell::new();
+
+tokio::task_local! {
+    static CALLER: Caller;
+}
+
+// Routes reads to `pool` from now on; without it every query goes to the primary
+pub fn init(pool: PgPool, config: &DatabaseConfig) {
+    let _ = REPLICA.set(Replica::new(pool, config.read_your_writes(), config.replica_retry()));
+}
+
+// Runs a read on the replica when the caller may use it, and on the primary otherwise
+// or when the replica can't be reached
+pub async fn read<T, F>(run: F) -> Result<T, sqlx::Error>
+where
+    F: for<'c> Fn(&'c mut PgConnection) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+{
+    match REPLICA.get() {
+        Some(replica) => replica.read(run).await,
+        None => read_primary(run).await,
+    }
+}
+
+async fn read_primary<T, F>(run: F) -> Result<T, sqlx::Error>
+where
+    F: for<'c> Fn(&'c mut PgConnection) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+{
+    let mut conn = metrics::acquire(get_postgres(), "primary").await?;
+    let value = run(&mut conn).await?;
+    metrics::record_read("primary");
+    Ok(value)
+}
+
+// The pool `read` would try first, for streamed reads that can't switch pools once started
+pub fn read_pool() -> &'static PgPool {
+    match REPLICA.get().filter(|replica| replica.usable()) {
+        Some(replica) => {
+            metrics::record_read("replica");
+            &replica.pool
+        }
+        None => {
+            metrics::record_read("primary");
+            get_postgres()
+        }
+    }
+}
+
+// Called after committing a change, so the caller's next reads see it
+pub fn record_write() {
+    if let Some(replica) = REPLICA.get() {
+        replica.record_write();
+    }
+}
+
+// Whether the current caller is within its window, so must not be answered from
+// caches the replica may have filled before it had the caller's change
+pub fn reads_own_writes() -> bool {
+    REPLICA.get().is_some_and(Replica::caller_wrote_recently)
+}
+
+// How long the replica may still miss the latest change made through this instance
+pub fn lag_remaining() -> Option<Duration> {
+    REPLICA.get()?.lag_remaining()
+}
+
+impl Replica {
+    fn new(pool: PgPool, read_your_writes: Duration, retry: Duration) -> Self {
+        Replica {
+            pool,
+            read_your_writes,
+            retry,
+            down_until: Mutex::new(None),
+            writes: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_CALLERS).unwrap())),
+            last_write: Mutex::new(None),
+        }
+    }
+
+    async fn read<T, F>(&self, run: F) -> Result<T, sqlx::Error>
+    where
+        F: for<'c> Fn(&'c mut PgConnection) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+    {
+        if self.usable() {
+            let result = match metrics::acquire(&self.pool, "replica").await {
+                Ok(mut conn) => run(&mut conn).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => {
+                    metrics::record_read("replica");
+                    return Ok(value);
+                }
+                Err(e) if circuit_breaker::is_unavailable(&e) => {
+                    tracing::warn!(error = %e, retry_secs = self.retry.as_secs(), "Read replica unavailable, reading from the primary");
+                    *self.down_until.lock().unwrap() = Some(Instant::now() + self.retry);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        read_primary(run).await
+    }
+
+    fn record_write(&self) {
+        let now = Instant::now();
+        *self.last_write.lock().unwrap() = Some(now);
+
+        let _ = CALLER.try_with(|caller| {
+            self.writes.lock().unwrap().put(caller.key.clone(), now);
+        });
+    }
+
+    fn lag_remaining(&self) -> Option<Duration> {
+        let written = (*self.last_write.lock().unwrap())?;
+        self.read_your_writes.checked_sub(written.elapsed()).filter(|remaining| !remaining.is_zero())
+    }
+
+    fn usable(&self) -> bool {
+        if self.down_until.lock().unwrap().is_some_and(|until| Instant::now() < until) {
+            return false;
+        }
+        !self.caller_wrote_recently()
+    }
+
+    fn caller_wrote_recently(&self) -> bool {
+        let wrote_recently = |caller: &Caller| {
+            self.writes.lock().unwrap().peek(&caller.key).is_some_and(|written| written.elapsed() < self.read_your_writes)
+        };
+        CALLER.try_with(wrote_recently).unwrap_or(false)
+    }
+}
+
+// Remembers who sent each request, so the changes it commits count for that caller
+pub struct TrackWrites {
+    trusted_proxy_hops: usize,
+}
+
+impl TrackWrites {
+    pub fn new(trusted_proxy_hops: usize) -> Self {
+        TrackWrites { trusted_proxy_hops }
+    }
+}
+
+#[handler]
+impl TrackWrites {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let key = match users::bearer_token(req.headers()) {
+            Some(token) => format!("key:{}", users::hash_key(token)),
+            None => format!("addr:{}", rate_limit::client_ip(req, self.trusted_proxy_hops)),
+        };
+        CALLER.scope(Caller { key }, ctrl.call_next(req, depot, res)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::test_support::with_database;
+
+    // Nothing listens there, so every connection attempt fails until the pool gives up
+    const CLOSED_PORT_URL: &str = "postgres://todo@127.0.0.1:1/todo";
+
+    fn replica(read_your_writes: Duration) -> Replica {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy(CLOSED_PORT_URL)
+            .unwrap();
+        Replica::new(pool, read_your_writes, Duration::from_secs(60))
+    }
+
+    fn caller(key: &str) -> Caller {
+        Caller { key: key.to_string() }
+    }
+
+    #[tokio::test]
+    async fn only_the_writing_caller_skips_the_replica() {
+        let replica = replica(Duration::from_secs(60));
+
+        CALLER
+            .scope(caller("key:writer"), async {
+                assert!(!replica.caller_wrote_recently());
+                replica.record_write();
+                assert!(replica.caller_wrote_recently());
+                assert!(!replica.usable());
+            })
+            .await;
+        CALLER
+            .scope(caller("key:reader"), async {
+                assert!(!replica.caller_wrote_recently());
+                assert!(replica.usable());
+            })
+            .await;
+        // Outside of a request nobody wrote
+        assert!(!replica.caller_wrote_recently());
+    }
+
+    #[tokio::test]
+    async fn writes_count_for_the_window_only() {
+        let replica = replica(Duration::ZERO);
+
+        CALLER
+            .scope(caller("addr:127.0.0.1"), async {
+                replica.record_write();
+                assert!(!replica.caller_wrote_recently());
+            })
+            .await;
+        assert_eq!(replica.lag_remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn lag_remains_after_any_write() {
+        let replica = replica(Duration::from_secs(60));
+        assert_eq!(replica.lag_remaining(), None);
+
+        // Writes outside of a request count too
+        replica.record_write();
+        let remaining = replica.lag_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60), "{:?}", remaining);
+    }
+
+    #[test]
+    fn reads_from_the_primary_while_the_replica_is_down() {
+        with_database(async {
+            let replica = replica(Duration::from_secs(60));
+
+            let one: i32 = replica.read(|conn| sqlx::query_scalar("SELECT 1").fetch_one(conn).boxed()).await.unwrap();
+            assert_eq!(one, 1);
+            // And skips it until `retry` passed
+            assert!(!replica.usable());
+        });
+    }
+}
@@ -0,0 +1,187 @@
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::Todo;
+
+// Request bodies and response envelopes of the HTTP API, shared by the
+// handlers and the OpenAPI document. Body fields are optional so missing
+// ones can be reported individually instead of as "Invalid JSON payload".
+
+/// Body of `POST /todos`.
+#[derive(Deserialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "TodoPayload"))]
+pub struct TodoPayload {
+    /// Must not be empty and must be unique.
+    #[salvo(schema(required = true, nullable = false))]
+    pub name: Option<String>,
+    #[salvo(schema(required = true, nullable = false))]
+    pub description: Option<String>,
+}
+
+/// Body of `PUT /todos/todo`; replaces every field.
+#[derive(Deserialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "UpdateTodoPayload"))]
+pub struct UpdateTodoPayload {
+    /// Must not be empty.
+    #[salvo(schema(required = true, nullable = false))]
+    pub name: Option<String>,
+    #[salvo(schema(required = true, nullable = false))]
+    pub description: Option<String>,
+    /// `"true"` or `"false"`, sent as a string.
+    #[salvo(schema(required = true, nullable = false, example = "true"))]
+    pub done: Option<String>,
+}
+
+/// Body of `PATCH /v2/todos/{id}`; fields left out keep their value.
+#[derive(Deserialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "PatchTodoPayload"))]
+pub struct PatchTodoPayload {
+    /// Must not be empty.
+    #[salvo(schema(nullable = false))]
+    pub name: Option<String>,
+    #[salvo(schema(nullable = false))]
+    pub description: Option<String>,
+    #[salvo(schema(nullable = false, example = true))]
+    pub done: Option<bool>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "TodoResponse"))]
+pub struct TodoResponse {
+    pub success: bool,
+    pub todo: Todo,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "TodoListResponse"))]
+pub struct TodoListResponse {
+    pub success: bool,
+    pub todos: Vec<Todo>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "MessageResponse"))]
+pub struct MessageResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+/// Returned with every 4xx and 5xx status.
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "ErrorResponse"))]
+pub struct ErrorResponse {
+    /// Always `false`.
+    pub success: bool,
+    pub error: String,
+}
+
+impl TodoResponse {
+    pub fn new(todo: Todo) -> Self {
+        TodoResponse { success: true, todo }
+    }
+}
+
+impl MessageResponse {
+    pub fn new(message: String) -> Self {
+        MessageResponse { success: true, message }
+    }
+}
+
+impl ErrorResponse {
+    pub fn new(error: String) -> Self {
+        ErrorResponse { success: false, error }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[salvo(schema(symbol = "ImportStatus"))]
+pub enum ImportStatus {
+    Created,
+    Updated,
+    /// The todo already matches the item, so nothing was written.
+    Unchanged,
+    /// A todo with the same name already exists.
+    Skipped,
+    Failed,
+}
+
+/// Outcome of importing one item of an uploaded file.
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "ImportItem"))]
+pub struct ImportItem {
+    /// Position of the item in the file, starting at 0.
+    pub index: usize,
+    /// UID of the item, for formats that have one.
+    pub uid: Option<String>,
+    pub status: ImportStatus,
+    /// The created or updated todo. For dry runs, the todo that would be created, with id 0.
+    pub todo: Option<Todo>,
+    /// Why the item was skipped or failed.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "ImportResponse"))]
+pub struct ImportResponse {
+    pub success: bool,
+    /// Nothing was written; `items` shows what an import would do.
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub items: Vec<ImportItem>,
+}
+
+impl ImportResponse {
+    pub fn new(items: Vec<ImportItem>, dry_run: bool) -> Self {
+        let count = |status| items.iter().filter(|item| item.status == status).count();
+        ImportResponse {
+            success: true,
+            dry_run,
+            created: count(ImportStatus::Created),
+            updated: count(ImportStatus::Updated),
+            unchanged: count(ImportStatus::Unchanged),
+            skipped: count(ImportStatus::Skipped),
+            failed: count(ImportStatus::Failed),
+            items,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "RestoreResponse"))]
+pub struct RestoreResponse {
+    pub success: bool,
+    /// `replace` or `merge`.
+    pub mode: String,
+    pub todos_restored: usize,
+    /// Todos left out of a merge because their name is already taken.
+    pub todos_skipped: usize,
+    pub webhooks_restored: usize,
+    /// Only restored when replacing.
+    pub events_restored: usize,
+    /// Users are merged by name, together with their API keys.
+    pub users_restored: usize,
+}
+
+/// Result of one readiness check.
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "HealthCheck"))]
+pub struct HealthCheck {
+    /// `database`, `migrations`, `outbox_dispatcher`, `outbox_broadcaster`, `grpc_server` or `shutdown`.
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "HealthResponse"))]
+pub struct HealthResponse {
+    /// `ok` or `unavailable`.
+    pub status: &'static str,
+    /// Empty for `/healthz`.
+    pub checks: Vec<HealthCheck>,
+}
@@ -0,0 +1,340 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use http_body_util::{LengthLimitError, Limited};
+use salvo::cors::{AllowOrigin, Cors, CorsHandler};
+use salvo::http::body::{Body, Frame, SizeHint};
+use salvo::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, ORIGIN};
+use salvo::http::{mime, Method, ReqBody, ResBody};
+use salvo::hyper::body::Bytes;
+use salvo::prelude::*;
+use salvo::BoxedError;
+
+use crate::backend_error::StoreError;
+use crate::config::{CorsConfig, LimitsConfig, SecurityHeadersConfig};
+
+// Hoops every request passes before reaching a route: CORS for the browser
+// frontend, security headers on every response and limits on request bodies.
+
+// Swagger UI and the GraphiQL playground are HTML pages with scripts
+const CSP_EXEMPT_PATHS: [&str; 2] = ["/docs", "/graphql"];
+
+// Takes `limits.max_restore_bytes` instead of `limits.max_body_bytes`
+const RESTORE_PATH: &str = "/admin/restore";
+
+// Answers CORS preflights and adds CORS headers to requests from the allowed
+// origins. Requests without `Origin` don't come from a browser and pass
+// untouched, which keeps CalDAV's own `OPTIONS` working.
+pub struct CrossOrigin {
+    cors: CorsHandler,
+}
+
+impl CrossOrigin {
+    // `None` when no origin is allowed; values are checked by `Config::validate`
+    pub fn new(config: &CorsConfig) -> Option<Self> {
+        if config.allowed_origins.is_empty() {
+            return None;
+        }
+
+        let origins = if config.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(config.allowed_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+        };
+        let methods: Vec<Method> = config.allowed_methods.iter().filter_map(|method| Method::from_str(method).ok()).collect();
+        let headers = |names: &[String]| -> Vec<HeaderName> { names.iter().filter_map(|name| HeaderName::from_str(name).ok()).collect() };
+
+        let cors = Cors::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers(&config.allowed_headers))
+            .expose_headers(headers(&config.exposed_headers))
+            .allow_credentials(config.allow_credentials)
+            .max_age(Duration::from_secs(config.max_age_secs))
+            .into_handler();
+        Some(CrossOrigin { cors })
+    }
+}
+
+#[handler]
+impl CrossOrigin {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        if req.headers().contains_key(ORIGIN) {
+            self.cors.handle(req, depot, res, ctrl).await;
+        }
+    }
+}
+
+// `nosniff`, no framing, no referrer, a restrictive CSP and, over TLS, HSTS
+pub struct SecurityHeaders {
+    content_security_policy: HeaderValue,
+    strict_transport_security: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig, tls: bool) -> Self {
+        let strict_transport_security = (tls && config.hsts_max_age_secs > 0)
+            .then(|| HeaderValue::from_str(&format!("max-age={}", config.hsts_max_age_secs)).ok())
+            .flatten();
+
+        SecurityHeaders {
+            content_security_policy: HeaderValue::from_str(&config.content_security_policy)
+                .unwrap_or_else(|_| HeaderValue::from_static("default-src 'none'")),
+            strict_transport_security,
+        }
+    }
+}
+
+#[handler]
+impl SecurityHeaders {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        ctrl.call_next(req, depot, res).await;
+
+        let path = req.uri().path();
+        let headers = res.headers_mut();
+        headers.insert("X-Content-Type-Options", HeaderValue::from_static("nosniff"));
+        headers.insert("X-Frame-Options", HeaderValue::from_static("DENY"));
+        headers.insert("Referrer-Policy", HeaderValue::from_static("no-referrer"));
+        if !CSP_EXEMPT_PATHS.iter().any(|exempt| path.starts_with(exempt)) {
+            headers.insert("Content-Security-Policy", self.content_security_policy.clone());
+        }
+        if let Some(hsts) = &self.strict_transport_security {
+            headers.insert("Strict-Transport-Security", hsts.clone());
+        }
+    }
+}
+
+// Refuses bodies above `max_body_bytes`, or `max_restore_bytes` for restores, and
+// JSON bodies nested deeper than `max_json_depth`. Streamed bodies are checked
+// while the handler reads them, which stops at the first byte or bracket too many.
+pub struct BodyLimits {
+    config: LimitsConfig,
+}
+
+// Where a JSON body is in its arrays, objects and strings, fed one chunk at a time
+struct DepthScanner {
+    max_depth: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+type BoxedBody = Pin<Box<dyn Body<Data = Bytes, Error = BoxedError> + Send + Sync>>;
+
+// The limits a handler ran into while reading a streamed body
+#[derive(Default)]
+struct Exceeded {
+    size: AtomicBool,
+    depth: AtomicBool,
+}
+
+// Passes a streamed body through `Limited`, and JSON through a `DepthScanner`,
+// noting which limit made the read fail
+struct CheckedBody {
+    inner: BoxedBody,
+    scanner: Option<DepthScanner>,
+    exceeded: Arc<Exceeded>,
+}
+
+impl BodyLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        BodyLimits { config }
+    }
+
+    fn too_deep(&self) -> StoreError {
+        StoreError::BadRequest(format!("JSON body is nested deeper than {} levels", self.config.max_json_depth))
+    }
+
+    fn max_bytes(&self, req: &Request) -> usize {
+        match req.uri().path() {
+            RESTORE_PATH => self.config.max_restore_bytes,
+            _ => self.config.max_body_bytes,
+        }
+    }
+
+    // Returns the limits the handler will have run into while reading a streamed body
+    fn check(&self, req: &mut Request) -> Result<Option<Arc<Exceeded>>, StoreError> {
+        let max = self.max_bytes(req);
+        let declared = req.header::<usize>(CONTENT_LENGTH);
+        if declared.is_some_and(|length| length > max) {
+            return Err(StoreError::PayloadTooLarge(max));
+        }
+
+        let is_json = req.content_type().is_some_and(|content_type| {
+            content_type.subtype() == mime::JSON || content_type.suffix() == Some(mime::JSON) || content_type.subtype() == "x-ndjson"
+        });
+
+        // Bodies without a length, such as chunked ones, stop being read at the limit
+        let (inner, fusewire): (BoxedBody, _) = match req.take_body() {
+            ReqBody::Hyper { inner, fusewire } => (Box::pin(Limited::new(inner, max)), fusewire),
+            ReqBody::Boxed { inner, fusewire } => (Box::pin(Limited::new(inner, max)), fusewire),
+            // Already in memory, such as in tests
+            ReqBody::Once(bytes) => {
+                if is_json && DepthScanner::new(self.config.max_json_depth).exceeded_by(&bytes) {
+                    return Err(self.too_deep());
+                }
+                req.replace_body(ReqBody::Once(bytes));
+                return Ok(None);
+            }
+            body => {
+                req.replace_body(body);
+                return Ok(None);
+            }
+        };
+
+        let exceeded = Arc::new(Exceeded::default());
+        let scanner = is_json.then(|| DepthScanner::new(self.config.max_json_depth));
+        let inner = Box::pin(CheckedBody { inner, scanner, exceeded: exceeded.clone() });
+        req.replace_body(ReqBody::Boxed { inner, fusewire });
+        Ok(Some(exceeded))
+    }
+}
+
+#[handler]
+impl BodyLimits {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let exceeded = match self.check(req) {
+            Ok(exceeded) => exceeded,
+            Err(e) => {
+                e.write(req, depot, res).await;
+                ctrl.skip_rest();
+                return;
+            }
+        };
+
+        ctrl.call_next(req, depot, res).await;
+
+        // The handler only saw a failed read, so the response says why
+        let Some(exceeded) = exceeded else { return };
+        if exceeded.size.load(Ordering::Relaxed) {
+            res.body(ResBody::None);
+            StoreError::PayloadTooLarge(self.max_bytes(req)).write(req, depot, res).await;
+        } else if exceeded.depth.load(Ordering::Relaxed) {
+            res.body(ResBody::None);
+            self.too_deep().write(req, depot, res).await;
+        }
+    }
+}
+
+impl DepthScanner {
+    fn new(max_depth: usize) -> Self {
+        DepthScanner { max_depth, depth: 0, in_string: false, escaped: false }
+    }
+
+    // Whether arrays and objects nest deeper than `max_depth` once `json` was read, without parsing it
+    fn exceeded_by(&mut self, json: &[u8]) -> bool {
+        for &byte in json {
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    if self.depth > self.max_depth {
+                        return true;
+                    }
+                }
+                b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+impl Body for CheckedBody {
+    type Data = Bytes;
+    type Error = BoxedError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxedError>>> {
+        let this = &mut *self;
+        let frame = ready!(this.inner.as_mut().poll_frame(cx));
+
+        if let Some(Err(e)) = &frame {
+            if e.is::<LengthLimitError>() {
+                this.exceeded.size.store(true, Ordering::Relaxed);
+            }
+        }
+        if let (Some(Ok(frame)), Some(scanner)) = (&frame, &mut this.scanner) {
+            if frame.data_ref().is_some_and(|data| scanner.exceeded_by(data)) {
+                this.exceeded.depth.store(true, Ordering::Relaxed);
+                return Poll::Ready(Some(Err("JSON body is nested too deeply".into())));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    fn exceeds_depth(json: &str, max_depth: usize) -> bool {
+        DepthScanner::new(max_depth).exceeded_by(json.as_bytes())
+    }
+
+    #[test]
+    fn counts_arrays_and_objects() {
+        assert!(!exceeds_depth(r#"{"a": [1, {"b": []}]}"#, 4));
+        assert!(exceeds_depth(r#"{"a": [1, {"b": [[]]}]}"#, 4));
+        assert!(!exceeds_depth(r#"[[]] [[]] [[]]"#, 2));
+        assert!(!exceeds_depth("", 1));
+    }
+
+    #[test]
+    fn ignores_brackets_in_strings() {
+        assert!(!exceeds_depth(r#"{"name": "[[[{{{"}"#, 1));
+        assert!(!exceeds_depth(r#"{"name": "quote \" [[[ "}"#, 1));
+        assert!(!exceeds_depth(r#"{"name": "backslash \\", "b": 1}"#, 1));
+        assert!(exceeds_depth(r#"{"name": "backslash \\", "b": [1]}"#, 1));
+        assert!(exceeds_depth(r#"["\u005b", [[]]]"#, 2));
+    }
+
+    #[test]
+    fn keeps_its_place_across_chunks() {
+        let mut scanner = DepthScanner::new(2);
+        assert!(!scanner.exceeded_by(br#"{"a": "[\"#));
+        assert!(!scanner.exceeded_by(br#"" [", "b": ["#));
+        assert!(scanner.exceeded_by(b"["));
+    }
+
+    #[tokio::test]
+    async fn notes_which_limit_a_streamed_body_exceeds() {
+        async fn read(json: &'static str, max_bytes: usize) -> (bool, bool, bool) {
+            let exceeded = Arc::new(Exceeded::default());
+            let body = CheckedBody {
+                inner: Box::pin(Limited::new(Full::new(Bytes::from(json)), max_bytes)),
+                scanner: Some(DepthScanner::new(2)),
+                exceeded: exceeded.clone(),
+            };
+            let failed = body.collect().await.is_err();
+            (failed, exceeded.size.load(Ordering::Relaxed), exceeded.depth.load(Ordering::Relaxed))
+        }
+
+        assert_eq!(read("[[1]]", 10).await, (false, false, false));
+        assert_eq!(read("[[1, 2, 3]]", 5).await, (true, true, false));
+        assert_eq!(read("[[[1]]]", 10).await, (true, false, true));
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::time::Instant;
+use tokio::sync::watch;
+
+// Process-wide shutdown state. Servers stop accepting connections once it is
+// triggered, long-lived streams end and background workers finish their
+// current work before the database pool is closed. All of them share one
+// grace period, counted from the moment shutdown was triggered.
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+static TRIGGERED_AT: OnceCell<Instant> = OnceCell::new();
+
+pub fn trigger() {
+    TRIGGERED_AT.get_or_init(Instant::now);
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+// Resolves once shutdown has been triggered, immediately if it already was
+pub async fn wait() {
+    let mut receiver = SHUTDOWN.subscribe();
+    let _ = receiver.wait_for(|&shutting_down| shutting_down).await;
+}
+
+// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM
+pub async fn signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+// Resolves `grace` after shutdown was triggered, for work that must not hold it up longer
+pub async fn deadline(grace: Duration) {
+    wait().await;
+    tokio::time::sleep(remaining(grace)).await;
+}
+
+// What is left of `grace` since shutdown was triggered, all of it before
+pub fn remaining(grace: Duration) -> Duration {
+    TRIGGERED_AT.get().map_or(grace, |triggered_at| grace.saturating_sub(triggered_at.elapsed()))
+}
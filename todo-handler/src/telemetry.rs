@@ -0,0 +1,189 @@
+use std::time::Instant;
+
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use rand::RngCore;
+use salvo::http::header::{HeaderMap, HeaderName, HeaderValue};
+use salvo::prelude::*;
+use tracing::{field, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::api_docs::RouteTemplates;
+use crate::backend_error::BackendError;
+use crate::config::{LogFormat, TelemetryConfig};
+use crate::metrics::UNMATCHED_ROUTE;
+
+// Logs go to stderr, as text or JSON lines, and spans are exported to an OTLP
+// collector when one is configured. Every HTTP request and gRPC call runs in a
+// span carrying its request id, which clients may pass in `X-Request-Id`.
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer ids, or ones with spaces or control characters, are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+const REQUEST_ID_BYTES: usize = 16;
+
+// Keeps the span exporter, if any, so the last spans can be flushed on exit
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    // Exports the spans still buffered
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!(error = %e, "Could not export the remaining spans");
+            }
+        }
+    }
+}
+
+// Installs the global subscriber; `log` records, such as SQLx's, are forwarded to it
+pub fn init(config: &TelemetryConfig) -> Result<Telemetry, BackendError> {
+    let filter = EnvFilter::try_new(&config.log_filter)
+        .map_err(|e| BackendError::ConfigError(format!("Invalid telemetry.log_filter: {}", e)))?;
+
+    let logs = match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().with_writer(std::io::stderr).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr).boxed(),
+    };
+
+    let provider = config
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| otlp_provider(endpoint, &config.service_name))
+        .transpose()?;
+    let spans = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))));
+
+    // W3C `traceparent` headers make our spans children of the caller's
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(logs)
+        .with(spans)
+        .try_init()
+        .map_err(|e| BackendError::ConfigError(format!("Could not install the log subscriber: {}", e)))?;
+
+    Ok(Telemetry { provider })
+}
+
+fn otlp_provider(endpoint: &str, service_name: &str) -> Result<TracerProvider, BackendError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| BackendError::ConfigError(format!("Could not set up the OTLP exporter: {}", e)))?;
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name.to_string())]))
+        .build();
+    global::set_tracer_provider(provider.clone());
+
+    Ok(provider)
+}
+
+// The client's `X-Request-Id` when it is usable, a new random id otherwise
+pub fn request_id(headers: &HeaderMap) -> String {
+    let client_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|byte| byte.is_ascii_graphic()));
+
+    match client_id {
+        Some(id) => id.to_string(),
+        None => {
+            let mut bytes = [0u8; REQUEST_ID_BYTES];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            hex::encode(bytes)
+        }
+    }
+}
+
+// Continues the trace of a caller that sent a `traceparent` header
+pub fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+// Runs every request in a span with its method, route, request id, todo id,
+// status and duration, logs its outcome and answers with its `X-Request-Id`
+pub struct RequestTracing {
+    routes: RouteTemplates,
+}
+
+impl RequestTracing {
+    pub fn new(routes: RouteTemplates) -> Self {
+        RequestTracing { routes }
+    }
+}
+
+#[handler]
+impl RequestTracing {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let started = Instant::now();
+        let request_id = request_id(req.headers());
+        let route = self.routes.find(req.uri().path());
+
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            route = route.as_deref().unwrap_or(UNMATCHED_ROUTE),
+            request_id = %request_id,
+            todo_id = field::Empty,
+            status = field::Empty,
+            duration_ms = field::Empty,
+        );
+        continue_trace(&span, req.headers());
+
+        ctrl.call_next(req, depot, res).instrument(span.clone()).await;
+
+        // `/todos/todo?id=` on v1, `/todos/{id}` on v2
+        if route.as_deref().is_some_and(|route| route.starts_with("/todos") || route.starts_with("/v2/todos")) {
+            if let Some(todo_id) = req.param::<i32>("id").or_else(|| req.query::<i32>("id")) {
+                span.record("todo_id", todo_id);
+            }
+        }
+
+        // Salvo only sets the 404 of unmatched requests after the hoops ran
+        let default_status = if route.is_none() { StatusCode::NOT_FOUND } else { StatusCode::OK };
+        let status = res.status_code.unwrap_or(default_status);
+        span.record("status", status.as_u16());
+        span.record("duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+
+        let _entered = span.enter();
+        if status.is_server_error() {
+            tracing::error!("Request failed");
+        } else {
+            tracing::info!("Request finished");
+        }
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+    }
+}
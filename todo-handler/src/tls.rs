@@ -0,0 +1,181 @@
+use std::future::ready;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use futures_util::{stream, Stream, StreamExt};
+use salvo::conn::rustls::{Keycert, RustlsConfig, ServerConfig};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::backend_error::BackendError;
+use crate::config::{ClientAuth, TlsConfig};
+
+// TLS for the HTTP listener. The certificate, key and client CA files are
+// checked every `reload_interval_secs` and reloaded once they stopped changing
+// for one interval, so a certificate isn't paired with the old key while both
+// are being replaced. New connections use the new files, while a broken update
+// keeps the previous certificate in place. Clients negotiate HTTP/2 or HTTP/1.1
+// through ALPN.
+
+const PEM_CERTIFICATE: &[u8] = b"-----BEGIN CERTIFICATE-----";
+
+// The configuration for the current files, followed by one for every change.
+// Salvo only polls the stream when accepting connections, so the files are
+// watched by a task of their own.
+pub fn config_stream(config: TlsConfig) -> Result<impl Stream<Item = RustlsConfig> + Send + 'static, BackendError> {
+    let initial = load(&config)?;
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(watch(config, sender));
+
+    let reloads = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|rustls| (rustls, receiver))
+    });
+    Ok(stream::once(ready(initial)).chain(reloads))
+}
+
+// Sends a configuration whenever the files changed and then stayed the same for one interval
+async fn watch(config: TlsConfig, sender: mpsc::Sender<RustlsConfig>) {
+    let mut loaded = modified_times(&config);
+    let mut seen = loaded.clone();
+
+    loop {
+        sleep(config.reload_interval()).await;
+
+        let current = modified_times(&config);
+        if current != seen {
+            seen = current;
+            continue;
+        }
+        if current == loaded {
+            continue;
+        }
+        // Not retried until the files change again
+        loaded = current;
+
+        match load(&config) {
+            Ok(rustls) => {
+                tracing::info!("Reloaded the TLS certificate");
+                if sender.send(rustls).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Could not reload the TLS certificate, keeping the previous one"),
+        }
+    }
+}
+
+// Reads the files and checks they make a working server configuration
+fn load(config: &TlsConfig) -> Result<RustlsConfig, BackendError> {
+    let cert = read(config.cert_path.as_deref())?;
+    let key = read(config.key_path.as_deref())?;
+    // An empty chain would otherwise be accepted and fail every handshake
+    if !cert.windows(PEM_CERTIFICATE.len()).any(|window| window == PEM_CERTIFICATE) {
+        return Err(BackendError::ConfigError("The TLS certificate file holds no PEM certificate".to_string()));
+    }
+
+    let mut rustls = RustlsConfig::new(Keycert::new().cert(cert).key(key));
+    rustls = match config.client_auth {
+        ClientAuth::None => rustls,
+        ClientAuth::Optional => rustls.client_auth_optional(read(config.client_ca_path.as_deref())?),
+        ClientAuth::Required => rustls.client_auth_required(read(config.client_ca_path.as_deref())?),
+    };
+
+    let _: ServerConfig = rustls
+        .clone()
+        .try_into()
+        .map_err(|e| BackendError::ConfigError(format!("Invalid TLS certificate, key or client CA: {}", e)))?;
+    Ok(rustls)
+}
+
+// Checked by `Config::validate`
+fn read(path: Option<&Path>) -> Result<Vec<u8>, BackendError> {
+    let path = path.unwrap_or(Path::new(""));
+    std::fs::read(path).map_err(|e| BackendError::ConfigError(format!("Could not read {}: {}", path.display(), e)))
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [&config.cert_path, &config.key_path, &config.client_ca_path]
+        .into_iter()
+        .map(|path: &Option<PathBuf>| {
+            path.as_ref()
+                .and_then(|path| std::fs::metadata(path).ok())
+                .and_then(|metadata| metadata.modified().ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rcgen::generate_simple_self_signed;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+
+    // Writes a new self-signed certificate for `localhost` and returns it in DER
+    fn write_certificate(config: &TlsConfig) -> Vec<u8> {
+        let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(config.cert_path.as_ref().unwrap(), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(config.key_path.as_ref().unwrap(), cert.serialize_private_key_pem()).unwrap();
+        cert.serialize_der().unwrap()
+    }
+
+    // Whether a client trusting only `trusted` completes a handshake and exchanges data with `server`
+    async fn handshake(server: RustlsConfig, trusted: &[u8]) -> bool {
+        let server: ServerConfig = server.try_into().unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(trusted.to_vec())).unwrap();
+        let client = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+
+        let (client_io, server_io) = duplex(16 * 1024);
+        let accept = async {
+            let mut stream = TlsAcceptor::from(Arc::new(server)).accept(server_io).await.ok()?;
+            stream.write_all(b"hello").await.ok()?;
+            stream.flush().await.ok()
+        };
+        let connect = async {
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = TlsConnector::from(Arc::new(client)).connect(name, client_io).await.ok()?;
+            let mut greeting = [0u8; 5];
+            stream.read_exact(&mut greeting).await.ok()?;
+            Some(greeting)
+        };
+
+        let (_, greeting) = tokio::join!(accept, connect);
+        greeting == Some(*b"hello")
+    }
+
+    #[tokio::test]
+    async fn serves_the_certificate_and_reloads_it() {
+        let dir = std::env::temp_dir().join(format!("todo-tls-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_path: Some(dir.join("cert.pem")),
+            key_path: Some(dir.join("key.pem")),
+            reload_interval_secs: 1,
+            ..TlsConfig::default()
+        };
+
+        let first = write_certificate(&config);
+        let mut configs = Box::pin(config_stream(config.clone()).unwrap());
+        let initial = configs.next().await.unwrap();
+        assert!(handshake(initial.clone(), &first).await);
+
+        // A broken update keeps the previous certificate
+        std::fs::write(config.key_path.as_ref().unwrap(), "not a key").unwrap();
+        assert!(timeout(Duration::from_secs(3), configs.next()).await.is_err());
+
+        let second = write_certificate(&config);
+        let reloaded = timeout(Duration::from_secs(10), configs.next()).await.unwrap().unwrap();
+        assert!(!handshake(initial, &second).await);
+        assert!(handshake(reloaded, &second).await);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use futures_util::FutureExt;
+use sqlx::{FromRow, PgConnection};
+
+use crate::backend_error::StoreError;
+use crate::events::{TodoEvent, TodoEventKind};
+use crate::{cache, circuit_breaker, get_postgres, metrics, outbox, replica, Todo};
+
+// Queries shared by the HTTP handlers and the WebSocket endpoint.
+// Every mutation records its `TodoEvent` in the outbox within the same transaction.
+// Todos and the list are read through the cache, which mutations clear after committing.
+// Reads use the read replica when one is configured, see `replica`.
+// Everything runs behind the circuit breaker, which also repeats reads after transient errors.
+// Each operation runs in its own span, which holds SQLx's events for its statements.
+
+#[tracing::instrument(skip_all)]
+pub async fn list_todos() -> Result<Vec<Todo>, StoreError> {
+    cache::read_through("list_todos", cache::TODO_LIST_KEY, async {
+        let _timer = metrics::query_timer("list_todos");
+        let todos = circuit_breaker::read("list_todos", || {
+            replica::read(|conn| sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos ORDER BY id").fetch_all(conn).boxed())
+        })
+        .await?;
+
+        Ok::<_, StoreError>(todos)
+    })
+    .await
+}
+
+// When the last todo was changed, taken from its outbox event; `None` before any change
+#[tracing::instrument(skip_all)]
+pub async fn last_modified() -> Result<Option<DateTime<Utc>>, StoreError> {
+    let _timer = metrics::query_timer("last_modified");
+    let last_modified = circuit_breaker::read("last_modified", || {
+        replica::read(|conn| sqlx::query_scalar("SELECT created_at FROM outbox ORDER BY id DESC LIMIT 1").fetch_optional(conn).boxed())
+    })
+    .await?;
+
+    Ok(last_modified)
+}
+
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn fetch_todo(todo_id: i32) -> Result<Option<Todo>, StoreError> {
+    cache::read_through("fetch_todo", &cache::todo_key(todo_id), async {
+        let _timer = metrics::query_timer("fetch_todo");
+        let todo = circuit_breaker::read("fetch_todo", || {
+            replica::read(|conn| {
+                sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos WHERE id = $1")
+                    .bind(todo_id)
+                    .fetch_optional(conn)
+                    .boxed()
+            })
+        })
+        .await?;
+
+        Ok::<_, StoreError>(todo)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn fetch_todo_by_name(name: &str) -> Result<Option<Todo>, StoreError> {
+    let _timer = metrics::query_timer("fetch_todo_by_name");
+    let todo = circuit_breaker::read("fetch_todo_by_name", || {
+        replica::read(|conn| {
+            sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos WHERE name = $1")
+                .bind(name.to_string())
+                .fetch_optional(conn)
+                .boxed()
+        })
+    })
+    .await?;
+
+    Ok(todo)
+}
+
+// The headings todos are listed under in Markdown checklists, for those listed under one
+#[tracing::instrument(skip_all, fields(todos = todo_ids.len()))]
+pub async fn fetch_projects(todo_ids: &[i32]) -> Result<HashMap<i32, String>, StoreError> {
+    let _timer = metrics::query_timer("fetch_projects");
+    let projects: Vec<(i32, String)> = circuit_breaker::read("fetch_projects", || {
+        replica::read(|conn| {
+            sqlx::query_as("SELECT todo_id, project FROM todo_outline WHERE todo_id = ANY($1) AND project IS NOT NULL")
+                .bind(todo_ids.to_vec())
+                .fetch_all(conn)
+                .boxed()
+        })
+    })
+    .await?;
+
+    Ok(projects.into_iter().collect())
+}
+
+#[derive(FromRow)]
+struct Subtask {
+    parent_id: i32,
+    #[sqlx(flatten)]
+    todo: Todo,
+}
+
+// The todos nested under each parent in Markdown checklists, in their order; parents without any are left out
+#[tracing::instrument(skip_all, fields(parents = parent_ids.len()))]
+pub async fn list_subtasks(parent_ids: &[i32]) -> Result<HashMap<i32, Vec<Todo>>, StoreError> {
+    let _timer = metrics::query_timer("list_subtasks");
+    let rows = circuit_breaker::read("list_subtasks", || {
+        replica::read(|conn| {
+            sqlx::query_as::<_, Subtask>(
+                "SELECT o.parent_id, t.id, t.name, t.description, t.done FROM todos t JOIN todo_outline o ON o.todo_id = t.id \
+                 WHERE o.parent_id = ANY($1) ORDER BY o.position, t.id"
+            )
+            .bind(parent_ids.to_vec())
+            .fetch_all(conn)
+            .boxed()
+        })
+    })
+    .await?;
+
+    let mut subtasks: HashMap<i32, Vec<Todo>> = HashMap::new();
+    for row in rows {
+        subtasks.entry(row.parent_id).or_default().push(row.todo);
+    }
+    Ok(subtasks)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create_todo(name: &str, description: &str) -> Result<Todo, StoreError> {
+    let _timer = metrics::query_timer("create_todo");
+    circuit_breaker::write(async {
+        let mut tx = begin().await?;
+        let todo = create_in(&mut tx, name, description).await?;
+        commit(tx, &[todo.id]).await?;
+        Ok::<_, StoreError>(todo)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn update_todo(todo_id: i32, name: &str, description: &str, done: bool) -> Result<Todo, StoreError> {
+    let _timer = metrics::query_timer("update_todo");
+    circuit_breaker::write(async {
+        let mut tx = begin().await?;
+        let todo = update_in(&mut tx, todo_id, name, description, done).await?;
+        commit(tx, &[todo_id]).await?;
+        Ok::<_, StoreError>(todo)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn mark_done(todo_id: i32) -> Result<Todo, StoreError> {
+    let _timer = metrics::query_timer("mark_done");
+    circuit_breaker::write(async {
+        let mut tx = begin().await?;
+        let todo = mark_done_in(&mut tx, todo_id).await?;
+        commit(tx, &[todo_id]).await?;
+        Ok::<_, StoreError>(todo)
+    })
+    .await
+}
+
+// Changes the fields given and keeps the others; only marking a todo as done is recorded as such
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn patch_todo(todo_id: i32, name: Option<&str>, description: Option<&str>, done: Option<bool>) -> Result<Todo, StoreError> {
+    let _timer = metrics::query_timer("patch_todo");
+    circuit_breaker::write(async {
+        let mut tx = begin().await?;
+        let todo = if name.is_none() && description.is_none() && done == Some(true) {
+            mark_done_in(&mut tx, todo_id).await?
+        } else {
+            // Locked, so no other change slips in between reading and writing the todo
+            let current = sqlx::query_as::<_, Todo>("SELECT id, name, description, done FROM todos WHERE id = $1 FOR UPDATE")
+                .bind(todo_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or(StoreError::NotFound(todo_id))?;
+            let name = name.unwrap_or(&current.name);
+            let description = description.unwrap_or(&current.description);
+            update_in(&mut tx, todo_id, name, description, done.unwrap_or(current.done)).await?
+        };
+        commit(tx, &[todo_id]).await?;
+        Ok::<_, StoreError>(todo)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn delete_todo(todo_id: i32) -> Result<(), StoreError> {
+    let _timer = metrics::query_timer("delete_todo");
+    circuit_breaker::write(async {
+        let mut tx = begin().await?;
+        ensure_exists(&mut tx, todo_id).await?;
+
+        let result = sqlx::query("DELETE FROM todos WHERE id = $1")
+            .bind(todo_id)
+            .execute(&mut *tx)
+            .await?;
+
+        // Rare but possible case
+        if result.rows_affected() != 1 {
+            return Err(StoreError::Unexpected("Todo was not deleted due to an unknown error"));
+        }
+
+        outbox::record(&mut tx, &TodoEvent::deleted(todo_id)).await?;
+        commit(tx, &[todo_id]).await?;
+        Ok::<_, StoreError>(())
+    })
+    .await
+}
+
+// Starts a transaction for several changes made with the `*_in` functions, such
+// as a todo together with its calendar properties; finish it with `commit`
+pub async fn begin() -> Result<sqlx::Transaction<'static, sqlx::Postgres>, StoreError> {
+    let conn = metrics::acquire(get_postgres(), "primary").await?;
+    Ok(sqlx::Transaction::begin(conn).await?)
+}
+
+// Creates a todo within the caller's transaction
+pub async fn create_in(conn: &mut PgConnection, name: &str, description: &str) -> Result<Todo, StoreError> {
+    validate_name(name)?;
+
+    // Check if a todo with the same name already exists
+    if sqlx::query("SELECT 1 FROM todos WHERE name = $1")
+        .bind(name)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some()
+    {
+        return Err(StoreError::Conflict);
+    }
+
+    // Insert the new todo and return it
+    let todo = sqlx::query_as::<_, Todo>(
+        "INSERT INTO todos (name, description) VALUES ($1, $2) RETURNING id, name, description, done"
+    )
+    .bind(name)
+    .bind(description)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    outbox::record(conn, &TodoEvent::new(TodoEventKind::Created, &todo)).await?;
+    Ok(todo)
+}
+
+// Replaces every field of a todo within the caller's transaction
+pub async fn update_in(conn: &mut PgConnection, todo_id: i32, name: &str, description: &str, done: bool) -> Result<Todo, StoreError> {
+    validate_name(name)?;
+    ensure_exists(conn, todo_id).await?;
+
+    // Update the todo in the database and fetch it
+    let todo = sqlx::query_as::<_, Todo>(
+        "UPDATE todos SET name = $1, description = $2, done = $3, updated_at = now() WHERE id = $4 RETURNING id, name, description, done"
+    )
+    .bind(name)
+    .bind(description)
+    .bind(done)
+    .bind(todo_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(StoreError::Unexpected("Todo was not updated due to an unknown error"))?;
+
+    outbox::record(conn, &TodoEvent::new(TodoEventKind::Updated, &todo)).await?;
+    Ok(todo)
+}
+
+// Marks a todo as done within the caller's transaction
+pub async fn mark_done_in(conn: &mut PgConnection, todo_id: i32) -> Result<Todo, StoreError> {
+    ensure_exists(conn, todo_id).await?;
+
+    // Mark the todo as done and fetch it
+    let todo = sqlx::query_as::<_, Todo>(
+        "UPDATE todos SET done = true, updated_at = now() WHERE id = $1 RETURNING id, name, description, done"
+    )
+    .bind(todo_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(StoreError::Unexpected("Todo was marked as done but could not be retrieved"))?;
+
+    outbox::record(conn, &TodoEvent::new(TodoEventKind::MarkedDone, &todo)).await?;
+    Ok(todo)
+}
+
+// Commits changes of `todo_ids` and drops their cached entries
+pub async fn commit(tx: sqlx::Transaction<'_, sqlx::Postgres>, todo_ids: &[i32]) -> Result<(), StoreError> {
+    tx.commit().await?;
+    replica::record_write();
+    for &todo_id in todo_ids {
+        cache::invalidate_todo(todo_id).await;
+    }
+    outbox::wake_up();
+    Ok(())
+}
+
+// Whether `create_todo` would fail with `StoreError::Conflict`
+#[tracing::instrument(skip_all)]
+pub async fn name_taken(name: &str) -> Result<bool, StoreError> {
+    let _timer = metrics::query_timer("name_taken");
+    let taken = circuit_breaker::read("name_taken", || {
+        sqlx::query("SELECT 1 FROM todos WHERE name = $1").bind(name).fetch_optional(get_postgres())
+    })
+    .await?
+    .is_some();
+
+    Ok(taken)
+}
+
+// Checked on the primary, ahead of parsing an update
+#[tracing::instrument(skip_all, fields(todo_id = todo_id))]
+pub async fn todo_exists(todo_id: i32) -> Result<bool, StoreError> {
+    let _timer = metrics::query_timer("todo_exists");
+    let exists = circuit_breaker::read("todo_exists", || {
+        sqlx::query("SELECT 1 FROM todos WHERE id = $1").bind(todo_id).fetch_optional(get_postgres())
+    })
+    .await?
+    .is_some();
+
+    Ok(exists)
+}
+
+pub fn validate_name(name: &str) -> Result<(), StoreError> {
+    if name.trim().is_empty() {
+        return Err(StoreError::BadRequest("Missing or empty 'name' field".to_string()));
+    }
+    Ok(())
+}
+
+async fn ensure_exists(conn: &mut PgConnection, todo_id: i32) -> Result<(), StoreError> {
+    match sqlx::query("SELECT 1 FROM todos WHERE id = $1")
+        .bind(todo_id)
+        .fetch_optional(conn)
+        .await?
+    {
+        Some(_) => Ok(()),
+        None => Err(StoreError::NotFound(todo_id)),
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use salvo::http::header::{HeaderMap, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use sqlx::prelude::FromRow;
+
+use crate::backend_error::StoreError;
+use crate::{circuit_breaker, get_postgres};
+
+// Operators and their API keys. Keys are random, shown once when issued and
+// stored as a SHA-256 hash. They grant access to the admin API and give their
+// owner a rate limit of their own.
+
+const KEY_PREFIX: &str = "todo_";
+const KEY_BYTES: usize = 32;
+
+// Characters of a key kept in clear text so operators can tell keys apart
+const VISIBLE_PREFIX_LEN: usize = KEY_PREFIX.len() + 8;
+
+#[derive(Debug, FromRow)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+}
+
+// The key a request authenticated with and its owner
+#[derive(Debug, Clone, FromRow)]
+pub struct Credentials {
+    pub key_id: i32,
+    pub user_id: i32,
+}
+
+#[derive(Debug, FromRow)]
+pub struct ApiKey {
+    pub id: i32,
+    pub prefix: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create_user(name: &str) -> Result<User, StoreError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(StoreError::BadRequest("User name must not be empty".to_string()));
+    }
+
+    circuit_breaker::write(async {
+        sqlx::query_as("INSERT INTO users (name) VALUES ($1) ON CONFLICT (name) DO NOTHING RETURNING id, name")
+            .bind(name)
+            .fetch_optional(get_postgres())
+            .await?
+            .ok_or_else(|| StoreError::BadRequest(format!("User '{}' already exists", name)))
+    })
+    .await
+}
+
+// Returns the stored key and the key itself, which can't be recovered later
+#[tracing::instrument(skip_all)]
+pub async fn issue_api_key(user_name: &str, valid_for: Option<Duration>) -> Result<(ApiKey, String), StoreError> {
+    let user_id: i32 = circuit_breaker::read("find_user", || {
+        sqlx::query_scalar("SELECT id FROM users WHERE name = $1").bind(user_name).fetch_optional(get_postgres())
+    })
+    .await?
+    .ok_or_else(|| StoreError::BadRequest(format!("Unknown user '{}'", user_name)))?;
+
+    let mut bytes = [0u8; KEY_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(bytes));
+
+    let api_key = circuit_breaker::write(async {
+        let api_key = sqlx::query_as(
+            "INSERT INTO api_keys (user_id, prefix, key_hash, expires_at) VALUES ($1, $2, $3, $4)
+            RETURNING id, prefix, expires_at"
+        )
+        .bind(user_id)
+        .bind(&key[..VISIBLE_PREFIX_LEN])
+        .bind(hash_key(&key))
+        .bind(valid_for.map(|valid_for| Utc::now() + valid_for))
+        .fetch_one(get_postgres())
+        .await?;
+        Ok(api_key)
+    })
+    .await?;
+
+    Ok((api_key, key))
+}
+
+// An unexpired key and the user owning it
+#[tracing::instrument(skip_all)]
+pub async fn authenticate(key: &str) -> Result<Option<Credentials>, StoreError> {
+    if !key.starts_with(KEY_PREFIX) {
+        return Ok(None);
+    }
+
+    let key_hash = hash_key(key);
+    let credentials = circuit_breaker::read("authenticate", || {
+        sqlx::query_as(
+            "SELECT k.id AS key_id, k.user_id FROM api_keys k
+            WHERE k.key_hash = $1 AND (k.expires_at IS NULL OR k.expires_at > now())"
+        )
+        .bind(&key_hash)
+        .fetch_optional(get_postgres())
+    })
+    .await?;
+
+    Ok(credentials)
+}
+
+// The token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Keys are stored and compared by this
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
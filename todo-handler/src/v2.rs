@@ -0,0 +1,199 @@
+use salvo::http::header::LOCATION;
+use salvo::prelude::*;
+
+use crate::backend_error::StoreError;
+use crate::schemas::{PatchTodoPayload, TodoListResponse, TodoPayload, TodoResponse, UpdateTodoPayload};
+use crate::{conditional, create_from_payload, parse_payload, todo_store, update_from_payload};
+
+// Resource style routes: `/v2/todos` and `/v2/todos/{id}`. Request bodies,
+// validation and storage are shared with the v1 routes in main.rs.
+
+pub fn router() -> Router {
+    Router::with_path("v2/todos")
+        .get(list_todos)
+        .post(create_todo)
+        .push(
+            Router::with_path("<id>")
+                .get(get_todo)
+                .put(replace_todo)
+                .patch(patch_todo)
+                .delete(delete_todo)
+        )
+}
+
+/// List every todo; `If-None-Match` or `If-Modified-Since` return 304 while it is unchanged.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(200, 304, 429, 500),
+    responses(
+        (status_code = 200, description = "All todos", body = TodoListResponse),
+        (status_code = 304, description = "The list is unchanged")
+    )
+)]
+async fn list_todos(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    conditional::render_todo_list(req, res).await
+}
+
+/// Create a todo; its URL is returned in the `Location` header.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(201, 400, 409, 413, 429, 500),
+    request_body = TodoPayload,
+    responses((status_code = 201, description = "The created todo", body = TodoResponse))
+)]
+async fn create_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let todo = create_from_payload(req).await?;
+
+    res.status_code(StatusCode::CREATED);
+    if let Ok(location) = format!("/v2/todos/{}", todo.id).parse() {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
+}
+
+/// Fetch one todo.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(200, 400, 404, 429, 500),
+    parameters(("id" = i32, Path, description = "Todo id")),
+    responses((status_code = 200, description = "The todo", body = TodoResponse))
+)]
+async fn get_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let todo_id = path_id(req)?;
+
+    let todo = todo_store::fetch_todo(todo_id).await?.ok_or(StoreError::NotFound(todo_id))?;
+
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
+}
+
+/// Replace every field of a todo.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(200, 400, 404, 413, 429, 500),
+    parameters(("id" = i32, Path, description = "Todo id")),
+    request_body = UpdateTodoPayload,
+    responses((status_code = 200, description = "The updated todo", body = TodoResponse))
+)]
+async fn replace_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let todo_id = path_id(req)?;
+
+    let todo = update_from_payload(todo_id, req).await?;
+
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
+}
+
+/// Change some fields of a todo; `{"done": true}` marks it as done.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(200, 400, 404, 413, 429, 500),
+    parameters(("id" = i32, Path, description = "Todo id")),
+    request_body = PatchTodoPayload,
+    responses((status_code = 200, description = "The updated todo", body = TodoResponse))
+)]
+async fn patch_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let todo_id = path_id(req)?;
+
+    let payload = parse_payload::<PatchTodoPayload>(req).await?;
+    if payload.name.is_none() && payload.description.is_none() && payload.done.is_none() {
+        return Err(StoreError::BadRequest("Nothing to change, send 'name', 'description' or 'done'".to_string()));
+    }
+    if let Some(name) = &payload.name {
+        todo_store::validate_name(name)?;
+    }
+
+    let todo = todo_store::patch_todo(todo_id, payload.name.as_deref(), payload.description.as_deref(), payload.done).await?;
+
+    res.render(Json(TodoResponse::new(todo)));
+    Ok(())
+}
+
+/// Delete a todo.
+#[endpoint(
+    tags("todos v2"),
+    status_codes(204, 400, 404, 429, 500),
+    parameters(("id" = i32, Path, description = "Todo id")),
+    responses((status_code = 204, description = "The todo was deleted"))
+)]
+async fn delete_todo(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let todo_id = path_id(req)?;
+
+    todo_store::delete_todo(todo_id).await?;
+
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+fn path_id(req: &Request) -> Result<i32, StoreError> {
+    req.param::<i32>("id")
+        .ok_or_else(|| StoreError::BadRequest("Invalid 'id' path parameter".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{unique, with_database};
+
+    fn url(path: &str) -> String {
+        format!("http://localhost{}", path)
+    }
+
+    #[test]
+    fn todos_are_resources() {
+        with_database(async {
+            let service = Service::new(crate::api_router(None));
+            let name = unique("v2-test");
+
+            let created = TestClient::post(url("/v2/todos")).json(&json!({ "name": name, "description": "From v2" })).send(&service).await;
+            assert_eq!(created.status_code, Some(StatusCode::CREATED));
+            let location = created.headers()[LOCATION].to_str().unwrap().to_string();
+
+            let mut fetched = TestClient::get(url(&location)).send(&service).await;
+            assert_eq!(fetched.status_code, Some(StatusCode::OK));
+            let fetched: Value = fetched.take_json().await.unwrap();
+            assert_eq!(location, format!("/v2/todos/{}", fetched["todo"]["id"]));
+
+            // Fields left out of a PATCH keep their value
+            let mut patched = TestClient::patch(url(&location)).json(&json!({ "description": "Changed" })).send(&service).await;
+            assert_eq!(patched.status_code, Some(StatusCode::OK));
+            let patched: Value = patched.take_json().await.unwrap();
+            assert_eq!(patched["todo"]["name"], name.as_str());
+            assert_eq!(patched["todo"]["description"], "Changed");
+            assert_eq!(patched["todo"]["done"], false);
+
+            let mut done = TestClient::patch(url(&location)).json(&json!({ "done": true })).send(&service).await;
+            let done: Value = done.take_json().await.unwrap();
+            assert_eq!(done["todo"]["description"], "Changed");
+            assert_eq!(done["todo"]["done"], true);
+
+            let empty = TestClient::patch(url(&location)).json(&json!({})).send(&service).await;
+            assert_eq!(empty.status_code, Some(StatusCode::BAD_REQUEST));
+
+            let deleted = TestClient::delete(url(&location)).send(&service).await;
+            assert_eq!(deleted.status_code, Some(StatusCode::NO_CONTENT));
+            let missing = TestClient::get(url(&location)).send(&service).await;
+            assert_eq!(missing.status_code, Some(StatusCode::NOT_FOUND));
+        });
+    }
+
+    #[test]
+    fn only_v1_is_deprecated() {
+        with_database(async {
+            let service = Service::new(crate::api_router(None));
+
+            let v1 = TestClient::get(url("/todos")).send(&service).await;
+            assert_eq!(v1.status_code, Some(StatusCode::OK));
+            assert_eq!(v1.headers()["Deprecation"], "true");
+            assert!(v1.headers()["Link"].to_str().unwrap().contains("</v2/todos>"));
+
+            let v2 = TestClient::get(url("/v2/todos")).send(&service).await;
+            assert_eq!(v2.status_code, Some(StatusCode::OK));
+            assert!(!v2.headers().contains_key("Deprecation"));
+        });
+    }
+}
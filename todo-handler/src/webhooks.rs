@@ -0,0 +1,549 @@
+use std::future::Future;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use salvo::oapi::ToSchema;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::prelude::FromRow;
+use tokio::time::{sleep, timeout};
+use tokio_util::task::TaskTracker;
+
+use crate::backend_error::{BackendError, StoreError};
+use crate::events::{TodoEvent, TodoEventKind};
+use crate::{circuit_breaker, get_postgres, shutdown};
+use crate::schemas::{ErrorResponse, MessageResponse};
+
+const MAX_ATTEMPTS: i32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Matches every event type
+const ANY_EVENT: &str = "*";
+
+// Running deliveries, so shutdown can wait for attempts in progress
+static DELIVERIES: Lazy<TaskTracker> = Lazy::new(TaskTracker::new);
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Failed to build the webhook HTTP client")
+});
+
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+#[salvo(schema(symbol = "WebhookSubscription"))]
+struct WebhookSubscription {
+    id: i32,
+    url: String,
+    event_types: Vec<String>,
+    #[serde(skip_serializing)]
+    secret: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+#[salvo(schema(symbol = "WebhookDelivery"))]
+struct WebhookDelivery {
+    id: i64,
+    subscription_id: i32,
+    event_type: String,
+    #[salvo(schema(value_type = Object))]
+    payload: Value,
+    status: String,
+    attempts: i32,
+    response_code: Option<i32>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// Body of `POST /webhooks`.
+#[derive(Deserialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "NewSubscription"))]
+struct NewSubscription {
+    /// http(s) URL receiving the deliveries.
+    url: String,
+    /// Any of `created`, `updated`, `marked_done`, `deleted`, or `*` for all of them.
+    event_types: Vec<String>,
+    /// Key of the `X-Todo-Signature` HMAC.
+    secret: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "WebhookResponse"))]
+struct WebhookResponse {
+    success: bool,
+    webhook: WebhookSubscription,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "WebhookListResponse"))]
+struct WebhookListResponse {
+    success: bool,
+    webhooks: Vec<WebhookSubscription>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[salvo(schema(symbol = "DeliveryListResponse"))]
+struct DeliveryListResponse {
+    success: bool,
+    deliveries: Vec<WebhookDelivery>,
+}
+
+// Queues a delivery for every subscription interested in the event. Called by
+// the outbox, so the same event may arrive more than once.
+#[tracing::instrument(skip_all, fields(event_id = %event.event_id, todo_id = event.todo_id))]
+pub async fn dispatch(event: &TodoEvent) -> Result<(), BackendError> {
+    let subscriptions = circuit_breaker::read("find_subscriptions", || {
+        sqlx::query_as::<_, WebhookSubscription>(
+            "SELECT id, url, event_types, secret, created_at FROM webhook_subscriptions WHERE $1 = ANY(event_types) OR $2 = ANY(event_types)"
+        )
+        .bind(event.kind.as_str())
+        .bind(ANY_EVENT)
+        .fetch_all(get_postgres())
+    })
+    .await?;
+
+    let payload = json!({
+        "event_id": event.event_id,
+        "event": event.kind,
+        "todo_id": event.todo_id,
+        "todo": event.todo,
+        "occurred_at": event.occurred_at
+    });
+
+    for subscription in subscriptions {
+        let delivery_id: Option<i64> = circuit_breaker::write(async {
+            let delivery_id = sqlx::query_scalar(
+                "INSERT INTO webhook_deliveries (subscription_id, event_id, event_type, payload) VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT (subscription_id, event_id) DO NOTHING RETURNING id"
+            )
+            .bind(subscription.id)
+            .bind(event.event_id)
+            .bind(event.kind.as_str())
+            .bind(&payload)
+            .fetch_optional(get_postgres())
+            .await?;
+            Ok(delivery_id)
+        })
+        .await?;
+
+        // Already queued by an earlier dispatch of this event
+        if let Some(delivery_id) = delivery_id {
+            DELIVERIES.spawn(deliver(subscription, delivery_id, payload.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Restarts deliveries that were still being retried when the process stopped
+#[tracing::instrument(skip_all)]
+pub async fn resume_pending() -> Result<(), BackendError> {
+    let pending: Vec<(i64, i32, Value)> = circuit_breaker::read("pending_deliveries", || {
+        sqlx::query_as("SELECT id, subscription_id, payload FROM webhook_deliveries WHERE status = 'pending' ORDER BY id")
+            .fetch_all(get_postgres())
+    })
+    .await?;
+
+    for (delivery_id, subscription_id, payload) in pending {
+        let subscription = circuit_breaker::read("fetch_subscription", || {
+            sqlx::query_as::<_, WebhookSubscription>(
+                "SELECT id, url, event_types, secret, created_at FROM webhook_subscriptions WHERE id = $1"
+            )
+            .bind(subscription_id)
+            .fetch_one(get_postgres())
+        })
+        .await?;
+
+        DELIVERIES.spawn(deliver(subscription, delivery_id, payload));
+    }
+
+    Ok(())
+}
+
+// Waits for attempts in progress on shutdown. Deliveries waiting for a retry
+// stop right away and stay pending.
+pub async fn finish_deliveries(grace: Duration) {
+    DELIVERIES.close();
+    if timeout(grace, DELIVERIES.wait()).await.is_err() {
+        tracing::warn!(deliveries = DELIVERIES.len(), "Gave up waiting for webhook deliveries");
+    }
+}
+
+// Retries with exponential backoff until the receiver answers 2xx or the delivery runs out of attempts
+#[tracing::instrument(name = "webhook_delivery", skip_all, fields(webhook_id = subscription.id, delivery_id))]
+async fn deliver(subscription: WebhookSubscription, delivery_id: i64, payload: Value) {
+    let body = payload.to_string();
+    retry_delivery(&subscription, delivery_id, &body, INITIAL_BACKOFF, |attempt| record_attempt(delivery_id, attempt)).await;
+}
+
+// Outcome of one request to the receiver
+struct Attempt {
+    succeeded: bool,
+    response_code: Option<i32>,
+    last_error: Option<String>,
+}
+
+// Sends until `record` no longer answers `pending`
+async fn retry_delivery<F, Fut>(subscription: &WebhookSubscription, delivery_id: i64, body: &str, mut backoff: Duration, mut record: F)
+where
+    F: FnMut(Attempt) -> Fut,
+    Fut: Future<Output = Result<String, StoreError>>,
+{
+    loop {
+        let attempt = match send_once(subscription, delivery_id, body).await {
+            Ok(code) if code.is_success() => Attempt { succeeded: true, response_code: Some(code.as_u16() as i32), last_error: None },
+            Ok(code) => Attempt {
+                succeeded: false,
+                response_code: Some(code.as_u16() as i32),
+                last_error: Some(format!("Receiver answered {}", code)),
+            },
+            Err(e) => Attempt { succeeded: false, response_code: None, last_error: Some(e.to_string()) },
+        };
+
+        match record(attempt).await {
+            Ok(status) if status == "pending" => {}
+            Ok(status) if status == "failed" => {
+                tracing::warn!(attempts = MAX_ATTEMPTS, "Webhook delivery failed");
+                return;
+            }
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!(delivery_id, error = %e, "Error recording webhook delivery");
+                return;
+            }
+        }
+
+        // Still pending, so the next start retries it
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown::wait() => return,
+        }
+        backoff *= 2;
+    }
+}
+
+// Attempts are counted in the database so resumed deliveries keep their budget
+#[tracing::instrument(skip_all)]
+async fn record_attempt(delivery_id: i64, attempt: Attempt) -> Result<String, StoreError> {
+    circuit_breaker::write(async {
+        let status = sqlx::query_scalar::<_, String>(
+            "UPDATE webhook_deliveries SET \
+                 status = CASE WHEN $1 THEN 'succeeded' WHEN attempts + 1 >= $2 THEN 'failed' ELSE 'pending' END, \
+                 attempts = attempts + 1, response_code = $3, last_error = $4, updated_at = now() \
+             WHERE id = $5 RETURNING status"
+        )
+        .bind(attempt.succeeded)
+        .bind(MAX_ATTEMPTS)
+        .bind(attempt.response_code)
+        .bind(attempt.last_error)
+        .bind(delivery_id)
+        .fetch_one(get_postgres())
+        .await?;
+        Ok(status)
+    })
+    .await
+}
+
+async fn send_once(subscription: &WebhookSubscription, delivery_id: i64, body: &str) -> Result<reqwest::StatusCode, BackendError> {
+    // Signed with every attempt, so receivers can reject old or replayed requests
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(&subscription.secret, &timestamp, body.as_bytes());
+
+    let response = HTTP_CLIENT
+        .post(&subscription.url)
+        .header("Content-Type", "application/json")
+        // Receivers can use the delivery id to drop duplicates
+        .header("X-Todo-Delivery", delivery_id.to_string())
+        .header("X-Todo-Timestamp", &timestamp)
+        .header("X-Todo-Signature", format!("sha256={}", signature))
+        .body(body.to_owned())
+        .send()
+        .await?;
+
+    Ok(response.status())
+}
+
+// Hex-encoded HMAC-SHA256 of `<timestamp>.<raw request body>`
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// List webhook subscriptions; secrets are never returned.
+#[endpoint(
+    tags("webhooks"),
+    status_codes(200, 429, 500),
+    responses((status_code = 200, description = "All webhook subscriptions", body = WebhookListResponse))
+)]
+pub async fn list_subscriptions(res: &mut Response) -> Result<(), StoreError> {
+    let subscriptions = fetch_subscriptions().await?;
+
+    res.render(Json(WebhookListResponse { success: true, webhooks: subscriptions }));
+    Ok(())
+}
+
+/// Subscribe a URL to todo events.
+#[endpoint(
+    tags("webhooks"),
+    status_codes(201, 400, 413, 429, 500),
+    request_body = NewSubscription,
+    responses((status_code = 201, description = "The created subscription", body = WebhookResponse))
+)]
+pub async fn create_subscription(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let new_subscription = req.parse_json::<NewSubscription>()
+        .await
+        .map_err(|_| StoreError::BadRequest("Invalid JSON payload".to_string()))?;
+
+    if !(new_subscription.url.starts_with("http://") || new_subscription.url.starts_with("https://")) {
+        return Err(StoreError::BadRequest("'url' must be an http(s) URL".to_string()));
+    }
+    if new_subscription.secret.is_empty() {
+        return Err(StoreError::BadRequest("Missing or empty 'secret' field".to_string()));
+    }
+    if new_subscription.event_types.is_empty() {
+        return Err(StoreError::BadRequest("'event_types' must not be empty".to_string()));
+    }
+    for event_type in &new_subscription.event_types {
+        if event_type != ANY_EVENT && !TodoEventKind::ALL.iter().any(|kind| kind.as_str() == event_type) {
+            return Err(StoreError::BadRequest(format!("Unknown event type '{}'", event_type)));
+        }
+    }
+
+    let subscription = insert_subscription(&new_subscription).await?;
+
+    res.status_code(StatusCode::CREATED);
+    res.render(Json(WebhookResponse { success: true, webhook: subscription }));
+    Ok(())
+}
+
+/// Delete a webhook subscription together with its delivery log.
+#[endpoint(
+    tags("webhooks"),
+    status_codes(200, 400, 404, 429, 500),
+    parameters(("id" = i32, Query, description = "Webhook id")),
+    responses((status_code = 200, description = "The subscription was deleted", body = MessageResponse))
+)]
+pub async fn delete_subscription(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    let webhook_id = req.query::<i32>("id")
+        .ok_or_else(|| StoreError::BadRequest("Missing 'id' query parameter".to_string()))?;
+
+    if !remove_subscription(webhook_id).await? {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(ErrorResponse::new(format!("Webhook with id {} does not exist", webhook_id))));
+        return Ok(());
+    }
+
+    res.render(Json(MessageResponse::new(format!("Webhook with id {} successfully deleted", webhook_id))));
+    Ok(())
+}
+
+/// List the most recent webhook deliveries.
+#[endpoint(
+    tags("webhooks"),
+    status_codes(200, 429, 500),
+    parameters(
+        ("webhook_id" = Option<i32>, Query, description = "Only deliveries of this subscription"),
+        ("status" = Option<String>, Query, description = "`pending`, `succeeded` or `failed`"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of deliveries, 1 to 1000, defaults to 100")
+    ),
+    responses((status_code = 200, description = "Deliveries, newest first", body = DeliveryListResponse))
+)]
+pub async fn list_deliveries(req: &mut Request, res: &mut Response) -> Result<(), StoreError> {
+    // Both filters are optional
+    let webhook_id = req.query::<i32>("webhook_id");
+    let status = req.query::<String>("status");
+    let limit = req.query::<i64>("limit").unwrap_or(100).clamp(1, 1000);
+
+    let deliveries = fetch_deliveries(webhook_id, status, limit).await?;
+
+    res.render(Json(DeliveryListResponse { success: true, deliveries }));
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn fetch_subscriptions() -> Result<Vec<WebhookSubscription>, StoreError> {
+    let subscriptions = circuit_breaker::read("list_subscriptions", || {
+        sqlx::query_as::<_, WebhookSubscription>("SELECT id, url, event_types, secret, created_at FROM webhook_subscriptions ORDER BY id")
+            .fetch_all(get_postgres())
+    })
+    .await?;
+
+    Ok(subscriptions)
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_subscription(new_subscription: &NewSubscription) -> Result<WebhookSubscription, StoreError> {
+    circuit_breaker::write(async {
+        let subscription = sqlx::query_as::<_, WebhookSubscription>(
+            "INSERT INTO webhook_subscriptions (url, event_types, secret) VALUES ($1, $2, $3) RETURNING id, url, event_types, secret, created_at"
+        )
+        .bind(&new_subscription.url)
+        .bind(&new_subscription.event_types)
+        .bind(&new_subscription.secret)
+        .fetch_one(get_postgres())
+        .await?;
+        Ok(subscription)
+    })
+    .await
+}
+
+// Whether the subscription existed
+#[tracing::instrument(skip_all, fields(webhook_id = webhook_id))]
+async fn remove_subscription(webhook_id: i32) -> Result<bool, StoreError> {
+    circuit_breaker::write(async {
+        let result = sqlx::query("DELETE FROM webhook_subscriptions WHERE id = $1")
+            .bind(webhook_id)
+            .execute(get_postgres())
+            .await?;
+        Ok(result.rows_affected() > 0)
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all)]
+async fn fetch_deliveries(webhook_id: Option<i32>, status: Option<String>, limit: i64) -> Result<Vec<WebhookDelivery>, StoreError> {
+    let deliveries = circuit_breaker::read("list_deliveries", || {
+        sqlx::query_as::<_, WebhookDelivery>(
+            "SELECT id, subscription_id, event_type, payload, status, attempts, response_code, last_error, created_at, updated_at \
+             FROM webhook_deliveries \
+             WHERE ($1::INTEGER IS NULL OR subscription_id = $1) AND ($2::TEXT IS NULL OR status = $2) \
+             ORDER BY id DESC LIMIT $3"
+        )
+        .bind(webhook_id)
+        .bind(status.as_deref())
+        .bind(limit)
+        .fetch_all(get_postgres())
+    })
+    .await?;
+
+    Ok(deliveries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Headers and body of a request the receiver got
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    // Answers requests with `statuses` in turn, recording what they carried
+    async fn receiver(statuses: Vec<u16>) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Received::default();
+
+        let log = received.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let (head, body) = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break (head.to_ascii_lowercase(), body.to_string());
+                        }
+                    }
+                };
+                log.lock().unwrap().push((head, body));
+
+                let response = format!("HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    fn subscription(url: String) -> WebhookSubscription {
+        WebhookSubscription { id: 1, url, event_types: vec![ANY_EVENT.to_string()], secret: "s3cret".to_string(), created_at: Utc::now() }
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> &'a str {
+        head.lines()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(':')))
+            .map(str::trim)
+            .unwrap()
+    }
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("s3cret", "1700000000", b"{}");
+        assert_eq!(signature.len(), 64);
+        assert_eq!(signature, sign("s3cret", "1700000000", b"{}"));
+        assert_ne!(signature, sign("s3cret", "1700000001", b"{}"));
+        assert_ne!(signature, sign("s3cret", "1700000000", b"{ }"));
+        assert_ne!(signature, sign("other", "1700000000", b"{}"));
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_requests_and_retries_until_accepted() {
+        let (url, received) = receiver(vec![500, 503, 204]).await;
+        let subscription = subscription(url);
+        let body = r#"{"event":"created","todo_id":7}"#;
+
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = attempts.clone();
+        retry_delivery(&subscription, 42, body, Duration::from_millis(10), move |attempt: Attempt| {
+            let status = if attempt.succeeded { "succeeded" } else { "pending" };
+            recorded.lock().unwrap().push(attempt.response_code);
+            async move { Ok(status.to_string()) }
+        })
+        .await;
+
+        assert_eq!(*attempts.lock().unwrap(), vec![Some(500), Some(503), Some(204)]);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        for (head, received_body) in received.iter() {
+            assert_eq!(received_body, body);
+            assert_eq!(header(head, "x-todo-delivery"), "42");
+            let timestamp = header(head, "x-todo-timestamp");
+            assert!((Utc::now().timestamp() - timestamp.parse::<i64>().unwrap()).abs() < 60);
+            let expected = format!("sha256={}", sign("s3cret", timestamp, body.as_bytes()));
+            assert_eq!(header(head, "x-todo-signature"), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_once_attempts_run_out() {
+        let (url, received) = receiver(vec![500, 500]).await;
+        let subscription = subscription(url);
+
+        let mut calls = 0;
+        retry_delivery(&subscription, 1, "{}", Duration::from_millis(10), |attempt: Attempt| {
+            calls += 1;
+            assert!(!attempt.succeeded);
+            assert_eq!(attempt.last_error.as_deref(), Some("Receiver answered 500 Internal Server Error"));
+            let status = if calls == 2 { "failed" } else { "pending" };
+            async move { Ok(status.to_string()) }
+        })
+        .await;
+
+        assert_eq!(calls, 2);
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+}
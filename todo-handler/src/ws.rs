@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use salvo::prelude::*;
+use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::backend_error::StoreError;
+use crate::events;
+use crate::shutdown;
+use crate::todo_store;
+
+// Connection id -> user name, per viewed todo
+type Viewers = HashMap<usize, String>;
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+static PRESENCE: Lazy<Mutex<HashMap<i32, Viewers>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static PRESENCE_UPDATES: Lazy<broadcast::Sender<PresenceUpdate>> = Lazy::new(|| broadcast::channel(256).0);
+
+#[derive(Serialize, Debug, Clone)]
+struct PresenceUpdate {
+    todo_id: i32,
+    viewers: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClientFrame {
+    request_id: Option<String>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    // An empty list subscribes to / unsubscribes from every todo
+    Subscribe {
+        #[serde(default)]
+        todo_ids: Vec<i32>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        todo_ids: Vec<i32>,
+    },
+    View { todo_id: i32 },
+    Leave { todo_id: i32 },
+    Create { name: String, description: String },
+    Update { id: i32, name: String, description: String, done: bool },
+    MarkDone { id: i32 },
+    Delete { id: i32 },
+}
+
+struct Session {
+    connection_id: usize,
+    user: String,
+    all_todos: bool,
+    todo_ids: HashSet<i32>,
+    viewing: HashSet<i32>,
+}
+
+/// Upgrade to a WebSocket for live subscriptions, mutations and presence.
+///
+/// The message protocol is described in the README.
+#[endpoint(
+    tags("todos"),
+    status_codes(101, 400),
+    parameters(("user" = Option<String>, Query, description = "Name shown to other clients in presence updates")),
+    responses((status_code = 101, description = "Switching to the WebSocket protocol"))
+)]
+pub async fn todo_socket(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    // Name shown to other clients in presence updates
+    let user = req.query::<String>("user")
+        .filter(|user| !user.trim().is_empty())
+        .unwrap_or_else(|| "anonymous".to_string());
+
+    WebSocketUpgrade::new()
+        .upgrade(req, res, move |ws| handle_socket(ws, user))
+        .await
+}
+
+async fn handle_socket(mut ws: WebSocket, user: String) {
+    let mut session = Session {
+        connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        user,
+        all_todos: false,
+        todo_ids: HashSet::new(),
+        viewing: HashSet::new(),
+    };
+    let mut todo_events = events::subscribe();
+    let mut presence_updates = PRESENCE_UPDATES.subscribe();
+
+    loop {
+        let outgoing = tokio::select! {
+            msg = ws.recv() => match msg {
+                Some(Ok(msg)) if msg.is_close() => break,
+                // Pings and binary frames carry no commands
+                Some(Ok(msg)) => match msg.to_str() {
+                    Ok(text) => Some(session.handle_frame(text).await),
+                    Err(_) => None,
+                },
+                _ => break,
+            },
+            event = todo_events.recv() => match event {
+                Ok(event) if session.is_subscribed(event.todo_id) => Some(json!({
+                    "type": "event",
+                    "event": event
+                })),
+                Ok(_) => None,
+                // Let the client know it should reload instead of silently dropping changes
+                Err(RecvError::Lagged(missed)) => Some(json!({
+                    "type": "lagged",
+                    "missed": missed
+                })),
+                Err(RecvError::Closed) => break,
+            },
+            update = presence_updates.recv() => match update {
+                Ok(update) if session.is_subscribed(update.todo_id) => Some(json!({
+                    "type": "presence",
+                    "todo_id": update.todo_id,
+                    "viewers": update.viewers
+                })),
+                _ => None,
+            },
+            _ = shutdown::wait() => {
+                let _ = ws.send(Message::close_with(1001u16, "Server shutting down")).await;
+                break;
+            }
+        };
+
+        if let Some(outgoing) = outgoing {
+            if ws.send(Message::text(outgoing.to_string())).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    session.leave_all();
+}
+
+impl Session {
+    fn is_subscribed(&self, todo_id: i32) -> bool {
+        self.all_todos || self.todo_ids.contains(&todo_id)
+    }
+
+    async fn handle_frame(&mut self, text: &str) -> Value {
+        let frame = match serde_json::from_str::<ClientFrame>(text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                return json!({
+                    "type": "ack",
+                    "request_id": null,
+                    "success": false,
+                    "error": format!("Invalid message: {}", e)
+                });
+            }
+        };
+
+        match self.run(frame.command).await {
+            Ok(payload) => {
+                let mut ack = json!({
+                    "type": "ack",
+                    "request_id": frame.request_id,
+                    "success": true
+                });
+                if let (Value::Object(ack), Value::Object(payload)) = (&mut ack, payload) {
+                    ack.extend(payload);
+                }
+                ack
+            }
+            Err(e) => json!({
+                "type": "ack",
+                "request_id": frame.request_id,
+                "success": false,
+                "error": e.to_string()
+            }),
+        }
+    }
+
+    async fn run(&mut self, command: Command) -> Result<Value, StoreError> {
+        match command {
+            Command::Subscribe { todo_ids } => {
+                if todo_ids.is_empty() {
+                    self.all_todos = true;
+                }
+                self.todo_ids.extend(todo_ids);
+                Ok(self.subscriptions())
+            }
+            Command::Unsubscribe { todo_ids } => {
+                if todo_ids.is_empty() {
+                    self.all_todos = false;
+                    self.todo_ids.clear();
+                }
+                for todo_id in todo_ids {
+                    self.todo_ids.remove(&todo_id);
+                }
+                Ok(self.subscriptions())
+            }
+            Command::View { todo_id } => {
+                if todo_store::fetch_todo(todo_id).await?.is_none() {
+                    return Err(StoreError::NotFound(todo_id));
+                }
+                self.viewing.insert(todo_id);
+                Ok(json!({ "todo_id": todo_id, "viewers": set_presence(todo_id, self.connection_id, Some(&self.user)) }))
+            }
+            Command::Leave { todo_id } => {
+                self.viewing.remove(&todo_id);
+                Ok(json!({ "todo_id": todo_id, "viewers": set_presence(todo_id, self.connection_id, None) }))
+            }
+            Command::Create { name, description } => {
+                let todo = todo_store::create_todo(&name, &description).await?;
+                Ok(json!({ "todo": todo }))
+            }
+            Command::Update { id, name, description, done } => {
+                let todo = todo_store::update_todo(id, &name, &description, done).await?;
+                Ok(json!({ "todo": todo }))
+            }
+            Command::MarkDone { id } => {
+                let todo = todo_store::mark_done(id).await?;
+                Ok(json!({ "todo": todo }))
+            }
+            Command::Delete { id } => {
+                todo_store::delete_todo(id).await?;
+                Ok(json!({ "message": format!("Todo with id {} successfully deleted", id) }))
+            }
+        }
+    }
+
+    fn subscriptions(&self) -> Value {
+        let mut todo_ids: Vec<i32> = self.todo_ids.iter().copied().collect();
+        todo_ids.sort_unstable();
+        json!({ "all_todos": self.all_todos, "todo_ids": todo_ids })
+    }
+
+    fn leave_all(&mut self) {
+        for todo_id in self.viewing.drain() {
+            set_presence(todo_id, self.connection_id, None);
+        }
+    }
+}
+
+// Adds (`Some(user)`) or removes (`None`) a connection from a todo's viewers,
+// notifies subscribers and returns the resulting viewer list
+fn set_presence(todo_id: i32, connection_id: usize, user: Option<&str>) -> Vec<String> {
+    let viewers = {
+        let mut presence = PRESENCE.lock().unwrap();
+        let viewers = presence.entry(todo_id).or_default();
+        match user {
+            Some(user) => viewers.insert(connection_id, user.to_string()),
+            None => viewers.remove(&connection_id),
+        };
+
+        let mut names: Vec<String> = viewers.values().cloned().collect();
+        names.sort();
+        names.dedup();
+        if viewers.is_empty() {
+            presence.remove(&todo_id);
+        }
+        names
+    };
+
+    let _ = PRESENCE_UPDATES.send(PresenceUpdate { todo_id, viewers: viewers.clone() });
+    viewers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use salvo::conn::Acceptor;
+    use tokio::time::timeout;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+
+    use super::*;
+    use crate::test_support::{broadcast_events, unique, with_database};
+
+    #[test]
+    fn subscribers_get_the_ack_and_the_event() {
+        with_database(async {
+            broadcast_events().await;
+            let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+            let addr = acceptor.holdings()[0].local_addr.clone().into_std().unwrap();
+            tokio::spawn(Server::new(acceptor).serve(Router::with_path("ws").goal(todo_socket)));
+
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+            let name = unique("ws-test");
+            for frame in [
+                json!({ "type": "subscribe", "request_id": "subscribe" }),
+                json!({ "type": "create", "request_id": "create", "name": name, "description": "" }),
+            ] {
+                socket.send(ClientMessage::text(frame.to_string())).await.unwrap();
+            }
+
+            // Events of other tests arrive too, and the event may come before or after the ack
+            let mut created = None;
+            let mut event_for = None;
+            while created.is_none() || event_for != created {
+                let frame = timeout(Duration::from_secs(10), socket.next()).await.unwrap().unwrap().unwrap();
+                let frame: Value = serde_json::from_str(frame.to_text().unwrap()).unwrap();
+                match (frame["type"].as_str(), frame["request_id"].as_str()) {
+                    (Some("ack"), Some("subscribe")) => assert_eq!(frame["all_todos"], true, "{}", frame),
+                    (Some("ack"), Some("create")) => {
+                        assert_eq!(frame["success"], true, "{}", frame);
+                        created = frame["todo"]["id"].as_i64();
+                    }
+                    (Some("event"), _) if frame["event"]["todo"]["name"] == name.as_str() => {
+                        assert_eq!(frame["event"]["kind"], "created");
+                        event_for = frame["event"]["todo_id"].as_i64();
+                    }
+                    _ => {}
+                }
+            }
+
+            todo_store::delete_todo(created.unwrap() as i32).await.unwrap();
+        });
+    }
+}